@@ -18,4 +18,27 @@ struct app_sk_stg {
 	__type(value, struct app_info);
 };
 
+/* Hints the traffic server pushes as it starts a response, into the maps a CCA
+ * declares with these names:
+ * - hint_sk_stg: req is the bytes of the response, resp the deadline in ms
+ *   shifted by 8 above the priority, see APP_HINT_DEADLINE_MS and APP_HINT_PRIORITY.
+ *   No CCA reads it yet, copa only declares it for the updates of the server;
+ * - rate_cap_sk_stg: req is a cap of the pacing rate in bytes per second, 0 for
+ *   none, see app_cap_pacing_rate.
+ * rate_sk_stg is not a hint: copa and bbr write their pacing rates into it.
+ */
+#define APP_HINT_DEADLINE_MS(info) ((info)->resp >> 8)
+#define APP_HINT_PRIORITY(info) ((info)->resp & 0xff)
+
+/* Pacing rate `rate` capped by the rate_cap_sk_stg map `cap_map` of the socket */
+static __always_inline unsigned long
+app_cap_pacing_rate(void *cap_map, struct sock *sk, unsigned long rate)
+{
+	struct app_info *cap = bpf_sk_storage_get(cap_map, (void *)sk, NULL, 0);
+
+	if (cap && cap->req && cap->req < rate)
+		return cap->req;
+	return rate;
+}
+
 #endif
\ No newline at end of file
@@ -13,6 +13,14 @@ struct app_sk_stg sk_stg_map SEC(".maps");
 
 struct app_sk_stg rate_sk_stg SEC(".maps");
 
+struct app_sk_stg rate_cap_sk_stg SEC(".maps");
+
+// The manager requires the report ring of every object, bbr submits no report yet
+struct {
+	__uint(type, BPF_MAP_TYPE_RINGBUF);
+	__uint(max_entries, 4096);
+} rb SEC(".maps");
+
 /* BBR has the following modes for deciding how fast to send: */
 enum bbr_mode {
 	BBR_STARTUP, /* ramp up sending rate rapidly to fill pipe */
@@ -241,7 +249,8 @@ static inline void bbr_init_pacing_rate_from_rtt(struct sock *sk)
 	}
 	bw = (u64)tp->snd_cwnd * BW_UNIT;
 	do_div(bw, rtt_us);
-	sk->sk_pacing_rate = bbr_bw_to_pacing_rate(sk, bw, bbr_high_gain);
+	sk->sk_pacing_rate = app_cap_pacing_rate(
+		&rate_cap_sk_stg, sk, bbr_bw_to_pacing_rate(sk, bw, bbr_high_gain));
 }
 
 /* Pace using current bw estimate and a gain factor. */
@@ -255,6 +264,8 @@ static inline void bbr_set_pacing_rate(struct sock *sk, u32 bw, int gain)
 		bbr_init_pacing_rate_from_rtt(sk);
 	if (bbr_full_bw_reached(sk) || rate > sk->sk_pacing_rate)
 		sk->sk_pacing_rate = rate;
+	sk->sk_pacing_rate =
+		app_cap_pacing_rate(&rate_cap_sk_stg, sk, sk->sk_pacing_rate);
 	bbr_update_rate_stg(sk);
 }
 
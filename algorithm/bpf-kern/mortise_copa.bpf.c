@@ -223,6 +223,11 @@ struct {
 
 struct app_sk_stg rate_sk_stg SEC(".maps");
 
+/* Not read yet, declared so that the hints of the server reach the flow */
+struct app_sk_stg hint_sk_stg SEC(".maps");
+
+struct app_sk_stg rate_cap_sk_stg SEC(".maps");
+
 static const u32 min_rtt_window = 10 * USEC_PER_SEC; // 10 seconds
 static const u32 standing_rtt_window = 100 * USEC_PER_MSEC; // 100 ms
 static const u64 quantization_base = 1000;
@@ -366,7 +371,7 @@ static inline void copa_init_pacing_rate_from_rtt(struct sock *sk, int gain)
 		rate >>= COPA_SCALE;
 	}
 
-	sk->sk_pacing_rate = rate;
+	sk->sk_pacing_rate = app_cap_pacing_rate(&rate_cap_sk_stg, sk, rate);
 }
 
 /* static inline u64 copa_get_delivery_rate(struct sock *sk, */
@@ -0,0 +1,200 @@
+#include "bpf_tcp_helpers.h"
+#include "vmlinux.h"
+#include <bpf/bpf_endian.h>
+
+char _license[] SEC("license") = "GPL";
+
+/*
+ * Enroll the TCP connections of the attached cgroups without a Connect call from the
+ * application. The manager reuses the flow_id_stg of the CCA object for this program,
+ * fills enroll_cfg and reads the events to create the per-flow maps.
+ *
+ * The congestion control is only switched once the manager marks the flow in
+ * enroll_ready, so that the struct_ops finds its maps. The manager takes over the
+ * socket as soon as mortise_enroll_iter finds it in a process, and marks the flow to
+ * be skipped here.
+ */
+
+#define ENROLL_CONNECT 1
+#define ENROLL_CLOSE 2
+
+#define ENROLL_SKIP 0
+#define ENROLL_APPLY 1
+
+struct enroll_cfg {
+	char tcp_ca[TCP_CA_NAME_MAX];
+	// flow ids allocated here start from a base chosen by the manager
+	u32 next_flow_id;
+	u32 enabled;
+};
+
+struct enroll_event {
+	u32 type;
+	u32 flow_id;
+	u32 family;
+	u32 local_port;
+	u32 remote_ip4;
+	u32 remote_port;
+	u32 remote_ip6[4];
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(max_entries, 1);
+	__type(key, u32);
+	__type(value, struct enroll_cfg);
+} enroll_cfg SEC(".maps");
+
+struct enroll_sock {
+	u32 flow_id;
+	u32 pid;
+	u32 fd;
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_SK_STORAGE);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, int);
+	__type(value, int);
+} flow_id_stg SEC(".maps");
+
+// flow ids of the enrolled sockets, kept when they migrate to another object
+struct {
+	__uint(type, BPF_MAP_TYPE_SK_STORAGE);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+	__type(key, int);
+	__type(value, u32);
+} enroll_id_stg SEC(".maps");
+
+// <flow_id, ENROLL_*> written by the manager once the maps of the flow exist
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 65536);
+	__type(key, u32);
+	__type(value, u32);
+} enroll_ready SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_RINGBUF);
+	__uint(max_entries, 1024 * 1024 /* 1 MB */);
+} enroll_rb SEC(".maps");
+
+static __always_inline void submit_event(struct bpf_sock_ops *skops, u32 type,
+					 u32 flow_id)
+{
+	struct enroll_event *e =
+		bpf_ringbuf_reserve(&enroll_rb, sizeof(struct enroll_event), 0);
+	if (!e)
+		return;
+	e->type = type;
+	e->flow_id = flow_id;
+	e->family = skops->family;
+	e->local_port = skops->local_port;
+	e->remote_ip4 = skops->remote_ip4;
+	e->remote_port = bpf_ntohl(skops->remote_port);
+	e->remote_ip6[0] = skops->remote_ip6[0];
+	e->remote_ip6[1] = skops->remote_ip6[1];
+	e->remote_ip6[2] = skops->remote_ip6[2];
+	e->remote_ip6[3] = skops->remote_ip6[3];
+	bpf_ringbuf_submit(e, 0);
+}
+
+static __always_inline void enroll(struct bpf_sock_ops *skops)
+{
+	u32 zero = 0;
+	struct enroll_cfg *cfg = bpf_map_lookup_elem(&enroll_cfg, &zero);
+	if (!cfg || !cfg->enabled || !skops->sk)
+		return;
+	u32 *enroll_id = bpf_sk_storage_get(&enroll_id_stg, skops->sk, NULL,
+					    BPF_LOCAL_STORAGE_GET_F_CREATE);
+	if (!enroll_id)
+		return;
+	u32 *flow_id = bpf_sk_storage_get(&flow_id_stg, skops->sk, NULL,
+					  BPF_LOCAL_STORAGE_GET_F_CREATE);
+	if (!flow_id) {
+		bpf_sk_storage_delete(&enroll_id_stg, skops->sk);
+		return;
+	}
+	*flow_id = __sync_fetch_and_add(&cfg->next_flow_id, 1);
+	*enroll_id = *flow_id;
+	// wait for the maps in the RTT callbacks
+	bpf_sock_ops_cb_flags_set(skops, BPF_SOCK_OPS_STATE_CB_FLAG |
+						 BPF_SOCK_OPS_RTT_CB_FLAG);
+	submit_event(skops, ENROLL_CONNECT, *flow_id);
+}
+
+static __always_inline void apply(struct bpf_sock_ops *skops)
+{
+	u32 zero = 0;
+	struct enroll_cfg *cfg = bpf_map_lookup_elem(&enroll_cfg, &zero);
+	if (!cfg || !skops->sk)
+		return;
+	u32 *flow_id = bpf_sk_storage_get(&enroll_id_stg, skops->sk, NULL, 0);
+	if (!flow_id)
+		return;
+	u32 id = *flow_id;
+	u32 *ready = bpf_map_lookup_elem(&enroll_ready, &id);
+	if (!ready)
+		return;
+	if (*ready == ENROLL_APPLY) {
+		char tcp_ca[TCP_CA_NAME_MAX];
+		__builtin_memcpy(tcp_ca, cfg->tcp_ca, TCP_CA_NAME_MAX);
+		tcp_ca[TCP_CA_NAME_MAX - 1] = '\0';
+		bpf_setsockopt(skops, SOL_TCP, TCP_CONGESTION, tcp_ca,
+			       sizeof(tcp_ca));
+	}
+	bpf_map_delete_elem(&enroll_ready, &id);
+	bpf_sock_ops_cb_flags_set(skops, BPF_SOCK_OPS_STATE_CB_FLAG);
+}
+
+SEC("sockops")
+int mortise_enroll(struct bpf_sock_ops *skops)
+{
+	switch (skops->op) {
+	case BPF_SOCK_OPS_ACTIVE_ESTABLISHED_CB:
+	case BPF_SOCK_OPS_PASSIVE_ESTABLISHED_CB:
+		enroll(skops);
+		break;
+	case BPF_SOCK_OPS_RTT_CB:
+		apply(skops);
+		break;
+	case BPF_SOCK_OPS_STATE_CB:
+		if (skops->args[1] == BPF_TCP_CLOSE && skops->sk) {
+			u32 *flow_id = bpf_sk_storage_get(&enroll_id_stg,
+							  skops->sk, NULL, 0);
+			if (flow_id) {
+				u32 id = *flow_id;
+				bpf_map_delete_elem(&enroll_ready, &id);
+				submit_event(skops, ENROLL_CLOSE, id);
+			}
+		}
+		break;
+	default:
+		break;
+	}
+	return 1;
+}
+
+// Report the fds of the enrolled sockets, so that the manager can take them over
+SEC("iter/task_file")
+int mortise_enroll_iter(struct bpf_iter__task_file *ctx)
+{
+	struct seq_file *seq = ctx->meta->seq;
+	struct task_struct *task = ctx->task;
+	struct file *file = ctx->file;
+	if (!task || !file)
+		return 0;
+	struct socket *sock = bpf_sock_from_file(file);
+	if (!sock || !sock->sk)
+		return 0;
+	u32 *flow_id = bpf_sk_storage_get(&enroll_id_stg, sock->sk, NULL, 0);
+	if (!flow_id)
+		return 0;
+	struct enroll_sock s = {
+		.flow_id = *flow_id,
+		.pid = task->tgid,
+		.fd = ctx->fd,
+	};
+	bpf_seq_write(seq, &s, sizeof(s));
+	return 0;
+}
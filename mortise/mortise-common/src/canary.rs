@@ -0,0 +1,56 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
+
+/// How newly connecting flows are split between the stable and the canary version.
+#[derive(
+    ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Readable, Writable,
+)]
+#[clap(rename_all = "kebab-case")]
+pub enum CanaryAssign {
+    /// Keep the share of canary flows at the percentage.
+    Percent,
+    /// Hash of the flow 4-tuple.
+    HashFlow,
+    /// Hash of the peer address, so that all the flows of a peer use the same version.
+    HashPeer,
+}
+
+/// Two versions of a logical CCA rolled out side by side.
+///
+/// Flows connecting to `stable` are assigned to `canary` for `percent` of them. The
+/// canary object registers its own struct_ops, whose name `canary_tcp_ca` is set on
+/// the sockets assigned to it. They are switched back to `stable_tcp_ca` on rollback.
+#[derive(Debug, Clone, Serialize, Deserialize, Readable, Writable)]
+pub struct CanaryConfig {
+    pub stable: u32,
+    pub canary: u32,
+    pub stable_tcp_ca: String,
+    pub canary_tcp_ca: String,
+    pub percent: u8,
+    pub assign: CanaryAssign,
+}
+
+/// QoE and request completion time of the flows assigned to one version.
+///
+/// The completion time of a frame is measured from its sending by the server to its
+/// reception by the client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VersionSummary {
+    pub obj_id: u32,
+    pub flows: u64,
+    pub qoe_samples: u64,
+    pub mean_qoe: f64,
+    pub rct_samples: u64,
+    pub mean_rct_us: u64,
+    pub p50_rct_us: u64,
+    pub p95_rct_us: u64,
+}
+
+/// JSON encoded response of the canary operations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryReport {
+    pub config: CanaryConfig,
+    pub stable: VersionSummary,
+    pub canary: VersionSummary,
+}
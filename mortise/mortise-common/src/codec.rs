@@ -0,0 +1,180 @@
+//! Encoding of the frames exchanged on the manager socket.
+//!
+//! A connection starts with JSON, which is easy to inspect and is what the python
+//! strategies speak. A client sending high-rate messages can switch its connection to
+//! the compact binary encoding of `speedy` with [`ManagerOperation::SetEncoding`].
+//!
+//! Unlike JSON, the binary layout changes with any operation it carries, e.g. when
+//! `Connect::obj_id` became optional. Both sides agree on [`BINARY_VERSION`] before
+//! switching, and a client of another version keeps JSON.
+//!
+//! [`ManagerOperation::SetEncoding`]: crate::ManagerOperation::SetEncoding
+
+use crate::{ManagerResponse, MortiseError, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use speedy::{Context, LittleEndian, Readable, Reader, Writable, Writer};
+
+/// Version of the binary layout, bumped whenever an operation or response changes.
+pub const BINARY_VERSION: u32 = 1;
+
+#[derive(
+    ValueEnum,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    Readable,
+    Writable,
+)]
+#[clap(rename_all = "lower")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Binary,
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            Encoding::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn encode<T>(&self, val: &T) -> Result<Vec<u8>>
+    where
+        T: Serialize + Writable<LittleEndian>,
+    {
+        match self {
+            Encoding::Json => {
+                serde_json::to_vec(val).map_err(|e| MortiseError::Codec(e.to_string()))
+            }
+            Encoding::Binary => val
+                .write_to_vec()
+                .map_err(|e| MortiseError::Codec(e.to_string())),
+        }
+    }
+
+    pub fn decode<'a, T>(&self, buf: &'a [u8]) -> Result<T>
+    where
+        T: Deserialize<'a> + Readable<'a, LittleEndian>,
+    {
+        match self {
+            Encoding::Json => {
+                serde_json::from_slice(buf).map_err(|e| MortiseError::Codec(e.to_string()))
+            }
+            Encoding::Binary => {
+                T::read_from_buffer(buf).map_err(|e| MortiseError::Codec(e.to_string()))
+            }
+        }
+    }
+}
+
+// `speedy` has no implementation for `Result`, so the response is written by hand
+// as the id, a one byte tag and the payload or error message.
+impl<C: Context> Writable<C> for ManagerResponse {
+    fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> std::result::Result<(), C::Error> {
+        self.id.write_to(writer)?;
+        match self.resp {
+            Ok(ref val) => {
+                writer.write_u8(0)?;
+                val.write_to(writer)
+            }
+            Err(ref e) => {
+                writer.write_u8(1)?;
+                e.write_to(writer)
+            }
+        }
+    }
+}
+
+impl<'a, C: Context> Readable<'a, C> for ManagerResponse {
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> std::result::Result<Self, C::Error> {
+        let id = reader.read_u64()?;
+        let resp = match reader.read_u8()? {
+            0 => Ok(Vec::read_from(reader)?),
+            1 => Err(String::read_from(reader)?),
+            tag => {
+                return Err(speedy::Error::custom(format!("invalid response tag {}", tag)).into())
+            }
+        };
+        Ok(ManagerResponse { id, resp })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+    use crate::{qoe::FrameQoE, FlowOperation, ManagerRequest, ManagerResponse, Operation};
+    use std::time::Duration;
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let qoe = FrameQoE {
+            server_send: 1,
+            client_recv: 2,
+            server_recv: 3,
+            size: 4,
+            frame_interval: Duration::from_micros(16667),
+            frame_id: 5,
+        };
+        let req = FlowOperation::QoEUpdate { qoe }.to_op(7).with_id(42);
+        for encoding in [Encoding::Json, Encoding::Binary] {
+            let buf = encoding.encode(&req).unwrap();
+            let decoded: ManagerRequest = encoding.decode(&buf).unwrap();
+            assert_eq!(decoded.id, 42);
+            match decoded.op {
+                Operation::Flow {
+                    flow_id: 7,
+                    op: FlowOperation::QoEUpdate { qoe },
+                } => assert_eq!(qoe.frame_interval, Duration::from_micros(16667)),
+                op => panic!("unexpected operation {:?}", op),
+            }
+
+            for resp in [Ok(vec![1, 2, 3]), Err("failed".to_string())] {
+                let buf = encoding
+                    .encode(&ManagerResponse {
+                        id: 42,
+                        resp: resp.clone(),
+                    })
+                    .unwrap();
+                let decoded: ManagerResponse = encoding.decode(&buf).unwrap();
+                assert_eq!(decoded.id, 42);
+                assert_eq!(decoded.resp, resp);
+            }
+        }
+
+        let mut buf = Encoding::Binary
+            .encode(&ManagerResponse {
+                id: 42,
+                resp: Ok(Vec::new()),
+            })
+            .unwrap();
+        buf[8] = 2;
+        assert!(Encoding::Binary.decode::<ManagerResponse>(&buf).is_err());
+    }
+
+    // The python strategies write this layout by hand, see `process-report.py`
+    #[test]
+    fn test_binary_map_update_layout() {
+        let req = FlowOperation::SkStgMapUpdate {
+            map_name: "m".to_string(),
+            val: vec![7],
+            flag: 0,
+        }
+        .to_op(3)
+        .with_id(1);
+        let mut expected = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend([1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend([1, 0, 0, 0, b'm', 1, 0, 0, 0, 7]);
+        expected.extend([0; 8]);
+        assert_eq!(Encoding::Binary.encode(&req).unwrap(), expected);
+    }
+}
@@ -17,8 +17,12 @@ pub enum MortiseError {
     ObjectNotFound(u32),
     #[error("Map of name {0} not found")]
     MapNotFound(String),
+    #[error("Program of name {0} not found")]
+    ProgNotFound(String),
     #[error("Element of Map {0} not found")]
     ElemNotFound(String),
+    #[error("Object id {0} is reserved for the kernel CCAs")]
+    ReservedObjectId(u32),
     #[error("Flow of id {0} not found")]
     FlowNotFound(u32),
     #[error("Fail to join thread")]
@@ -31,6 +35,30 @@ pub enum MortiseError {
     ManagerChannelRecvError(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("Flow of id {0} already connected")]
     FlowConnected(u32),
+    #[error("Invalid policy: {0}")]
+    InvalidPolicy(String),
+    #[error("No policy applies to flow {0}")]
+    NoPolicy(u32),
+    #[error("No canary rollout in progress")]
+    CanaryNotFound,
+    #[error("Manager is shutting down")]
+    ShuttingDown,
+    #[error("Object {0} is not trusted: {1}")]
+    UntrustedObject(String, String),
+    #[error("{0} is not owned by uid {1}")]
+    NotOwner(String, u32),
+    #[error("Invalid object {0}: {1}")]
+    InvalidObject(String, String),
+    #[error("Object of id {0} can not be upgraded: {1}")]
+    IncompatibleUpgrade(u32, String),
+    #[error("Operation {0} can not be rolled back in an atomic batch")]
+    BatchNotAtomic(String),
+    #[error("Batch aborted at operation {0}: {1}")]
+    BatchAborted(usize, String),
+    #[error("Codec error: {0}")]
+    Codec(String),
+    #[error("Shared memory error: {0}")]
+    ShmError(String),
     #[error("Unknown data store error: {0}")]
     Unknown(String),
     #[error("{0}")]
@@ -1,21 +1,31 @@
 use nix::sys::resource::{setrlimit, Resource};
-use socket2::SockAddr;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use tcp_info_sys::get_tcp_info;
+use socket2::{SockAddr, SockRef};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::BorrowedFd;
+pub use tcp_info_sys::get_tcp_info;
 
+pub mod canary;
+pub mod codec;
 pub mod congestion;
 pub mod error;
 pub mod op;
 pub mod pidfd;
+pub mod policy;
 pub mod qoe;
 pub mod report;
+pub mod shm;
+pub mod store;
 pub mod sync;
 
+pub use codec::Encoding;
 pub use congestion::CongestionOpt;
 pub use error::{MortiseError, Result};
 pub use op::{
-    ConnectOption, FlowOperation, ManagerIpcOperation, ManagerOperation, Operation, SkArrayMap,
+    BatchResponse, ConnectOption, FlowOperation, ManagerEvent, ManagerIpcOperation,
+    ManagerOperation, ManagerRequest, ManagerResponse, Operation, ShutdownReport, SkArrayMap,
+    KERNEL_CCA_OBJ_ID,
 };
+pub use tcp_info_sys::tcp_info as TcpInfo;
 
 pub const NANOS_PER_SEC: i64 = 1_000_000_000;
 
@@ -83,3 +93,17 @@ pub fn get_tcp_info_total_retrans(sk_fd: i32) -> Result<u32> {
     let tcp_info = get_tcp_info(sk_fd)?;
     Ok(tcp_info.tcpi_total_retrans)
 }
+
+/// Set the congestion control of a socket, e.g. to the struct_ops of a loaded object.
+pub fn set_tcp_congestion(sk_fd: i32, tcp_ca: &[u8]) -> Result<()> {
+    let fd = unsafe { BorrowedFd::borrow_raw(sk_fd) };
+    SockRef::from(&fd).set_tcp_congestion(tcp_ca)?;
+    Ok(())
+}
+
+/// Local and peer address of a connected socket.
+pub fn get_sk_addrs(sk_fd: i32) -> Result<(Option<SocketAddr>, Option<SocketAddr>)> {
+    let fd = unsafe { BorrowedFd::borrow_raw(sk_fd) };
+    let sk = SockRef::from(&fd);
+    Ok((sk.local_addr()?.as_socket(), sk.peer_addr()?.as_socket()))
+}
@@ -1,9 +1,16 @@
+use crate::canary::CanaryConfig;
+use crate::codec::Encoding;
+use crate::policy::Strategy;
 use crate::qoe::FrameQoE;
 use crate::Result;
 use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
 use tokio::sync::oneshot;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Object id of the flows using a congestion control built in the kernel.
+pub const KERNEL_CCA_OBJ_ID: u32 = 0;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Readable, Writable)]
 pub struct SkArrayMap {
     /// The name of the outer map.
     pub mim: String,
@@ -18,12 +25,12 @@ pub struct SkArrayMap {
 ///
 /// `sk_array_maps` means a list of maps that are used to store
 /// CCA's data for each socket. These maps should be created from user space.
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Readable, Writable)]
 pub struct ConnectOption {
     pub sk_array_maps: Vec<SkArrayMap>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Readable, Writable)]
 pub enum ManagerOperation {
     Load {
         path: String,
@@ -37,15 +44,88 @@ pub enum ManagerOperation {
         path: String,
         option: Option<ConnectOption>,
     },
+    /// Replace the loaded object `obj_id` by the object at `path`, keeping its flows.
+    Upgrade {
+        obj_id: u32,
+        path: String,
+    },
+    /// Assign part of the flows connecting to a stable object to a canary version.
+    StartCanary {
+        config: CanaryConfig,
+    },
+    /// Assign all the new flows to the canary version, returning the [`CanaryReport`].
+    ///
+    /// [`CanaryReport`]: crate::canary::CanaryReport
+    PromoteCanary,
+    /// Stop the canary rollout, returning the [`CanaryReport`].
+    ///
+    /// [`CanaryReport`]: crate::canary::CanaryReport
+    RollbackCanary,
+    /// Per-version QoE and RCT summaries as a [`CanaryReport`].
+    ///
+    /// [`CanaryReport`]: crate::canary::CanaryReport
+    CanaryStats,
+    /// Load the policy file at `path`, or reload the current one without `path`. Only
+    /// root clients load another file than the current one.
+    ReloadPolicy {
+        path: Option<String>,
+    },
+    /// Hits of the policy rules, as a JSON encoded [`PolicyReport`].
+    ///
+    /// [`PolicyReport`]: crate::policy::PolicyReport
+    PolicyStats,
+    /// Return the loaded objects with the hash of their file and who loaded them, as a
+    /// JSON encoded list of [`ObjectInfo`].
+    ///
+    /// [`ObjectInfo`]: crate::store::ObjectInfo
+    ListObjects,
+    /// Attach the sockops hook of the object at `path` to `cgroups`, so that their new
+    /// TCP connections use `tcp_ca` of object `obj_id` without calling `Connect`.
+    Enroll {
+        obj_id: u32,
+        path: String,
+        tcp_ca: String,
+        cgroups: Vec<String>,
+    },
+    Unenroll,
+    /// Wait for the next [`ManagerEvent`], answered JSON encoded when it happens. Only
+    /// useful on a connection with tagged requests.
+    Subscribe,
+    /// Switch the connected flows to the fallback CCA, let the clients disconnect them
+    /// and unload all the objects. Answered with a JSON encoded [`ShutdownReport`] to
+    /// the manager itself.
     Shutdown,
     PingPong,
     RegisterRingBuf {
         obj_ids: Vec<u32>,
     },
     UnregisterRingBuf,
+    /// Create a shared-memory ring of `capacity` reports for the strategy consumer `name`.
+    /// The manager sends a datagram to the socket bound at `doorbell` when the ring
+    /// turns non-empty.
+    RegisterShmReport {
+        name: String,
+        capacity: u32,
+        doorbell: String,
+    },
+    /// Remove the ring of consumer `name`. Returns the number of reports dropped because
+    /// the ring was full.
+    UnregisterShmReport {
+        name: String,
+    },
+    /// Switch the encoding of the following frames on this connection. `version` is the
+    /// [`BINARY_VERSION`] the client was built with, binary encoding is refused on a
+    /// mismatch.
+    ///
+    /// [`BINARY_VERSION`]: crate::codec::BINARY_VERSION
+    SetEncoding {
+        encoding: Encoding,
+        #[serde(default)]
+        version: u32,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Readable, Writable)]
 pub enum FlowOperation {
     SkStgMapUpdate {
         map_name: String,
@@ -55,28 +135,94 @@ pub enum FlowOperation {
     SkStgMapLookup {
         map_name: String,
     },
+    /// Connect the socket `sk_fd` of process `pid` to object `obj_id`. Without `obj_id`
+    /// the CCA and the initial tunable are chosen by the manager's policy, which can
+    /// match on the application `tag`.
     Connect {
-        obj_id: u32,
+        obj_id: Option<u32>,
         sk_fd: i32,
         pid: i32,
         default_app_info: Option<u64>,
+        #[serde(default)]
+        tag: Option<String>,
     },
     Disconnect,
+    /// Switch the flow to the struct_ops `tcp_ca` of object `obj_id`, or to the kernel
+    /// CCA `tcp_ca` if `obj_id` is [`KERNEL_CCA_OBJ_ID`].
+    Migrate {
+        obj_id: u32,
+        tcp_ca: String,
+    },
     QoEUpdate {
         qoe: FrameQoE,
     },
+    /// A connection of flow id `flow_id` was enrolled by the sockops hook, sent by the
+    /// manager itself. Clients sending it are refused.
+    Enrolled {
+        obj_id: u32,
+    },
+    /// Policy applied to the flow, as a JSON encoded [`FlowPolicy`].
+    ///
+    /// [`FlowPolicy`]: crate::policy::FlowPolicy
+    PolicyLookup,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum PyOperation {
-    Disconnect { flow_id: u32 },
-    Connect { flow_id: u32 },
+    Disconnect {
+        flow_id: u32,
+    },
+    /// Start tuning a flow, unless its policy gives the tuning to another `strategy`.
+    Connect {
+        flow_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        strategy: Option<Strategy>,
+    },
+    /// The flow switched to object `obj_id`, [`KERNEL_CCA_OBJ_ID`] if it left mortise.
+    Migrate {
+        flow_id: u32,
+        obj_id: u32,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Readable, Writable)]
 pub enum Operation {
     Manager(ManagerOperation),
-    Flow { flow_id: u32, op: FlowOperation },
+    Flow {
+        flow_id: u32,
+        op: FlowOperation,
+    },
+    /// Execute a list of operations in one pass of the manager.
+    ///
+    /// The response is a JSON encoded [`BatchResponse`] with one result per operation.
+    /// If `atomic` is set, the batch is aborted at the first failure and the map updates
+    /// already applied are rolled back, so only operations that can be undone are accepted.
+    Batch {
+        ops: Vec<Operation>,
+        #[serde(default)]
+        atomic: bool,
+    },
+}
+
+/// Per-operation results of an [`Operation::Batch`], in the order of the requests.
+pub type BatchResponse = Vec<std::result::Result<Vec<u8>, String>>;
+
+/// Request frame tagged with a correlation id chosen by the client.
+///
+/// Tagged requests are processed concurrently by the manager and answered with a
+/// [`ManagerResponse`] carrying the same id, possibly out of order. A plain [`Operation`]
+/// frame is still accepted and answered in lockstep without id.
+#[derive(Debug, Deserialize, Serialize, Readable, Writable)]
+pub struct ManagerRequest {
+    pub id: u64,
+    pub op: Operation,
+}
+
+/// Response frame to a [`ManagerRequest`] of the same id.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ManagerResponse {
+    pub id: u64,
+    pub resp: std::result::Result<Vec<u8>, String>,
 }
 
 impl From<ManagerOperation> for Operation {
@@ -85,14 +231,75 @@ impl From<ManagerOperation> for Operation {
     }
 }
 
+impl Operation {
+    pub fn batch(ops: Vec<Operation>, atomic: bool) -> Self {
+        Operation::Batch { ops, atomic }
+    }
+
+    pub fn with_id(self, id: u64) -> ManagerRequest {
+        ManagerRequest { id, op: self }
+    }
+}
+
 impl FlowOperation {
     pub fn to_op(self, flow_id: u32) -> Operation {
         Operation::Flow { flow_id, op: self }
     }
 }
 
+/// Event of the manager sent to the subscribed clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ManagerEvent {
+    /// The manager is shutting down: the flows are switched to the kernel CCA `fallback_tcp_ca`
+    /// and should be disconnected within `drain_ms`.
+    Shutdown {
+        fallback_tcp_ca: String,
+        drain_ms: u64,
+    },
+}
+
+/// Flows handed back to the fallback CCA on shutdown.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShutdownReport {
+    pub fallback_tcp_ca: String,
+    pub migrated: Vec<u32>,
+    /// Flows whose CCA could not be switched, with the reason.
+    pub failed: Vec<(u32, String)>,
+    /// Flows enrolled by the sockops hook, whose socket the manager does not hold.
+    pub unmanaged: Vec<u32>,
+    /// Flows still connected when the drain timed out.
+    pub remaining: u64,
+}
+
 #[derive(Debug)]
 pub struct ManagerIpcOperation {
     pub req: Operation,
     pub resp: oneshot::Sender<Result<Vec<u8>>>,
+    /// Uid of the client sending the request, `None` for the requests of the manager.
+    pub peer_uid: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlowOperation, Operation};
+
+    #[test]
+    fn test_batch_default_non_atomic() {
+        let req = r#"{"Batch":{"ops":[{"Manager":"PingPong"},{"Flow":{"flow_id":1,"op":"Disconnect"}}]}}"#;
+        let op: Operation = serde_json::from_str(req).unwrap();
+        match op {
+            Operation::Batch { ops, atomic } => {
+                assert!(!atomic);
+                assert_eq!(ops.len(), 2);
+                assert!(matches!(
+                    ops[1],
+                    Operation::Flow {
+                        flow_id: 1,
+                        op: FlowOperation::Disconnect
+                    }
+                ));
+            }
+            _ => panic!("expect a batch operation"),
+        }
+    }
 }
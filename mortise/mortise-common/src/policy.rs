@@ -0,0 +1,279 @@
+//! Operator policy assigning a CCA and its parameters to the flows connecting without
+//! an explicit choice.
+//!
+//! The policy file is a JSON object with a list of `rules` and an optional `default`
+//! action. Rules are tried in order and the first one whose `match` holds for the flow
+//! is applied; a missing match field matches any flow.
+//!
+//! ```json
+//! {
+//!     "rules": [
+//!         {
+//!             "name": "video",
+//!             "match": { "dst_prefix": "10.0.0.0/8", "tag": "video" },
+//!             "action": {
+//!                 "obj_id": 1, "tcp_ca": "mortise_copa", "app_info": 50,
+//!                 "qoe_model": "frame", "strategy": "qoe"
+//!             }
+//!         }
+//!     ],
+//!     "default": { "obj_id": 0, "tcp_ca": "cubic" }
+//! }
+//! ```
+
+use crate::qoe::FrameQoE;
+use crate::{MortiseError, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Address prefix like `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl TryFrom<String> for IpPrefix {
+    type Error = MortiseError;
+
+    fn try_from(value: String) -> Result<Self> {
+        let invalid = || MortiseError::InvalidPolicy(format!("invalid prefix {}", value));
+        let (addr, len) = match value.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (value.as_str(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if len > max_len {
+            return Err(invalid());
+        }
+        Ok(IpPrefix { addr, len })
+    }
+}
+
+impl From<IpPrefix> for String {
+    fn from(value: IpPrefix) -> Self {
+        format!("{}/{}", value.addr, value.len)
+    }
+}
+
+impl IpPrefix {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of a dual-stack socket show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(prefix), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(prefix) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(prefix) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Attributes of a connecting flow checked by the policy.
+#[derive(Debug, Clone, Default)]
+pub struct FlowAttrs {
+    pub dst: Option<IpAddr>,
+    pub local_port: Option<u16>,
+    pub process: Option<String>,
+    pub uid: Option<u32>,
+    pub cgroup: Option<String>,
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyMatch {
+    pub dst_prefix: Option<IpPrefix>,
+    pub local_port: Option<u16>,
+    /// Name of the process, as in `/proc/<pid>/comm`.
+    pub process: Option<String>,
+    pub uid: Option<u32>,
+    /// Prefix of the cgroup v2 path of the process.
+    pub cgroup: Option<String>,
+    /// Tag given by the application when connecting.
+    pub tag: Option<String>,
+}
+
+impl PolicyMatch {
+    pub fn matches(&self, attrs: &FlowAttrs) -> bool {
+        fn check<T, U>(rule: &Option<T>, attr: &Option<U>, f: impl Fn(&T, &U) -> bool) -> bool {
+            match (rule, attr) {
+                (None, _) => true,
+                (Some(rule), Some(attr)) => f(rule, attr),
+                (Some(_), None) => false,
+            }
+        }
+        check(&self.dst_prefix, &attrs.dst, |p, ip| p.contains(*ip))
+            && check(&self.local_port, &attrs.local_port, |p, port| p == port)
+            && check(&self.process, &attrs.process, |p, name| p == name)
+            && check(&self.uid, &attrs.uid, |p, uid| p == uid)
+            && check(&self.cgroup, &attrs.cgroup, |p, path| {
+                path.starts_with(p.as_str())
+            })
+            && check(&self.tag, &attrs.tag, |p, tag| p == tag)
+    }
+}
+
+/// Score given to the QoE updates of a flow, from which the manager derives its trade-off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QoEModel {
+    /// [`FrameQoE::score`], the quality of the frame minus its delay penalty.
+    #[default]
+    Frame,
+    /// [`FrameQoE::delay_score`], for flows whose bitrate does not matter.
+    Delay,
+}
+
+impl QoEModel {
+    pub fn score(&self, qoe: &FrameQoE) -> f64 {
+        match self {
+            QoEModel::Frame => qoe.score(),
+            QoEModel::Delay => qoe.delay_score(),
+        }
+    }
+}
+
+/// Who tunes the trade-off of a flow after it connected. Without a strategy both the
+/// manager and the python strategies do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// The manager, from the QoE updates sent by the application.
+    Qoe,
+    /// The python strategies, from the reports of the CCA.
+    Report,
+    /// Nobody, the flow keeps its initial `app_info`.
+    Static,
+}
+
+/// CCA and parameters given to the matched flows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyAction {
+    /// Object of the struct_ops, or [`KERNEL_CCA_OBJ_ID`] for a kernel CCA.
+    ///
+    /// [`KERNEL_CCA_OBJ_ID`]: crate::KERNEL_CCA_OBJ_ID
+    pub obj_id: u32,
+    pub tcp_ca: String,
+    /// Initial value of the tunable in `sk_stg_map`.
+    pub app_info: Option<u64>,
+    pub qoe_model: Option<QoEModel>,
+    pub strategy: Option<Strategy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub matches: PolicyMatch,
+    pub action: PolicyAction,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+    pub default: Option<PolicyAction>,
+}
+
+impl Policy {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| MortiseError::InvalidPolicy(format!("{}: {}", path, e)))
+    }
+
+    /// Index of the first rule matching the flow, `None` if only the default applies.
+    pub fn lookup(&self, attrs: &FlowAttrs) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|rule| rule.matches.matches(attrs))
+    }
+}
+
+/// Policy applied to a flow, returned by `FlowOperation::PolicyLookup`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowPolicy {
+    /// Name of the matched rule, `None` for the default action.
+    pub rule: Option<String>,
+    pub action: PolicyAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRuleHits {
+    pub name: String,
+    pub hits: u64,
+}
+
+/// JSON encoded response of `ManagerOperation::PolicyStats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyReport {
+    pub path: String,
+    pub rules: Vec<PolicyRuleHits>,
+    pub default_hits: u64,
+    /// Flows matched by no rule while there is no default action.
+    pub misses: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlowAttrs, Policy, QoEModel, Strategy};
+
+    #[test]
+    fn test_policy_first_match() {
+        let policy: Policy = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "name": "video",
+                        "match": { "dst_prefix": "10.1.0.0/16", "tag": "video" },
+                        "action": {
+                            "obj_id": 1, "tcp_ca": "mortise_copa", "app_info": 50,
+                            "qoe_model": "delay", "strategy": "static"
+                        }
+                    },
+                    {
+                        "name": "lan",
+                        "match": { "dst_prefix": "10.0.0.0/8" },
+                        "action": { "obj_id": 0, "tcp_ca": "cubic" }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut attrs = FlowAttrs {
+            dst: Some("10.1.2.3".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(policy.lookup(&attrs), Some(1));
+        attrs.tag = Some("video".to_string());
+        assert_eq!(policy.lookup(&attrs), Some(0));
+        attrs.dst = Some("::ffff:10.2.0.1".parse().unwrap());
+        assert_eq!(policy.lookup(&attrs), Some(1));
+        attrs.dst = Some("192.168.0.1".parse().unwrap());
+        assert_eq!(policy.lookup(&attrs), None);
+        assert_eq!(policy.rules[0].action.qoe_model, Some(QoEModel::Delay));
+        assert_eq!(policy.rules[0].action.strategy, Some(Strategy::Static));
+        assert!(serde_json::from_str::<Policy>(
+            r#"{ "default": { "obj_id": 0, "tcp_ca": "cubic", "strategy": "bandit" } }"#
+        )
+        .is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use plain::Plain;
 use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
 use std::time::Duration;
 
 const BASE_SSIM: f64 = 14.4;
@@ -8,8 +9,9 @@ const HIGH_SSIM: f64 = 19.7 - BASE_SSIM;
 const DELAY_IGNORE_THRESHOLD: f64 = 80.0;
 const DELAY_DDL: f64 = 120.0;
 const DELAY_LIMIT: f64 = 150.0;
+const MAX_DELAY_SCORE: f64 = 8.0;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Readable, Writable)]
 pub struct FrameQoE {
     pub server_send: u64,
     pub client_recv: u64,
@@ -60,6 +62,12 @@ impl FrameQoE {
         }
     }
 
+    /// Score of the delay alone, on the scale of [`FrameQoE::score`] with a frame of
+    /// maximal quality.
+    pub fn delay_score(&self) -> f64 {
+        MAX_DELAY_SCORE - self.delay_punish()
+    }
+
     pub fn score(&self) -> f64 {
         // TODO: change SSIM function
         // -1.92 * 0.001 * delay + 0.101 * ssim + 2.67
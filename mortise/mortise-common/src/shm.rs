@@ -0,0 +1,290 @@
+//! Single-producer single-consumer ring of [`ReportEntry`] in shared memory.
+//!
+//! The manager is the producer: it writes the decoded reports of the ring buffer into
+//! one ring per strategy consumer, and rings the consumer's doorbell (a unix datagram
+//! socket) when the ring turns non-empty. The consumer reads the entries in place and
+//! only moves the tail after it is done with them.
+//!
+//! Layout of the shared memory: a [`ShmRingHeader`] followed by `capacity` slots of
+//! [`ReportEntry`]. `head` is only written by the producer and `tail` only by the
+//! consumer. When the ring is full the new entry is dropped and `overruns` is increased.
+
+use crate::report::ReportEntry;
+use crate::{MortiseError, Result};
+use shared_memory::{Shmem, ShmemConf};
+use std::os::unix::net::UnixDatagram;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const SHM_RING_MAGIC: u64 = 0x4d4f_5254_5250_5430; // "MORTRPT0"
+
+/// Name of the shared memory holding the report ring of a consumer.
+pub fn shm_report_os_id(name: &str) -> String {
+    format!("mortise-report-{name}")
+}
+
+#[repr(C)]
+pub struct ShmRingHeader {
+    pub magic: u64,
+    pub capacity: u64,
+    pub entry_size: u64,
+    pub overruns: AtomicU64,
+    _pad0: [u64; 4],
+    // head and tail are kept in different cache lines
+    pub head: AtomicU64,
+    _pad1: [u64; 7],
+    pub tail: AtomicU64,
+    _pad2: [u64; 7],
+}
+
+pub enum RingPush {
+    /// The ring was empty, the consumer should be woken up.
+    Wakeup,
+    Queued,
+    /// The ring is full and the entry is dropped, with the total number of overruns.
+    Overrun(u64),
+}
+
+pub struct ReportRing {
+    base: NonNull<u8>,
+    capacity: u64,
+}
+
+impl ReportRing {
+    /// Size of the shared memory needed by a ring of `capacity` entries.
+    pub fn mem_size(capacity: u32) -> usize {
+        std::mem::size_of::<ShmRingHeader>()
+            + capacity as usize * std::mem::size_of::<ReportEntry>()
+    }
+
+    /// Initialize a new ring in the memory.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for `len` bytes, aligned to 8 bytes and outlive the ring.
+    pub unsafe fn init(ptr: *mut u8, len: usize, capacity: u32) -> Result<Self> {
+        if capacity == 0 || len < Self::mem_size(capacity) {
+            return Err(MortiseError::ShmError(format!(
+                "{len} bytes is too small for {capacity} report entries"
+            )));
+        }
+        let base = NonNull::new(ptr)
+            .ok_or_else(|| MortiseError::ShmError("Null shared memory".to_string()))?;
+        let header = ShmRingHeader {
+            magic: SHM_RING_MAGIC,
+            capacity: capacity as u64,
+            entry_size: std::mem::size_of::<ReportEntry>() as u64,
+            overruns: AtomicU64::new(0),
+            _pad0: [0; 4],
+            head: AtomicU64::new(0),
+            _pad1: [0; 7],
+            tail: AtomicU64::new(0),
+            _pad2: [0; 7],
+        };
+        std::ptr::write(ptr as *mut ShmRingHeader, header);
+        Ok(ReportRing {
+            base,
+            capacity: capacity as u64,
+        })
+    }
+
+    /// Attach to a ring initialized by the producer.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for `len` bytes, aligned to 8 bytes and outlive the ring.
+    pub unsafe fn attach(ptr: *mut u8, len: usize) -> Result<Self> {
+        let base = NonNull::new(ptr)
+            .ok_or_else(|| MortiseError::ShmError("Null shared memory".to_string()))?;
+        if len < std::mem::size_of::<ShmRingHeader>() {
+            return Err(MortiseError::ShmError(
+                "Shared memory is too small".to_string(),
+            ));
+        }
+        let header = &*(ptr as *const ShmRingHeader);
+        if header.magic != SHM_RING_MAGIC {
+            return Err(MortiseError::ShmError("Not a report ring".to_string()));
+        }
+        if header.entry_size != std::mem::size_of::<ReportEntry>() as u64 {
+            return Err(MortiseError::ShmError(format!(
+                "Report entry size mismatch: {} in ring, {} expected",
+                header.entry_size,
+                std::mem::size_of::<ReportEntry>()
+            )));
+        }
+        if len < Self::mem_size(header.capacity as u32) {
+            return Err(MortiseError::ShmError(
+                "Shared memory is too small".to_string(),
+            ));
+        }
+        Ok(ReportRing {
+            base,
+            capacity: header.capacity,
+        })
+    }
+
+    fn header(&self) -> &ShmRingHeader {
+        unsafe { &*(self.base.as_ptr() as *const ShmRingHeader) }
+    }
+
+    fn slot(&self, idx: u64) -> *mut ReportEntry {
+        let offset = std::mem::size_of::<ShmRingHeader>()
+            + (idx % self.capacity) as usize * std::mem::size_of::<ReportEntry>();
+        unsafe { self.base.as_ptr().add(offset) as *mut ReportEntry }
+    }
+
+    /// Append an entry. Must only be called by the producer.
+    pub fn push(&self, entry: &ReportEntry) -> RingPush {
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        let tail = header.tail.load(Ordering::Acquire);
+        // A tail moved past head by a faulty consumer reads as a full ring
+        if head.wrapping_sub(tail) >= self.capacity {
+            return RingPush::Overrun(header.overruns.fetch_add(1, Ordering::Relaxed) + 1);
+        }
+        unsafe { std::ptr::write(self.slot(head), entry.clone()) };
+        header.head.store(head.wrapping_add(1), Ordering::Release);
+        if head == tail {
+            RingPush::Wakeup
+        } else {
+            RingPush::Queued
+        }
+    }
+
+    /// Oldest entry not consumed yet. Must only be called by the consumer.
+    pub fn peek(&self) -> Option<&ReportEntry> {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        let head = header.head.load(Ordering::Acquire);
+        if tail == head {
+            None
+        } else {
+            Some(unsafe { &*self.slot(tail) })
+        }
+    }
+
+    /// Release the oldest entry to the producer. Must only be called by the consumer.
+    pub fn advance(&self) {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        if tail != header.head.load(Ordering::Acquire) {
+            header.tail.store(tail.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        let header = self.header();
+        let head = header.head.load(Ordering::Acquire);
+        head.wrapping_sub(header.tail.load(Ordering::Acquire))
+            .min(self.capacity)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.header().overruns.load(Ordering::Relaxed)
+    }
+}
+
+/// Consumer side of a report ring registered with `ManagerOperation::RegisterShmReport`.
+pub struct ShmReportReader {
+    _shmem: Shmem,
+    ring: ReportRing,
+    doorbell: UnixDatagram,
+}
+
+impl ShmReportReader {
+    /// Open the ring of consumer `name`. `doorbell` is the socket bound to the doorbell
+    /// path given at registration.
+    pub fn open(name: &str, doorbell: UnixDatagram) -> Result<Self> {
+        let shmem = ShmemConf::new()
+            .os_id(shm_report_os_id(name))
+            .open()
+            .map_err(|e| MortiseError::ShmError(e.to_string()))?;
+        let ring = unsafe { ReportRing::attach(shmem.as_ptr(), shmem.len())? };
+        Ok(ShmReportReader {
+            _shmem: shmem,
+            ring,
+            doorbell,
+        })
+    }
+
+    /// Block until the producer rings the doorbell or the timeout expires.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<()> {
+        if !self.ring.is_empty() {
+            return Ok(());
+        }
+        self.doorbell.set_read_timeout(timeout)?;
+        let mut buf = [0u8; 8];
+        match self.doorbell.recv(&mut buf) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Pass all the available entries to `f` in place, returning the number of entries.
+    pub fn drain<F: FnMut(&ReportEntry)>(&mut self, mut f: F) -> usize {
+        let mut cnt = 0;
+        while let Some(entry) = self.ring.peek() {
+            f(entry);
+            self.ring.advance();
+            cnt += 1;
+        }
+        cnt
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.ring.overruns()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReportRing, RingPush};
+    use crate::report::ReportEntry;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_ring_overrun() {
+        // u64 backing memory keeps the ring aligned
+        let mut mem = vec![0u64; ReportRing::mem_size(2) / 8 + 1];
+        let len = mem.len() * 8;
+        let ring = unsafe { ReportRing::init(mem.as_mut_ptr() as *mut u8, len, 2).unwrap() };
+        let mut entry = ReportEntry::default();
+        for flow_id in 1..=3 {
+            entry.flow_id = flow_id;
+            match (flow_id, ring.push(&entry)) {
+                (1, RingPush::Wakeup) | (2, RingPush::Queued) | (3, RingPush::Overrun(1)) => {}
+                (flow_id, _) => panic!("unexpected push result of entry {flow_id}"),
+            }
+        }
+        assert_eq!(ring.peek().unwrap().flow_id, 1);
+        ring.advance();
+        assert_eq!(ring.peek().unwrap().flow_id, 2);
+        ring.advance();
+        assert!(ring.peek().is_none());
+        assert_eq!(ring.overruns(), 1);
+    }
+
+    #[test]
+    fn test_ring_corrupt_tail() {
+        let mut mem = vec![0u64; ReportRing::mem_size(2) / 8 + 1];
+        let len = mem.len() * 8;
+        let ring = unsafe { ReportRing::init(mem.as_mut_ptr() as *mut u8, len, 2).unwrap() };
+        ring.header().tail.store(5, Ordering::Relaxed);
+        assert!(matches!(
+            ring.push(&ReportEntry::default()),
+            RingPush::Overrun(1)
+        ));
+        assert_eq!(ring.len(), 2);
+    }
+}
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+/// Where a loaded object comes from, recorded when it is opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectProvenance {
+    /// Hex encoded SHA-256 of the object file.
+    pub sha256: String,
+    /// Uid of the client asking for the load, the manager's own uid for its defaults.
+    pub loader_uid: u32,
+    /// Seconds since the unix epoch.
+    pub loaded_at: u64,
+}
+
+/// Entry of the JSON encoded response of `ManagerOperation::ListObjects`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    pub obj_id: u32,
+    pub path: String,
+    pub provenance: ObjectProvenance,
+    pub flows: u64,
+    /// Inner map pool of each `SkArrayMap` of the connect option.
+    pub map_pools: Vec<MapPoolStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MapPoolStats {
+    /// Cleared maps waiting for a flow.
+    pub free: u64,
+    /// Maps created since the object was loaded.
+    pub created: u64,
+    /// Flows served by a map of the pool.
+    pub reused: u64,
+}
@@ -0,0 +1,73 @@
+//! Compare the connect latency of flows with and without the inner map pool.
+//!
+//! Short flows connect and disconnect in turn while `CONCURRENT_FLOWS` of them stay
+//! connected, as with many short downloads. The number of inner maps created by the
+//! manager is reported next to the latency.
+//!
+//! Needs root and the built object: run with
+//! `sudo -E cargo bench -p mortise-manager --bench connect`, setting
+//! `MORTISE_BENCH_OBJ` to the path of `mortise_copa.bpf.o` if it is not at the default
+//! load path.
+
+use mortise_common::{CongestionOpt, ManagerOperation};
+use mortise_manager::{pool::DEFAULT_MAP_POOL_SIZE, MortiseManager};
+use std::collections::VecDeque;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+const FLOWS: u32 = 2_000;
+const CONCURRENT_FLOWS: usize = 8;
+
+fn bench_connect(map_pool_size: usize) -> (Duration, Duration, u64, u64) {
+    let (path, option) = match CongestionOpt::MortiseCopa.get_load_option() {
+        ManagerOperation::Load { path, option } => (path, option),
+        _ => unreachable!(),
+    };
+    let path = std::env::var("MORTISE_BENCH_OBJ").unwrap_or(path);
+    let mut m = MortiseManager::new();
+    m.map_pool_size = map_pool_size;
+    let obj_id = m.open_and_load_object(path, option, 0).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let pid = std::process::id() as i32;
+    let mut connected = VecDeque::new();
+    let mut latencies = Vec::with_capacity(FLOWS as usize);
+    for _ in 0..FLOWS {
+        let stream = TcpStream::connect(addr).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        let flow_id = m
+            .connect(pid, Some(obj_id), stream.as_raw_fd(), None, None)
+            .unwrap();
+        latencies.push(start.elapsed());
+        connected.push_back((flow_id, stream, peer));
+        if connected.len() > CONCURRENT_FLOWS {
+            let (flow_id, _, _) = connected.pop_front().unwrap();
+            m.disconnect(flow_id).unwrap();
+        }
+    }
+    for (flow_id, _, _) in connected.drain(..) {
+        m.disconnect(flow_id).unwrap();
+    }
+    let stats = &m.list_objects()[0].map_pools;
+    let created = stats.iter().map(|pool| pool.created).sum();
+    let reused = stats.iter().map(|pool| pool.reused).sum();
+    m.shutdown().unwrap();
+
+    latencies.sort();
+    let mean = latencies.iter().sum::<Duration>() / FLOWS;
+    let p99 = latencies[latencies.len() * 99 / 100];
+    (mean, p99, created, reused)
+}
+
+fn main() {
+    for map_pool_size in [0, DEFAULT_MAP_POOL_SIZE] {
+        let (mean, p99, created, reused) = bench_connect(map_pool_size);
+        println!(
+            "pool of {:>2}: {:.2?} mean, {:.2?} p99 per connect, {} inner maps created, {} reused",
+            map_pool_size, mean, p99, created, reused
+        );
+    }
+}
@@ -1,7 +1,14 @@
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
 use futures::{SinkExt, StreamExt};
-use mortise_common::{read_be_u32, ManagerOperation, Operation};
+use mortise_common::{
+    canary::{CanaryAssign, CanaryConfig, CanaryReport},
+    policy::PolicyReport,
+    read_be_u32,
+    shm::{shm_report_os_id, ShmReportReader},
+    store::ObjectInfo,
+    BatchResponse, FlowOperation, ManagerEvent, ManagerOperation, Operation,
+};
 use tokio::net::{
     unix::{ReadHalf, WriteHalf},
     UnixStream,
@@ -24,6 +31,28 @@ enum Commands {
     Unload(UnloadArgs),
     /// Insert bpf struct_ops into kernel
     Insert(InsertArgs),
+    /// Replace a loaded bpf struct_ops without disconnecting its flows
+    Upgrade(UpgradeArgs),
+    /// Switch a connected flow to another bpf struct_ops or kernel CCA
+    Migrate(MigrateArgs),
+    /// Send a batch of operations read from a JSON file
+    Batch(BatchArgs),
+    /// Use a bpf struct_ops for all new connections of some cgroups
+    Enroll(EnrollArgs),
+    /// Stop enrolling the connections of the cgroups
+    Unenroll,
+    /// List the loaded objects with their hash and loader
+    Objects,
+    /// Wait for the next event of the manager, e.g. its shutdown
+    Subscribe,
+    /// Print the reports of the flows, read from a shared-memory ring
+    Reports(ReportsArgs),
+    /// Inspect or reload the policy of the manager
+    #[command(subcommand)]
+    Policy(PolicyCommands),
+    /// Roll out a new version of a bpf struct_ops on part of the flows
+    #[command(subcommand)]
+    Canary(CanaryCommands),
     /// Send ping-pong to manager
     Ping,
     /// Enter interactive mode
@@ -32,9 +61,23 @@ enum Commands {
     Quit,
 }
 
+/// Objects are sent by absolute path, or by name to a manager with an object directory.
+fn object_path(path: &str, name: bool) -> std::io::Result<String> {
+    if name {
+        return Ok(path.to_string());
+    }
+    Ok(std::path::Path::new(path)
+        .canonicalize()?
+        .display()
+        .to_string())
+}
+
 #[derive(Args, Debug)]
 struct LoadArgs {
     path: String,
+    /// Name of the object in the object directory of the manager, instead of a local path
+    #[arg(long)]
+    name: bool,
 }
 
 #[derive(Args, Debug)]
@@ -46,6 +89,119 @@ struct UnloadArgs {
 struct InsertArgs {
     obj_id: u32,
     path: String,
+    /// Name of the object in the object directory of the manager, instead of a local path
+    #[arg(long)]
+    name: bool,
+}
+
+#[derive(Args, Debug)]
+struct UpgradeArgs {
+    obj_id: u32,
+    path: String,
+    /// Name of the object in the object directory of the manager, instead of a local path
+    #[arg(long)]
+    name: bool,
+}
+
+#[derive(Args, Debug)]
+struct MigrateArgs {
+    flow_id: u32,
+    /// Object of the struct_ops, 0 for a kernel CCA
+    obj_id: u32,
+    /// Name of the tcp congestion control
+    tcp_ca: String,
+}
+
+#[derive(Args, Debug)]
+struct EnrollArgs {
+    obj_id: u32,
+    /// Path of the object holding the sockops program
+    path: String,
+    /// Name of the object in the object directory of the manager, instead of a local path
+    #[arg(long)]
+    name: bool,
+    /// Name of the tcp congestion control of the struct_ops
+    tcp_ca: String,
+    /// Paths of the cgroup v2 directories
+    #[arg(required = true)]
+    cgroups: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct ReportsArgs {
+    /// Name of the ring
+    #[arg(long, default_value = "manager-cli")]
+    name: String,
+    /// Number of reports the ring holds
+    #[arg(long, default_value_t = 1024)]
+    capacity: u32,
+    /// Stop after this many reports
+    #[arg(long)]
+    count: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+struct BatchArgs {
+    /// JSON file holding a list of operations
+    path: String,
+    /// Roll back all the operations if any of them fails
+    #[arg(long)]
+    atomic: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum PolicyCommands {
+    /// Show the hits of the policy rules
+    Show,
+    /// Load a policy file, or reload the current one
+    Reload { path: Option<String> },
+}
+
+#[derive(Subcommand, Debug)]
+enum CanaryCommands {
+    /// Assign a share of the flows connecting to the stable object to the canary
+    Start(CanaryStartArgs),
+    /// Show the QoE and RCT summaries of both versions
+    Stats,
+    /// Assign all the new flows to the canary
+    Promote,
+    /// Stop the rollout and switch the canary flows back to the stable object
+    Rollback,
+}
+
+#[derive(Args, Debug)]
+struct CanaryStartArgs {
+    stable: u32,
+    canary: u32,
+    /// Name of the tcp congestion control registered by the canary object
+    tcp_ca: String,
+    /// Name of the tcp congestion control registered by the stable object
+    #[arg(long, default_value = "mortise_copa")]
+    stable_tcp_ca: String,
+    #[arg(long, default_value_t = 10)]
+    percent: u8,
+    #[arg(long, value_enum, default_value_t = CanaryAssign::Percent)]
+    assign: CanaryAssign,
+}
+
+fn print_canary_report(report: &CanaryReport) {
+    println!(
+        "Canary {} -> {} at {}% ({:?})",
+        report.config.stable, report.config.canary, report.config.percent, report.config.assign
+    );
+    for (version, summary) in [("stable", &report.stable), ("canary", &report.canary)] {
+        println!(
+            "{version}: obj_id {}, {} flows, mean qoe {:.3} ({} samples), rct mean/p50/p95 {}/{}/{} us ({} samples)",
+            summary.obj_id,
+            summary.flows,
+            summary.mean_qoe,
+            summary.qoe_samples,
+            summary.mean_rct_us,
+            summary.p50_rct_us,
+            summary.p95_rct_us,
+            summary.rct_samples
+        );
+    }
 }
 
 async fn handle_command(
@@ -55,9 +211,7 @@ async fn handle_command(
 ) -> Result<u32> {
     match cli.command {
         Commands::Load(args) => {
-            let path = std::path::Path::new(&args.path);
-            let path = path.canonicalize()?;
-            let path = path.display().to_string();
+            let path = object_path(&args.path, args.name)?;
             let req: Operation = ManagerOperation::Load { path, option: None }.into();
             let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
             writer.send(req_bytes).await?;
@@ -89,9 +243,7 @@ async fn handle_command(
             }
         }
         Commands::Insert(args) => {
-            let path = std::path::Path::new(&args.path);
-            let path = path.canonicalize()?;
-            let path = path.display().to_string();
+            let path = object_path(&args.path, args.name)?;
             let req: Operation = ManagerOperation::Insert {
                 obj_id: args.obj_id,
                 path,
@@ -108,6 +260,241 @@ async fn handle_command(
                 Err(e) => println!("Failed to insert: {e}"),
             }
         }
+        Commands::Upgrade(args) => {
+            let path = object_path(&args.path, args.name)?;
+            let req: Operation = ManagerOperation::Upgrade {
+                obj_id: args.obj_id,
+                path,
+            }
+            .into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(_) => println!("Upgraded object with id {}", args.obj_id),
+                Err(e) => println!("Failed to upgrade: {e}"),
+            }
+        }
+        Commands::Enroll(args) => {
+            let path = object_path(&args.path, args.name)?;
+            let req: Operation = ManagerOperation::Enroll {
+                obj_id: args.obj_id,
+                path,
+                tcp_ca: args.tcp_ca,
+                cgroups: args.cgroups,
+            }
+            .into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(_) => println!("Enrolled cgroups to object with id {}", args.obj_id),
+                Err(e) => println!("Failed to enroll: {e}"),
+            }
+        }
+        Commands::Unenroll => {
+            let req: Operation = ManagerOperation::Unenroll.into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(_) => println!("Unenrolled"),
+                Err(e) => println!("Failed to unenroll: {e}"),
+            }
+        }
+        Commands::Objects => {
+            let req: Operation = ManagerOperation::ListObjects.into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(r) => {
+                    let objs: Vec<ObjectInfo> = serde_json::from_slice(&r)?;
+                    for obj in objs {
+                        println!(
+                            "{}\t{}\tsha256 {}\tuid {}\tloaded at {}\t{} flows",
+                            obj.obj_id,
+                            obj.path,
+                            obj.provenance.sha256,
+                            obj.provenance.loader_uid,
+                            obj.provenance.loaded_at,
+                            obj.flows
+                        );
+                        for (idx, pool) in obj.map_pools.iter().enumerate() {
+                            println!(
+                                "\tinner map {}: {} free, {} created, {} reused",
+                                idx, pool.free, pool.created, pool.reused
+                            );
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to list objects: {e}"),
+            }
+        }
+        Commands::Subscribe => {
+            let req: Operation = ManagerOperation::Subscribe.into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(r) => {
+                    let event: ManagerEvent = serde_json::from_slice(&r)?;
+                    println!("{:?}", event);
+                }
+                Err(e) => println!("Failed to subscribe: {e}"),
+            }
+        }
+        Commands::Policy(command) => {
+            let op = match command {
+                PolicyCommands::Show => ManagerOperation::PolicyStats,
+                PolicyCommands::Reload { path } => {
+                    let path = match path {
+                        Some(path) => Some(
+                            std::path::Path::new(&path)
+                                .canonicalize()?
+                                .display()
+                                .to_string(),
+                        ),
+                        None => None,
+                    };
+                    ManagerOperation::ReloadPolicy { path }
+                }
+            };
+            let req: Operation = op.into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(r) if r.is_empty() => println!("Policy loaded"),
+                Ok(r) => {
+                    let report: PolicyReport = serde_json::from_slice(&r)?;
+                    println!("Policy {}", report.path);
+                    for rule in report.rules {
+                        println!("{}: {} hits", rule.name, rule.hits);
+                    }
+                    println!("default: {} hits", report.default_hits);
+                    println!("no match: {}", report.misses);
+                }
+                Err(e) => println!("Failed: {e}"),
+            }
+        }
+        Commands::Canary(command) => {
+            let op = match command {
+                CanaryCommands::Start(args) => ManagerOperation::StartCanary {
+                    config: CanaryConfig {
+                        stable: args.stable,
+                        canary: args.canary,
+                        stable_tcp_ca: args.stable_tcp_ca,
+                        canary_tcp_ca: args.tcp_ca,
+                        percent: args.percent,
+                        assign: args.assign,
+                    },
+                },
+                CanaryCommands::Stats => ManagerOperation::CanaryStats,
+                CanaryCommands::Promote => ManagerOperation::PromoteCanary,
+                CanaryCommands::Rollback => ManagerOperation::RollbackCanary,
+            };
+            let req: Operation = op.into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(r) if r.is_empty() => println!("Canary started"),
+                Ok(r) => print_canary_report(&serde_json::from_slice(&r)?),
+                Err(e) => println!("Failed: {e}"),
+            }
+        }
+        Commands::Migrate(args) => {
+            let req = FlowOperation::Migrate {
+                obj_id: args.obj_id,
+                tcp_ca: args.tcp_ca,
+            }
+            .to_op(args.flow_id);
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(_) => println!("Migrated flow {} to object {}", args.flow_id, args.obj_id),
+                Err(e) => println!("Failed to migrate: {e}"),
+            }
+        }
+        Commands::Batch(args) => {
+            let ops: Vec<Operation> = serde_json::from_reader(std::fs::File::open(&args.path)?)?;
+            let req = Operation::batch(ops, args.atomic);
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(r) => {
+                    let results: BatchResponse = serde_json::from_slice(&r)?;
+                    for (idx, res) in results.into_iter().enumerate() {
+                        match res {
+                            Ok(_) => println!("[{idx}] Ok"),
+                            Err(e) => println!("[{idx}] Failed: {e}"),
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to execute batch: {e}"),
+            }
+        }
+        Commands::Reports(args) => {
+            let doorbell_path =
+                std::env::temp_dir().join(format!("{}.sock", shm_report_os_id(&args.name)));
+            let _ = std::fs::remove_file(&doorbell_path);
+            let doorbell = std::os::unix::net::UnixDatagram::bind(&doorbell_path)?;
+            let req: Operation = ManagerOperation::RegisterShmReport {
+                name: args.name.clone(),
+                capacity: args.capacity,
+                doorbell: doorbell_path.display().to_string(),
+            }
+            .into();
+            let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+            writer.send(req_bytes).await?;
+            let resp_bytes = reader.next().await.unwrap()?;
+            let resp: std::result::Result<Vec<u8>, String> =
+                serde_json::from_slice(resp_bytes.as_ref())?;
+            match resp {
+                Ok(_) => {
+                    let mut ring = ShmReportReader::open(&args.name, doorbell)?;
+                    let mut received = 0;
+                    while args.count.is_none_or(|count| received < count) {
+                        ring.wait(Some(std::time::Duration::from_secs(1)))?;
+                        received += ring.drain(|entry| {
+                            println!(
+                                "flow {} chunk {} len {}",
+                                entry.flow_id, entry.chunk_id, entry.chunk_len
+                            );
+                        });
+                    }
+                    println!("{} reports, {} dropped", received, ring.overruns());
+                    let req: Operation =
+                        ManagerOperation::UnregisterShmReport { name: args.name }.into();
+                    let req_bytes = serde_json::to_vec(&req).map(Into::into)?;
+                    writer.send(req_bytes).await?;
+                    reader.next().await.unwrap()?;
+                }
+                Err(e) => println!("Failed to register report ring: {e}"),
+            }
+            let _ = std::fs::remove_file(&doorbell_path);
+        }
         Commands::Ping => {
             println!("Ping");
             let req: Operation = ManagerOperation::PingPong.into();
@@ -1,14 +1,41 @@
-use mortise_common::{read_be_u32, CongestionOpt, ManagerIpcOperation, ManagerOperation, Result};
+use clap::Parser;
+use mortise_common::{
+    read_be_u32, CongestionOpt, ManagerIpcOperation, ManagerOperation, Result, ShutdownReport,
+};
 use mortise_manager::*;
-use std::{os::unix::prelude::PermissionsExt, thread};
+use std::{os::unix::prelude::PermissionsExt, thread, time::Duration};
 use tokio::{
     net::UnixListener,
     sync::{mpsc, oneshot},
 };
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+#[derive(Parser, Debug)]
+struct Cli {
+    /// Policy file choosing the CCA of the flows connecting without explicit choice
+    #[arg(long)]
+    policy: Option<String>,
+    /// Directory of the trusted objects, loaded by name once set. Without it only root
+    /// clients load objects, by path
+    #[arg(long)]
+    object_dir: Option<String>,
+    /// PEM encoded ed25519 public key verifying the manifest of the object directory
+    #[arg(long, requires = "object_dir")]
+    object_pubkey: Option<String>,
+    /// Free inner maps kept per map of a loaded object, 0 to create them on connect
+    #[arg(long, default_value_t = pool::DEFAULT_MAP_POOL_SIZE)]
+    map_pool: usize,
+    /// Kernel CCA the flows are switched to on shutdown
+    #[arg(long, default_value = "cubic")]
+    fallback_cca: String,
+    /// Time given to the clients to disconnect their flows on shutdown
+    #[arg(long, default_value_t = 2000)]
+    drain_timeout_ms: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     tracing_subscriber::registry()
         .with(fmt::layer())
@@ -16,20 +43,45 @@ async fn main() -> Result<()> {
         .init();
     // Try to connect to the python process server
     let py_con = connect_py().await;
+    let store = match cli.object_dir {
+        Some(dir) => Some(ObjectStore::open(dir, cli.object_pubkey)?),
+        None => {
+            tracing::warn!(target: "manager:store", "No object directory, only root clients can load objects");
+            None
+        }
+    };
+    let use_store = store.is_some();
     let (manager_tx, manager_rx) = mpsc::channel::<ManagerIpcOperation>(32);
     let inner_manager_tx = manager_tx.clone();
+    let config = ManagerConfig {
+        store,
+        map_pool_size: cli.map_pool,
+        fallback_tcp_ca: cli.fallback_cca.clone(),
+        drain_timeout: Duration::from_millis(cli.drain_timeout_ms),
+        canary_active: Default::default(),
+    };
+    let canary_active = config.canary_active.clone();
     let manager_handle = thread::Builder::new()
         .name("mortise-manager".to_string())
-        .spawn(move || manager(inner_manager_tx, manager_rx, py_con))?;
+        .spawn(move || manager(inner_manager_tx, manager_rx, py_con, config))?;
 
     // Load some default CCAs
     let ca_list = vec![CongestionOpt::MortiseCopa];
 
     for tcp_ca in ca_list {
         let (tx, rx) = oneshot::channel::<Result<Vec<u8>>>();
+        let mut load_op = tcp_ca.get_load_option();
+        if let (true, ManagerOperation::Load { path, .. }) = (use_store, &mut load_op) {
+            // The store only knows the objects by name
+            *path = std::path::Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+        }
         let op = ManagerIpcOperation {
-            req: tcp_ca.get_load_option().into(),
+            req: load_op.into(),
             resp: tx,
+            peer_uid: None,
         };
         manager_tx.send(op).await?;
         let r = match rx.await? {
@@ -48,12 +100,24 @@ async fn main() -> Result<()> {
     let op = ManagerIpcOperation {
         req: ManagerOperation::RegisterRingBuf { obj_ids: vec![1] }.into(),
         resp: tx,
+        peer_uid: None,
     };
     manager_tx.send(op).await?;
     if let Err(e) = rx.await? {
         tracing::error!(target: "manager:register", "Fail to register RingBuf: {:?}", e);
     }
 
+    if let Some(path) = cli.policy {
+        let (tx, rx) = oneshot::channel::<Result<Vec<u8>>>();
+        let op = ManagerIpcOperation {
+            req: ManagerOperation::ReloadPolicy { path: Some(path) }.into(),
+            resp: tx,
+            peer_uid: None,
+        };
+        manager_tx.send(op).await?;
+        rx.await??;
+    }
+
     // Unix Domain Socket
     // privdrop::PrivDrop::default()
     //     .user("nobody")
@@ -75,15 +139,22 @@ async fn main() -> Result<()> {
     // Event loop
     loop {
         let manager_tx = manager_tx.clone();
+        let canary_active = canary_active.clone();
         tokio::select! {
             biased;
             _ = ctrlc_rx.recv() => {
-                tracing::warn!(target: "manager:shutdown", "Gracefully shutdown of ctrl_c. Wait for 1 seconds...");
-                let (tx, _) = oneshot::channel::<Result<Vec<u8>>>();
+                tracing::warn!(target: "manager:shutdown", "Gracefully shutdown of ctrl_c. Switch flows to {} and wait up to {} ms...", cli.fallback_cca, cli.drain_timeout_ms);
+                let (tx, rx) = oneshot::channel::<Result<Vec<u8>>>();
                 manager_tx.send(ManagerIpcOperation {
                     req: ManagerOperation::Shutdown.into(),
                     resp: tx,
+                    peer_uid: None,
                 }).await?;
+                if let Ok(Ok(report)) = rx.await {
+                    if let Ok(report) = serde_json::from_slice::<ShutdownReport>(&report) {
+                        tracing::info!(target: "manager:shutdown", "{} flows migrated, {} failed, {} still connected", report.migrated.len(), report.failed.len(), report.remaining);
+                    }
+                }
                 manager_handle.join().unwrap();
                 let _ = std::fs::remove_file(MORTISE_SOCK_PATH);
                 tracing::info!(target: "manager:shutdown", "Shutdown finished");
@@ -93,7 +164,7 @@ async fn main() -> Result<()> {
                 if let Ok((receiver, _)) = res {
                     tracing::info!("receive one new connect");
                     tokio::spawn(async move {
-                        handle_uds(receiver, manager_tx).await;
+                        handle_uds(receiver, manager_tx, canary_active).await;
                     });
                 }
             }
@@ -0,0 +1,246 @@
+use mortise_common::{
+    canary::{CanaryAssign, CanaryConfig, CanaryReport, VersionSummary},
+    qoe::FrameQoE,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+/// Whether a canary rollout is in progress, so that the connection handlers only
+/// forward the QoE updates to the manager thread when it needs them.
+pub type CanaryActive = Arc<AtomicBool>;
+
+// Completion times kept per version to compute the percentiles
+const RCT_WINDOW: usize = 4096;
+
+#[derive(Default)]
+struct VersionStats {
+    flows: u64,
+    qoe_samples: u64,
+    qoe_sum: f64,
+    rct_samples: u64,
+    rct_sum_us: u64,
+    rct_window: VecDeque<u64>,
+}
+
+impl VersionStats {
+    fn record(&mut self, qoe: &FrameQoE) {
+        self.qoe_samples += 1;
+        self.qoe_sum += qoe.score();
+        // Completion as seen by the client, the clocks of both ends are comparable
+        if qoe.client_recv > qoe.server_send {
+            let rct_us = (qoe.client_recv - qoe.server_send) / 1000;
+            self.rct_samples += 1;
+            self.rct_sum_us += rct_us;
+            self.rct_window.push_back(rct_us);
+            if self.rct_window.len() > RCT_WINDOW {
+                self.rct_window.pop_front();
+            }
+        }
+    }
+
+    fn summary(&self, obj_id: u32) -> VersionSummary {
+        let mut rcts: Vec<u64> = self.rct_window.iter().copied().collect();
+        rcts.sort_unstable();
+        let percentile = |p: usize| {
+            if rcts.is_empty() {
+                0
+            } else {
+                rcts[(rcts.len() - 1) * p / 100]
+            }
+        };
+        VersionSummary {
+            obj_id,
+            flows: self.flows,
+            qoe_samples: self.qoe_samples,
+            mean_qoe: if self.qoe_samples > 0 {
+                self.qoe_sum / self.qoe_samples as f64
+            } else {
+                0.0
+            },
+            rct_samples: self.rct_samples,
+            mean_rct_us: self.rct_sum_us.checked_div(self.rct_samples).unwrap_or(0),
+            p50_rct_us: percentile(50),
+            p95_rct_us: percentile(95),
+        }
+    }
+}
+
+/// Canary rollout of a logical CCA, see [`CanaryConfig`].
+pub struct CanaryRollout {
+    pub config: CanaryConfig,
+    stable: VersionStats,
+    canary: VersionStats,
+}
+
+impl CanaryRollout {
+    pub fn new(config: CanaryConfig) -> Self {
+        CanaryRollout {
+            config,
+            stable: VersionStats::default(),
+            canary: VersionStats::default(),
+        }
+    }
+
+    /// Choose the version of a new flow connecting to the stable object.
+    pub fn assign(&mut self, local: Option<SocketAddr>, peer: Option<SocketAddr>) -> u32 {
+        let percent = self.config.percent.min(100) as u64;
+        let to_canary = match self.config.assign {
+            CanaryAssign::Percent => {
+                let total = self.stable.flows + self.canary.flows + 1;
+                self.canary.flows * 100 < percent * total
+            }
+            CanaryAssign::HashFlow => {
+                let mut hasher = DefaultHasher::new();
+                (local, peer).hash(&mut hasher);
+                hasher.finish() % 100 < percent
+            }
+            CanaryAssign::HashPeer => {
+                let mut hasher = DefaultHasher::new();
+                peer.map(|addr| addr.ip()).hash(&mut hasher);
+                hasher.finish() % 100 < percent
+            }
+        };
+        if to_canary {
+            self.config.canary
+        } else {
+            self.config.stable
+        }
+    }
+
+    pub fn add_flow(&mut self, obj_id: u32) {
+        if let Some(stats) = self.stats_mut(obj_id) {
+            stats.flows += 1;
+        }
+    }
+
+    /// Count a flow migrated from object `from` to object `to`.
+    pub fn move_flow(&mut self, from: u32, to: u32) {
+        if let Some(stats) = self.stats_mut(from) {
+            stats.flows = stats.flows.saturating_sub(1);
+        }
+        self.add_flow(to);
+    }
+
+    pub fn record_qoe(&mut self, obj_id: u32, qoe: &FrameQoE) {
+        if let Some(stats) = self.stats_mut(obj_id) {
+            stats.record(qoe);
+        }
+    }
+
+    pub fn report(&self) -> CanaryReport {
+        CanaryReport {
+            config: self.config.clone(),
+            stable: self.stable.summary(self.config.stable),
+            canary: self.canary.summary(self.config.canary),
+        }
+    }
+
+    fn stats_mut(&mut self, obj_id: u32) -> Option<&mut VersionStats> {
+        if obj_id == self.config.canary {
+            Some(&mut self.canary)
+        } else if obj_id == self.config.stable {
+            Some(&mut self.stable)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CanaryRollout;
+    use mortise_common::{
+        canary::{CanaryAssign, CanaryConfig},
+        qoe::FrameQoE,
+    };
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn rollout(percent: u8, assign: CanaryAssign) -> CanaryRollout {
+        CanaryRollout::new(CanaryConfig {
+            stable: 1,
+            canary: 2,
+            stable_tcp_ca: "mortise_copa".to_string(),
+            canary_tcp_ca: "mortise_copa_v2".to_string(),
+            percent,
+            assign,
+        })
+    }
+
+    fn frame(server_send: u64, rct_us: u64) -> FrameQoE {
+        FrameQoE {
+            server_send,
+            client_recv: server_send + rct_us * 1000,
+            server_recv: server_send + rct_us * 2000,
+            size: 10000,
+            frame_interval: Duration::from_millis(33),
+            frame_id: 0,
+        }
+    }
+
+    #[test]
+    fn test_assign_percent() {
+        let mut canary = rollout(10, CanaryAssign::Percent);
+        for _ in 0..100 {
+            let obj_id = canary.assign(None, None);
+            canary.add_flow(obj_id);
+        }
+        let report = canary.report();
+        assert_eq!((report.stable.flows, report.canary.flows), (90, 10));
+        // Rolled back flows leave the canary
+        for _ in 0..10 {
+            canary.move_flow(2, 1);
+        }
+        let report = canary.report();
+        assert_eq!((report.stable.flows, report.canary.flows), (100, 0));
+        canary.move_flow(2, 1);
+        assert_eq!(canary.report().canary.flows, 0);
+    }
+
+    #[test]
+    fn test_assign_hash() {
+        let peer: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let mut canary = rollout(50, CanaryAssign::HashPeer);
+        let obj_id = canary.assign(None, Some(peer));
+        for port in 1..100 {
+            let local = SocketAddr::from(([10, 0, 0, 2], port));
+            assert_eq!(canary.assign(Some(local), Some(peer)), obj_id);
+        }
+        let mut stable = rollout(0, CanaryAssign::HashFlow);
+        let mut canary = rollout(100, CanaryAssign::HashFlow);
+        for port in 1..100 {
+            let local = SocketAddr::from(([10, 0, 0, 2], port));
+            assert_eq!(stable.assign(Some(local), Some(peer)), 1);
+            assert_eq!(canary.assign(Some(local), Some(peer)), 2);
+        }
+    }
+
+    #[test]
+    fn test_record_qoe() {
+        let mut canary = rollout(10, CanaryAssign::Percent);
+        let frames: Vec<FrameQoE> = (1..=100).map(|ms| frame(1 << 30, ms * 1000)).collect();
+        for qoe in frames.iter() {
+            canary.record_qoe(2, qoe);
+        }
+        // Other objects and frames without a completion are not counted
+        canary.record_qoe(3, &frames[0]);
+        let mut late = frame(1 << 30, 0);
+        late.client_recv = 0;
+        canary.record_qoe(1, &late);
+
+        let report = canary.report();
+        let canary = report.canary;
+        assert_eq!((canary.qoe_samples, canary.rct_samples), (100, 100));
+        let mean_qoe = frames.iter().map(|qoe| qoe.score()).sum::<f64>() / 100.0;
+        assert!((canary.mean_qoe - mean_qoe).abs() < 1e-9);
+        assert_eq!(canary.mean_rct_us, 50500);
+        assert_eq!((canary.p50_rct_us, canary.p95_rct_us), (50000, 95000));
+        let stable = report.stable;
+        assert_eq!((stable.qoe_samples, stable.rct_samples), (1, 0));
+        assert_eq!((stable.mean_rct_us, stable.p95_rct_us), (0, 0));
+    }
+}
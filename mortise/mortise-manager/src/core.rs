@@ -1,16 +1,33 @@
-use crate::{MortiseManagedObject, MortiseObject, MortiseOpenObject};
-use libbpf_rs::{MapFlags as BpfMapFlags, MapHandle as BpfMapHandle, MapType as BpfMapType};
+use crate::canary::{CanaryActive, CanaryRollout};
+use crate::enroll::{is_enrolled, EnrollConfig, EnrollSock, Enrollment, ENROLL_FLOW_ID_BASE};
+use crate::policy::{flow_attrs, PolicyEngine};
+use crate::pool::{InnerMapPool, DEFAULT_MAP_POOL_SIZE};
+use crate::store::{check_owner, provenance, read_object, ObjectStore};
+use crate::validate::{check_upgrade, validate_loaded, validate_open};
+use crate::{
+    MortiseManagedObject, MortiseObject, MortiseOpenObject, ShmReportSink, ShmReportSinks,
+};
+use libbpf_rs::{Link as BpfLink, MapFlags as BpfMapFlags, MapHandle as BpfMapHandle};
 use mortise_common::{
     bump_memlock_rlimit, bump_nofile_rlimit,
+    canary::{CanaryConfig, CanaryReport},
+    get_sk_addrs,
     pidfd::{pid_open, pidfd_getfd},
-    qoe::AppInfo,
-    ConnectOption, MemorySize, MortiseError, Result,
+    policy::{FlowPolicy, PolicyAction, PolicyReport},
+    qoe::{AppInfo, FrameQoE},
+    set_tcp_congestion,
+    store::ObjectInfo,
+    ConnectOption, MemorySize, MortiseError, Result, ShutdownReport, SkArrayMap, KERNEL_CCA_OBJ_ID,
 };
 use rustc_hash::FxHashMap as HashMap;
 use std::{
     os::fd::{AsFd, AsRawFd},
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
 pub struct RingBufManager {
@@ -23,6 +40,8 @@ pub struct FlowMetadata {
     pub sk_fd: i32,
     pub local_sk_fd: i32,
     pub obj_id: u32,
+    /// Policy applied when the flow connected without choosing its CCA.
+    pub policy: Option<FlowPolicy>,
 }
 
 /// Local socket file descriptor cell
@@ -51,11 +70,48 @@ pub struct FlowManager {
     pub flow_id: u32,
 }
 
+/// Settings of the manager given on the command line.
+pub struct ManagerConfig {
+    pub store: Option<ObjectStore>,
+    pub map_pool_size: usize,
+    /// Kernel CCA the flows are switched to on shutdown.
+    pub fallback_tcp_ca: String,
+    /// How long clients are given to disconnect their flows on shutdown.
+    pub drain_timeout: Duration,
+    /// Set while a canary rollout is in progress, shared with the connection handlers.
+    pub canary_active: CanaryActive,
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        ManagerConfig {
+            store: None,
+            map_pool_size: DEFAULT_MAP_POOL_SIZE,
+            fallback_tcp_ca: "cubic".to_string(),
+            drain_timeout: Duration::from_secs(2),
+            canary_active: CanaryActive::default(),
+        }
+    }
+}
+
 pub struct MortiseManager {
     pub obj_id: u32,
     pub objs: HashMap<u32, MortiseManagedObject<MortiseObject>>,
     pub open_objs: HashMap<u32, MortiseManagedObject<MortiseOpenObject>>,
     pub rb_manager: Option<RingBufManager>,
+    pub report_sinks: ShmReportSinks,
+    pub canary: Option<CanaryRollout>,
+    pub canary_active: CanaryActive,
+    pub policy: Option<PolicyEngine>,
+    pub enrollment: Option<Enrollment>,
+    pub store: Option<ObjectStore>,
+    /// Number of free inner maps kept for each `SkArrayMap` of a loaded object.
+    pub map_pool_size: usize,
+    // record <flow_id, obj_id> of the flows enrolled by the sockops hook whose socket
+    // is not held by the manager yet
+    pub enrolled: HashMap<u32, u32>,
+    /// Kernel CCA the enrolled flows are switched to when the enrollment is detached.
+    pub fallback_tcp_ca: String,
     pub flow_manager: FlowManager,
 }
 
@@ -124,6 +180,21 @@ impl FlowManager {
     }
 
     pub fn insert(&mut self, pid: i32, sk_fd: i32, obj_id: u32) -> Result<u32> {
+        let flow_id = self.flow_id + 1;
+        self.insert_with_id(flow_id, pid, sk_fd, obj_id)?;
+        self.flow_id = flow_id;
+        Ok(flow_id)
+    }
+
+    /// Register the socket `sk_fd` of process `pid` as flow `flow_id`, and returns the
+    /// local socket file descriptor.
+    pub fn insert_with_id(
+        &mut self,
+        flow_id: u32,
+        pid: i32,
+        sk_fd: i32,
+        obj_id: u32,
+    ) -> Result<i32> {
         // If the pid as never been connected, create a new SkFdManager
         let sk_fd_manager = if let Some(m) = self.pid_map.get_mut(&pid) {
             m
@@ -145,22 +216,22 @@ impl FlowManager {
                 return Err(e);
             }
         };
-        self.flow_id += 1;
         let flow_metadata = FlowMetadata {
             pid,
             sk_fd,
             local_sk_fd,
             obj_id,
+            policy: None,
         };
         sk_fd_manager.sk_fd_map.insert(
             sk_fd,
             SkFdCell {
                 local_sk_fd,
-                flow_id: self.flow_id,
+                flow_id,
             },
         );
-        self.flow_map.insert(self.flow_id, flow_metadata);
-        Ok(self.flow_id)
+        self.flow_map.insert(flow_id, flow_metadata);
+        Ok(local_sk_fd)
     }
 
     pub fn remove(&mut self, flow_id: u32) -> Option<FlowMetadata> {
@@ -179,6 +250,39 @@ impl FlowManager {
     }
 }
 
+/// Insert an inner map taken from the pools for each of `sk_array_maps` under `flow_id`.
+/// The maps taken are pushed to `new_maps`, including the one failing to insert.
+fn insert_inner_maps(
+    obj: &mut MortiseManagedObject<MortiseObject>,
+    flow_id: u32,
+    sk_array_maps: &[SkArrayMap],
+    new_maps: &mut Vec<BpfMapHandle>,
+) -> Result<()> {
+    for (idx, sk_array_map) in sk_array_maps.iter().enumerate() {
+        let sub_map = obj.object.pools[idx].take()?;
+        tracing::debug!(target: "manager:flow", "inner map taken: {}", sub_map.name());
+        let map_fd = sub_map.as_fd().as_raw_fd();
+        new_maps.push(sub_map);
+        let map = obj
+            .map_mut(&sk_array_map.mim)
+            .ok_or_else(|| MortiseError::MapNotFound(sk_array_map.mim.clone()))?;
+        tracing::debug!(target: "manager:flow", "map name: {}", map.name());
+        // should pin the map to /sys/fs/bpf/xxx_map
+        let key = flow_id.to_ne_bytes();
+        let val = map_fd.to_ne_bytes();
+        if let Err(e) = map.update(&key, &val, BpfMapFlags::ANY) {
+            if let libbpf_rs::Error::System(7) = e {
+                tracing::error!(target: "manager:flow", "Exceed max_entries of map {}", map.name());
+            } else {
+                tracing::error!(target: "manager:flow", "Failed to update map {}: {}", map.name(), e);
+            }
+            return Err(e.into());
+        }
+        tracing::debug!(target: "manager:flow", "Successfully connect {flow_id} -> {map_fd}");
+    }
+    Ok(())
+}
+
 impl Default for MortiseManager {
     fn default() -> Self {
         Self::new()
@@ -194,6 +298,15 @@ impl MortiseManager {
             objs: HashMap::default(),
             open_objs: HashMap::default(),
             rb_manager: None,
+            report_sinks: ShmReportSinks::default(),
+            canary: None,
+            canary_active: CanaryActive::default(),
+            policy: None,
+            enrollment: None,
+            store: None,
+            map_pool_size: DEFAULT_MAP_POOL_SIZE,
+            enrolled: HashMap::default(),
+            fallback_tcp_ca: ManagerConfig::default().fallback_tcp_ca,
             flow_manager: FlowManager::new(),
         }
     }
@@ -211,28 +324,83 @@ impl MortiseManager {
     /// # Returns
     ///
     /// A handle to the open object.
-    pub fn open_object(&mut self, path: String) -> Result<u32> {
-        let mut obj_builder = libbpf_rs::ObjectBuilder::default();
-        obj_builder.name(&path).relaxed_maps(true);
-        let obj = obj_builder.open_file(path.clone())?;
-        let obj = MortiseOpenObject { object: obj };
-        let obj = MortiseManagedObject { path, object: obj };
+    pub fn open_object(&mut self, path: String, loader_uid: u32) -> Result<u32> {
+        let obj = self.open_file(path, loader_uid)?;
         self.obj_id += 1;
         self.open_objs.insert(self.obj_id, obj);
         Ok(self.obj_id)
     }
 
-    pub fn insert_object(&mut self, obj_id: u32, path: String) -> Result<u32> {
-        let mut obj_builder = libbpf_rs::ObjectBuilder::default();
-        obj_builder.name(&path).relaxed_maps(true);
-        let obj = obj_builder.open_file(path.clone())?;
-        let obj = MortiseOpenObject { object: obj };
-        let obj = MortiseManagedObject { path, object: obj };
+    pub fn insert_object(&mut self, obj_id: u32, path: String, loader_uid: u32) -> Result<u32> {
+        if obj_id == KERNEL_CCA_OBJ_ID {
+            return Err(MortiseError::ReservedObjectId(obj_id));
+        }
+        let obj = self.open_file(path, loader_uid)?;
         self.obj_id = std::cmp::max(self.obj_id, obj_id);
         self.open_objs.insert(obj_id, obj);
         Ok(obj_id)
     }
 
+    /// Path and content of the object `path`, which is a name in the object store if
+    /// the manager has one. Without a store only root loads objects, by path.
+    fn read_object(&self, path: String, loader_uid: u32) -> Result<(String, Vec<u8>, String)> {
+        match self.store {
+            Some(ref store) => store.resolve(&path),
+            None if loader_uid != 0 => Err(MortiseError::UntrustedObject(
+                path,
+                format!(
+                    "uid {} loads objects from the object store only, start the manager with --object-dir",
+                    loader_uid
+                ),
+            )),
+            None => {
+                let (data, hash) = read_object(&path)?;
+                Ok((path, data, hash))
+            }
+        }
+    }
+
+    fn open_file(
+        &self,
+        path: String,
+        loader_uid: u32,
+    ) -> Result<MortiseManagedObject<MortiseOpenObject>> {
+        let (path, data, hash) = self.read_object(path, loader_uid)?;
+        let mut obj_builder = libbpf_rs::ObjectBuilder::default();
+        obj_builder.relaxed_maps(true);
+        let obj = obj_builder.open_memory(&path, &data)?;
+        tracing::info!(target: "manager:store", "Open object {} of sha256 {} for uid {}", path, hash, loader_uid);
+        let obj = MortiseOpenObject { object: obj };
+        Ok(MortiseManagedObject {
+            path,
+            provenance: provenance(hash, loader_uid),
+            object: obj,
+        })
+    }
+
+    /// Objects loaded by the manager with their provenance.
+    pub fn list_objects(&self) -> Vec<ObjectInfo> {
+        let mut objs: Vec<ObjectInfo> = self
+            .objs
+            .iter()
+            .map(|(obj_id, obj)| ObjectInfo {
+                obj_id: *obj_id,
+                path: obj.path.clone(),
+                provenance: obj.provenance.clone(),
+                map_pools: obj.object.pools.iter().map(|pool| pool.stats()).collect(),
+                flows: self
+                    .flow_manager
+                    .flow_map
+                    .values()
+                    .filter(|metadata| metadata.obj_id == *obj_id)
+                    .count() as u64
+                    + self.enrolled.values().filter(|id| *id == obj_id).count() as u64,
+            })
+            .collect();
+        objs.sort_by_key(|info| info.obj_id);
+        objs
+    }
+
     pub fn close_object(&mut self, obj_id: u32) -> Result<()> {
         self.open_objs
             .remove(&obj_id)
@@ -245,7 +413,16 @@ impl MortiseManager {
             .open_objs
             .remove(&obj_id)
             .ok_or_else(|| MortiseError::ObjectNotFound(obj_id))?;
-        let mut obj = obj.load(option)?;
+        validate_open(&obj, &option)?;
+        let mut obj = obj.load(option.clone())?;
+        validate_loaded(&obj)?;
+        if let Some(option) = option {
+            obj.object.pools = option
+                .sk_array_maps
+                .iter()
+                .map(|spec| InnerMapPool::new(spec, self.map_pool_size))
+                .collect::<Result<_>>()?;
+        }
         obj.attach_struct_ops()?;
         self.objs.insert(obj_id, obj);
         Ok(())
@@ -255,8 +432,9 @@ impl MortiseManager {
         &mut self,
         path: String,
         option: Option<ConnectOption>,
+        loader_uid: u32,
     ) -> Result<u32> {
-        let obj_id = self.open_object(path)?;
+        let obj_id = self.open_object(path, loader_uid)?;
         self.load_object(obj_id, option)?;
         Ok(obj_id)
     }
@@ -266,8 +444,9 @@ impl MortiseManager {
         obj_id: u32,
         path: String,
         option: Option<ConnectOption>,
+        loader_uid: u32,
     ) -> Result<u32> {
-        self.insert_object(obj_id, path)?;
+        self.insert_object(obj_id, path, loader_uid)?;
         self.load_object(obj_id, option)?;
         Ok(obj_id)
     }
@@ -279,6 +458,112 @@ impl MortiseManager {
         Ok(())
     }
 
+    /// Replace the loaded object `obj_id` by the object at `path` without disconnecting
+    /// its flows.
+    ///
+    /// The new object is loaded aside, the per-flow inner maps and socket storages are
+    /// copied into it, then the struct_ops links are updated in place so that sockets
+    /// never fall back to another congestion control. The old object is left untouched
+    /// if the layouts are incompatible.
+    ///
+    /// If the object enrolls connections, the new object shares its `flow_id_stg` with the
+    /// enrollment hook, so that the flows enrolled but not taken over yet keep their id.
+    pub fn upgrade_object(&mut self, obj_id: u32, path: String, loader_uid: u32) -> Result<()> {
+        let option = self.get_object(obj_id)?.connect_option();
+        let mut obj = self.open_file(path, loader_uid)?;
+        validate_open(&obj, &option)?;
+        let enrolling =
+            matches!(self.enrollment, Some(ref enrollment) if enrollment.obj_id == obj_id);
+        if enrolling {
+            let flow_id_stg = self
+                .get_object(obj_id)?
+                .map("flow_id_stg")
+                .ok_or_else(|| MortiseError::MapNotFound("flow_id_stg".to_string()))?;
+            obj.map_mut("flow_id_stg")
+                .ok_or_else(|| MortiseError::MapNotFound("flow_id_stg".to_string()))?
+                .reuse_fd(flow_id_stg.as_fd())?;
+        }
+        let mut new_obj = obj.load(option.clone())?;
+        validate_loaded(&new_obj)?;
+
+        let sk_fds: Vec<i32> = self
+            .flow_manager
+            .flow_map
+            .values()
+            .filter(|metadata| metadata.obj_id == obj_id)
+            .map(|metadata| metadata.local_sk_fd)
+            .collect();
+        let old_obj = self
+            .objs
+            .get_mut(&obj_id)
+            .ok_or_else(|| MortiseError::ObjectNotFound(obj_id))?;
+        let sk_stg_maps = check_upgrade(obj_id, old_obj, &new_obj)?;
+
+        // The inner maps are shared, only the outer maps of the new object are filled
+        if let Some(ref option) = option {
+            for (flow_id, maps) in old_obj.object.maps.iter() {
+                for (sk_array_map, inner_map) in option.sk_array_maps.iter().zip(maps) {
+                    let map = new_obj
+                        .map(&sk_array_map.mim)
+                        .ok_or_else(|| MortiseError::MapNotFound(sk_array_map.mim.clone()))?;
+                    let key = flow_id.to_ne_bytes();
+                    let val = inner_map.as_fd().as_raw_fd().to_ne_bytes();
+                    map.update(&key, &val, BpfMapFlags::ANY)?;
+                }
+            }
+        }
+        for name in sk_stg_maps.iter() {
+            if enrolling && name == "flow_id_stg" {
+                continue;
+            }
+            let old_map = old_obj
+                .map(name)
+                .ok_or_else(|| MortiseError::MapNotFound(name.clone()))?;
+            let new_map = new_obj
+                .map(name)
+                .ok_or_else(|| MortiseError::MapNotFound(name.clone()))?;
+            for sk_fd in sk_fds.iter() {
+                let key = sk_fd.to_ne_bytes();
+                if let Some(val) = old_map.lookup(&key, BpfMapFlags::ANY)? {
+                    new_map.update(&key, &val, BpfMapFlags::ANY)?;
+                }
+            }
+            tracing::debug!(target: "manager:upgrade", "Migrated map {} of {} flows", name, sk_fds.len());
+        }
+
+        // All the links follow the new object or none does
+        let mut updated: Vec<(&String, &BpfLink)> = Vec::new();
+        for (name, link) in old_obj.links().iter() {
+            let res = new_obj
+                .map(name)
+                .ok_or_else(|| MortiseError::MapNotFound(name.clone()))
+                .and_then(|map| Ok(link.update_map(map)?));
+            if let Err(e) = res {
+                for (name, link) in updated {
+                    if let Some(map) = old_obj.map(name) {
+                        if let Err(e) = link.update_map(map) {
+                            tracing::error!(target: "manager:upgrade", "Fail to restore struct_ops link {}: {}", name, e);
+                        }
+                    }
+                }
+                return Err(e);
+            }
+            updated.push((name, link));
+            tracing::debug!(target: "manager:upgrade", "Updated struct_ops link {}", name);
+        }
+        new_obj.object.links = std::mem::take(&mut old_obj.object.links);
+        new_obj.object.maps = std::mem::take(&mut old_obj.object.maps);
+        new_obj.object.pools = std::mem::take(&mut old_obj.object.pools);
+        self.objs.insert(obj_id, new_obj);
+        if self.rb_manager.is_some() {
+            tracing::warn!(target: "manager:upgrade", "RingBuf still polls the previous version of object {}, register it again", obj_id);
+        }
+        if enrolling {
+            tracing::info!(target: "manager:upgrade", "Enrollment of object {} continues with the new version, {} flows not taken over yet", obj_id, self.enrolled.len());
+        }
+        Ok(())
+    }
+
     pub fn get_object(&self, obj_id: u32) -> Result<&MortiseManagedObject<MortiseObject>> {
         self.objs
             .get(&obj_id)
@@ -342,6 +627,18 @@ impl MortiseManager {
         Ok(res)
     }
 
+    pub fn delete_map<T>(&mut self, obj_id: u32, map_name: T, key: &[u8]) -> Result<()>
+    where
+        T: AsRef<str>,
+    {
+        let map = self
+            .get_object_mut(obj_id)?
+            .map_mut(&map_name)
+            .ok_or_else(|| MortiseError::MapNotFound(map_name.as_ref().to_string()))?;
+        map.delete(key)?;
+        Ok(())
+    }
+
     // TODO: change interface, may have other names for ring buffer
     pub fn get_rb_map_handles(&mut self, obj_ids: &[u32]) -> Result<Vec<BpfMapHandle>> {
         let mut handles = Vec::new();
@@ -374,27 +671,231 @@ impl MortiseManager {
         Ok(())
     }
 
+    pub fn register_shm_report(
+        &mut self,
+        name: String,
+        capacity: u32,
+        doorbell_path: String,
+        peer_uid: u32,
+    ) -> Result<()> {
+        // The manager rings the doorbell as root
+        check_owner(&doorbell_path, peer_uid)?;
+        let mut sinks = self.report_sinks.lock().unwrap();
+        if sinks.contains_key(&name) {
+            return Err(MortiseError::ShmError(format!(
+                "Report consumer {} already registered",
+                name
+            )));
+        }
+        let sink = ShmReportSink::create(name.clone(), capacity, doorbell_path)?;
+        sinks.insert(name, sink);
+        Ok(())
+    }
+
+    pub fn unregister_shm_report(&mut self, name: &str) -> Result<u64> {
+        let sink = self
+            .report_sinks
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| MortiseError::ShmError(format!("Report consumer {} not found", name)))?;
+        Ok(sink.overruns())
+    }
+
+    pub fn start_canary(&mut self, config: CanaryConfig) -> Result<()> {
+        self.get_object(config.stable)?;
+        self.get_object(config.canary)?;
+        self.canary = Some(CanaryRollout::new(config));
+        self.canary_active.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn promote_canary(&mut self) -> Result<CanaryReport> {
+        let canary = self.canary.as_mut().ok_or(MortiseError::CanaryNotFound)?;
+        canary.config.percent = 100;
+        Ok(canary.report())
+    }
+
+    /// Stop the canary rollout and switch the flows assigned to the canary back to the
+    /// stable version.
+    pub fn rollback_canary(&mut self) -> Result<CanaryReport> {
+        let canary = self.canary.take().ok_or(MortiseError::CanaryNotFound)?;
+        self.canary_active.store(false, Ordering::Relaxed);
+        let config = &canary.config;
+        let mut flow_ids: Vec<u32> = self
+            .flow_manager
+            .flow_map
+            .iter()
+            .filter(|(_, metadata)| metadata.obj_id == config.canary)
+            .map(|(flow_id, _)| *flow_id)
+            .collect();
+        flow_ids.sort();
+        let mut failed = 0;
+        for flow_id in flow_ids.iter() {
+            if let Err(e) = self.migrate(*flow_id, config.stable, &config.stable_tcp_ca) {
+                tracing::error!(target: "manager:canary", "Fail to roll back flow {}: {}", flow_id, e);
+                failed += 1;
+            }
+        }
+        tracing::info!(target: "manager:canary", "Roll back {} canary flows to object {}, {} failed", flow_ids.len(), config.stable, failed);
+        Ok(canary.report())
+    }
+
+    pub fn canary_report(&self) -> Result<CanaryReport> {
+        let canary = self.canary.as_ref().ok_or(MortiseError::CanaryNotFound)?;
+        Ok(canary.report())
+    }
+
+    pub fn record_qoe(&mut self, flow_id: u32, qoe: &FrameQoE) {
+        if let (Some(canary), Some(metadata)) = (
+            self.canary.as_mut(),
+            self.flow_manager.flow_map.get(&flow_id),
+        ) {
+            canary.record_qoe(metadata.obj_id, qoe);
+        }
+    }
+
+    /// Choose the version of a flow connecting to `obj_id` during a canary rollout.
+    fn assign_version(&mut self, flow_id: u32, obj_id: u32) -> u32 {
+        let canary = match self.canary.as_mut() {
+            Some(canary) if canary.config.stable == obj_id => canary,
+            _ => return obj_id,
+        };
+        let metadata = self.flow_manager.flow_map.get_mut(&flow_id).unwrap();
+        let (local, peer) = get_sk_addrs(metadata.local_sk_fd).unwrap_or((None, None));
+        let mut version = canary.assign(local, peer);
+        if version == canary.config.canary {
+            if let Err(e) =
+                set_tcp_congestion(metadata.local_sk_fd, canary.config.canary_tcp_ca.as_bytes())
+            {
+                tracing::error!(target: "manager:canary", "Fail to set {} on flow {}, keep it stable: {}", canary.config.canary_tcp_ca, flow_id, e);
+                version = obj_id;
+            }
+        }
+        metadata.obj_id = version;
+        canary.add_flow(version);
+        tracing::debug!(target: "manager:canary", "Assign flow {} to object {}", flow_id, version);
+        version
+    }
+
     pub fn get_flow_metadata(&self, flow_id: u32) -> Option<&FlowMetadata> {
         self.flow_manager.flow_map.get(&flow_id)
     }
 
+    /// Switch every connected flow to the kernel CCA `tcp_ca`, so that no socket loses
+    /// its congestion control when the objects are unloaded.
+    pub fn fallback_flows(&mut self, tcp_ca: &str) -> ShutdownReport {
+        let mut report = ShutdownReport {
+            fallback_tcp_ca: tcp_ca.to_string(),
+            ..Default::default()
+        };
+        self.resolve_enrolled();
+        let mut flow_ids: Vec<u32> = self
+            .flow_manager
+            .flow_map
+            .iter()
+            .filter(|(_, metadata)| metadata.obj_id != KERNEL_CCA_OBJ_ID)
+            .map(|(flow_id, _)| *flow_id)
+            .collect();
+        flow_ids.sort();
+        for flow_id in flow_ids {
+            match self.migrate(flow_id, KERNEL_CCA_OBJ_ID, tcp_ca) {
+                Ok(_) => report.migrated.push(flow_id),
+                Err(e) => report.failed.push((flow_id, e.to_string())),
+            }
+            // No client disconnects the enrolled flows
+            if is_enrolled(flow_id) {
+                self.flow_manager.remove(flow_id);
+            }
+        }
+        report.unmanaged = self.enrolled.keys().copied().collect();
+        report.unmanaged.sort();
+        report
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         self.unregister_rb()?;
+        self.unenroll();
+        self.report_sinks.lock().unwrap().clear();
         self.objs.clear();
         self.open_objs.clear();
         Ok(())
     }
 
+    /// Load the policy file at `path`, or reload the current one. The policy applies to
+    /// the flows of every user, so only root loads another file than the current one.
+    /// The hit counters are reset.
+    pub fn reload_policy(&mut self, path: Option<String>, peer_uid: u32) -> Result<()> {
+        let path = match (path, self.policy.as_ref()) {
+            (Some(path), Some(engine)) if path == engine.path => path,
+            (Some(path), _) if peer_uid != 0 => {
+                return Err(MortiseError::InvalidPolicy(format!(
+                    "only root loads {}, uid {} reloads the current policy",
+                    path, peer_uid
+                )));
+            }
+            (Some(path), _) => path,
+            (None, Some(engine)) => engine.path.clone(),
+            (None, None) => {
+                return Err(MortiseError::InvalidPolicy(
+                    "no policy file to reload".to_string(),
+                ))
+            }
+        };
+        self.policy = Some(PolicyEngine::load(path)?);
+        Ok(())
+    }
+
+    pub fn policy_report(&self) -> Result<PolicyReport> {
+        let engine = self
+            .policy
+            .as_ref()
+            .ok_or_else(|| MortiseError::InvalidPolicy("no policy loaded".to_string()))?;
+        Ok(engine.report())
+    }
+
+    /// Apply the operator policy to a flow connecting without choosing its CCA.
+    fn apply_policy(
+        &mut self,
+        flow_id: u32,
+        pid: i32,
+        local_sk_fd: i32,
+        tag: Option<String>,
+    ) -> Result<PolicyAction> {
+        let engine = self
+            .policy
+            .as_mut()
+            .ok_or(MortiseError::NoPolicy(flow_id))?;
+        let attrs = flow_attrs(pid, local_sk_fd, tag);
+        let policy = engine
+            .resolve(&attrs)
+            .ok_or(MortiseError::NoPolicy(flow_id))?;
+        set_tcp_congestion(local_sk_fd, policy.action.tcp_ca.as_bytes())?;
+        tracing::info!(target: "manager:policy", "Apply {} to flow {}: {:?}", policy.rule.as_deref().unwrap_or("default"), flow_id, policy.action);
+        let action = policy.action.clone();
+        let metadata = self.flow_manager.flow_map.get_mut(&flow_id).unwrap();
+        metadata.obj_id = action.obj_id;
+        metadata.policy = Some(policy);
+        Ok(action)
+    }
+
+    /// Connect a flow to object `obj_id`, or to the object chosen by the policy if
+    /// `obj_id` is `None`.
     pub fn connect(
         &mut self,
         pid: i32,
-        obj_id: u32,
+        obj_id: Option<u32>,
         sk_fd: i32,
         default_app_info: Option<u64>,
+        tag: Option<String>,
     ) -> Result<u32> {
         // TODO: handle double connect, insert should return a error indicating the flow_id is already in use
         // We can make an enum to hold the flow_id
-        let flow_id = match self.flow_manager.insert(pid, sk_fd, obj_id) {
+        let flow_id = match self.flow_manager.insert(
+            pid,
+            sk_fd,
+            obj_id.unwrap_or(KERNEL_CCA_OBJ_ID),
+        ) {
             Ok(id) => id,
             Err(e) => {
                 if let MortiseError::FlowConnected(flow_id) = e {
@@ -406,102 +907,416 @@ impl MortiseManager {
             }
         };
         let local_sk_fd = self.get_flow_metadata(flow_id).unwrap().local_sk_fd;
+        // A socket enrolled by the sockops hook keeps its flow id and maps
+        if let Some(enrolled_id) = self
+            .enrollment
+            .as_ref()
+            .and_then(|enrollment| enrollment.flow_id(local_sk_fd))
+            .filter(|id| self.enrolled.contains_key(id) || self.get_flow_metadata(*id).is_some())
+        {
+            self.flow_manager.remove(flow_id);
+            if let Some(enrolled_obj_id) = self.enrolled.remove(&enrolled_id) {
+                let sock = EnrollSock {
+                    flow_id: enrolled_id,
+                    pid: pid as u32,
+                    fd: sk_fd as u32,
+                };
+                if let Err(e) = self.adopt_enrolled(&sock, enrolled_obj_id) {
+                    self.enrolled.insert(enrolled_id, enrolled_obj_id);
+                    return Err(e);
+                }
+            }
+            tracing::info!(target: "manager:enroll", "Socket {} of pid {} is already enrolled as flow {}", sk_fd, pid, enrolled_id);
+            return Ok(enrolled_id);
+        }
+        let (obj_id, default_app_info) = match obj_id {
+            Some(obj_id) => (obj_id, default_app_info),
+            None => match self.apply_policy(flow_id, pid, local_sk_fd, tag) {
+                Ok(action) => (action.obj_id, default_app_info.or(action.app_info)),
+                Err(e) => {
+                    self.flow_manager.remove(flow_id);
+                    return Err(e);
+                }
+            },
+        };
+        let obj_id = self.assign_version(flow_id, obj_id);
+        self.attach_flow(flow_id, obj_id, Some(local_sk_fd))?;
+        if obj_id == KERNEL_CCA_OBJ_ID {
+            return Ok(flow_id);
+        }
+        if let Some(default_app_info) = default_app_info {
+            self.set_app_info(obj_id, local_sk_fd, default_app_info)?;
+        }
+        Ok(flow_id)
+    }
+
+    fn set_app_info(&mut self, obj_id: u32, local_sk_fd: i32, req: u64) -> Result<()> {
+        let obj = self.get_object_mut(obj_id)?;
+        let app_info_map = obj
+            .map_mut("sk_stg_map")
+            .ok_or_else(|| MortiseError::MapNotFound("sk_stg_map".to_string()))?;
+        let key = local_sk_fd.to_ne_bytes();
+        let app_info = AppInfo { req, resp: 0 };
+        let val = Vec::from(app_info.as_bytes());
+        app_info_map.update(&key, &val, BpfMapFlags::ANY)?;
+        tracing::debug!(target: "manager:flow", "Updated map {}", app_info_map.name());
+        Ok(())
+    }
+
+    /// Create the per-flow maps of object `obj_id` and register the flow in it. The
+    /// `flow_id_stg` of enrolled flows is already set by the enrollment hook.
+    ///
+    /// On failure, the maps inserted so far are removed and given back to the pools.
+    fn attach_flow(&mut self, flow_id: u32, obj_id: u32, local_sk_fd: Option<i32>) -> Result<()> {
+        if obj_id == KERNEL_CCA_OBJ_ID {
+            return Ok(());
+        }
         let obj = self.get_object_mut(obj_id)?;
         if let Some(option) = obj.connect_option() {
             if !option.sk_array_maps.is_empty() {
                 let mut new_maps = Vec::new();
+                let res = insert_inner_maps(obj, flow_id, &option.sk_array_maps, &mut new_maps);
+                obj.set_sk_array_maps(flow_id, new_maps);
+                // update flow_id
+                let res = res.and_then(|_| match local_sk_fd {
+                    Some(local_sk_fd) => {
+                        let flow_id_map = obj
+                            .map_mut("flow_id_stg")
+                            .ok_or_else(|| MortiseError::MapNotFound("flow_id_stg".to_string()))?;
+                        let key = local_sk_fd.to_ne_bytes();
+                        let val = flow_id.to_ne_bytes();
+                        flow_id_map.update(&key, &val, BpfMapFlags::ANY)?;
+                        tracing::debug!(target: "manager:flow", "Updated map {}", flow_id_map.name());
+                        Ok(())
+                    }
+                    None => Ok(()),
+                });
+                if let Err(e) = res {
+                    let key = flow_id.to_ne_bytes();
+                    for sk_array_map in option.sk_array_maps.iter() {
+                        if let Some(map) = obj.map_mut(&sk_array_map.mim) {
+                            let _ = map.delete(&key);
+                        }
+                    }
+                    obj.release_sk_array_maps(flow_id);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the per-flow maps of object `obj_id`.
+    fn detach_flow(&mut self, flow_id: u32, obj_id: u32) -> Result<()> {
+        if obj_id == KERNEL_CCA_OBJ_ID {
+            return Ok(());
+        }
+        let obj = self.get_object_mut(obj_id)?;
+        if let Some(option) = obj.connect_option() {
+            if !option.sk_array_maps.is_empty() {
                 for sk_array_map in option.sk_array_maps.iter() {
                     let map = obj
                         .map_mut(&sk_array_map.mim)
                         .ok_or_else(|| MortiseError::MapNotFound(sk_array_map.mim.clone()))?;
-                    tracing::debug!(target: "manager:flow", "map name: {}", map.name());
-                    let opts = libbpf_rs::libbpf_sys::bpf_map_create_opts {
-                        sz: std::mem::size_of::<libbpf_rs::libbpf_sys::bpf_map_create_opts>()
-                            as libbpf_rs::libbpf_sys::size_t,
-                        map_flags: libbpf_rs::libbpf_sys::BPF_F_NO_PREALLOC,
-                        btf_fd: 0,
-                        btf_key_type_id: 0,
-                        btf_value_type_id: 0,
-                        btf_vmlinux_value_type_id: 0,
-                        inner_map_fd: 0,
-                        map_extra: 0,
-                        numa_node: 0,
-                        map_ifindex: 0,
-                    };
-                    let sub_map = match BpfMapHandle::create::<String>(
-                        BpfMapType::Hash,
-                        None,
-                        4,
-                        sk_array_map.value_size,
-                        sk_array_map.max_entries,
-                        &opts,
-                    ) {
-                        Ok(map) => map,
-                        Err(e) => {
-                            tracing::error!(target: "manager:flow", "Failed to create map: {}", e);
-                            return Err(e.into());
-                        }
-                    };
-                    tracing::debug!(target: "manager:flow", "new map created: {}", sub_map.name());
-                    let map_fd = sub_map.as_fd().as_raw_fd();
-                    new_maps.push(sub_map);
-                    // should pin the map to /sys/fs/bpf/xxx_map
                     let key = flow_id.to_ne_bytes();
-                    let val = map_fd.to_ne_bytes();
-                    if let Err(e) = map.update(&key, &val, BpfMapFlags::ANY) {
-                        if let libbpf_rs::Error::System(7) = e {
-                            tracing::error!(target: "manager:flow", "Exceed max_entries of map {}", map.name());
-                        } else {
-                            tracing::error!(target: "manager:flow", "Failed to update map {}: {}", map.name(), e);
-                        }
-                        return Err(e.into());
-                    }
-                    tracing::debug!(target: "manager:flow", "Successfully connect {flow_id} -> {map_fd}");
+                    map.delete(&key)?;
                 }
-                obj.set_sk_array_maps(flow_id, new_maps);
+                obj.release_sk_array_maps(flow_id);
+            }
+        }
+        Ok(())
+    }
 
-                // update flow_id
-                let flow_id_map = obj
-                    .map_mut("flow_id_stg")
-                    .ok_or_else(|| MortiseError::MapNotFound("flow_id_stg".to_string()))?;
-                let key = local_sk_fd.to_ne_bytes();
-                let val = flow_id.to_ne_bytes();
-                flow_id_map.update(&key, &val, BpfMapFlags::ANY)?;
-                tracing::debug!(target: "manager:flow", "Updated map {}", flow_id_map.name());
+    /// Switch a connected flow to the struct_ops of another object, or to the kernel CCA
+    /// `tcp_ca` if `obj_id` is [`KERNEL_CCA_OBJ_ID`].
+    ///
+    /// The per-flow maps of the new object are created before the switch so that its
+    /// struct_ops finds them when initializing the socket, and the application info
+    /// is carried over.
+    pub fn migrate(&mut self, flow_id: u32, obj_id: u32, tcp_ca: &str) -> Result<()> {
+        let metadata = self
+            .get_flow_metadata(flow_id)
+            .ok_or(MortiseError::FlowNotFound(flow_id))?;
+        let old_obj_id = metadata.obj_id;
+        let local_sk_fd = metadata.local_sk_fd;
+        if old_obj_id == obj_id {
+            return Ok(());
+        }
+        let app_info = if old_obj_id == KERNEL_CCA_OBJ_ID {
+            None
+        } else {
+            self.lookup_map(old_obj_id, "sk_stg_map", &local_sk_fd.to_ne_bytes())
+                .ok()
+        };
+        if obj_id != KERNEL_CCA_OBJ_ID {
+            self.attach_flow(flow_id, obj_id, Some(local_sk_fd))?;
+            if let Some(ref val) = app_info {
+                if let Err(e) = self.update_map(
+                    obj_id,
+                    "sk_stg_map",
+                    &local_sk_fd.to_ne_bytes(),
+                    val,
+                    BpfMapFlags::ANY,
+                ) {
+                    tracing::error!(target: "manager:flow", "Fail to carry the app info of flow {} to object {}: {}", flow_id, obj_id, e);
+                    let _ = self.detach_flow(flow_id, obj_id);
+                    return Err(e);
+                }
             }
         }
-        if let Some(default_app_info) = default_app_info {
-            let app_info_map = obj
-                .map_mut("sk_stg_map")
-                .ok_or_else(|| MortiseError::MapNotFound("sk_stg_map".to_string()))?;
+        if let Err(e) = set_tcp_congestion(local_sk_fd, tcp_ca.as_bytes()) {
+            tracing::error!(target: "manager:flow", "Fail to set {} on flow {}: {}", tcp_ca, flow_id, e);
+            let _ = self.detach_flow(flow_id, obj_id);
+            return Err(e);
+        }
+        self.flow_manager.flow_map.get_mut(&flow_id).unwrap().obj_id = obj_id;
+        if let Some(canary) = self.canary.as_mut() {
+            canary.move_flow(old_obj_id, obj_id);
+        }
+        if let Err(e) = self.detach_flow(flow_id, old_obj_id) {
+            tracing::warn!(target: "manager:flow", "Fail to clear maps of object {} for flow {}: {}", old_obj_id, flow_id, e);
+        }
+        if old_obj_id != KERNEL_CCA_OBJ_ID {
+            // The socket storages of the old object are no longer read by its struct_ops
             let key = local_sk_fd.to_ne_bytes();
-            let app_info = AppInfo {
-                req: default_app_info,
-                resp: 0,
+            for name in ["flow_id_stg", "sk_stg_map"] {
+                let _ = self.delete_map(old_obj_id, name, &key);
+            }
+        }
+        tracing::info!(target: "manager:flow", "Migrate flow {} from object {} to {} ({})", flow_id, old_obj_id, obj_id, tcp_ca);
+        Ok(())
+    }
+
+    /// Attach the sockops hook of the object at `path` to `cgroups`, enrolling their new
+    /// connections to object `obj_id` with the congestion control `tcp_ca`.
+    ///
+    /// The hook shares the `flow_id_stg` of the object, so that its struct_ops finds the
+    /// flow ids. The cgroups must belong to the client of uid `peer_uid`. Returns the
+    /// ring buffer of the enrollment events.
+    pub fn enroll(
+        &mut self,
+        obj_id: u32,
+        path: String,
+        tcp_ca: String,
+        cgroups: Vec<String>,
+        peer_uid: u32,
+    ) -> Result<BpfMapHandle> {
+        for cgroup in cgroups.iter() {
+            check_owner(cgroup, peer_uid)?;
+        }
+        self.unenroll();
+        let flow_id_stg = self
+            .get_object(obj_id)?
+            .map("flow_id_stg")
+            .ok_or_else(|| MortiseError::MapNotFound("flow_id_stg".to_string()))?;
+        let flow_id_stg = BpfMapHandle::try_clone(flow_id_stg)?;
+        let mut cfg = EnrollConfig {
+            next_flow_id: ENROLL_FLOW_ID_BASE,
+            enabled: 1,
+            ..Default::default()
+        };
+        // keep the trailing nul of the name
+        if tcp_ca.len() >= cfg.tcp_ca.len() {
+            return Err(MortiseError::Custom(format!(
+                "Congestion control name {} is too long",
+                tcp_ca
+            )));
+        }
+        cfg.tcp_ca[..tcp_ca.len()].copy_from_slice(tcp_ca.as_bytes());
+
+        let (path, data, _) = self.read_object(path, peer_uid)?;
+        let mut obj_builder = libbpf_rs::ObjectBuilder::default();
+        obj_builder.relaxed_maps(true);
+        let mut obj = obj_builder.open_memory(&path, &data)?;
+        obj.map_mut("flow_id_stg")
+            .ok_or_else(|| MortiseError::MapNotFound("flow_id_stg".to_string()))?
+            .reuse_fd(flow_id_stg.as_fd())?;
+        let mut object = obj.load()?;
+        let cfg_map = object
+            .map_mut("enroll_cfg")
+            .ok_or_else(|| MortiseError::MapNotFound("enroll_cfg".to_string()))?;
+        cfg_map.update(&0u32.to_ne_bytes(), cfg.as_bytes(), BpfMapFlags::ANY)?;
+        let rb = object
+            .map("enroll_rb")
+            .ok_or_else(|| MortiseError::MapNotFound("enroll_rb".to_string()))?;
+        let rb = BpfMapHandle::try_clone(rb)?;
+
+        let prog = object
+            .prog_mut("mortise_enroll")
+            .ok_or_else(|| MortiseError::ProgNotFound("mortise_enroll".to_string()))?;
+        let mut links = Vec::new();
+        for cgroup in cgroups.iter() {
+            let cgroup_fd = std::fs::File::open(cgroup)?;
+            links.push(prog.attach_cgroup(cgroup_fd.as_raw_fd())?);
+            tracing::info!(target: "manager:enroll", "Enroll connections of cgroup {} to object {}", cgroup, obj_id);
+        }
+        let iter = object
+            .prog_mut("mortise_enroll_iter")
+            .ok_or_else(|| MortiseError::ProgNotFound("mortise_enroll_iter".to_string()))?
+            .attach()?;
+        self.enrollment = Some(Enrollment {
+            obj_id,
+            tcp_ca,
+            cgroups,
+            object,
+            links,
+            iter,
+            notify: None,
+        });
+        Ok(rb)
+    }
+
+    /// Detach the sockops hook. Nothing reports the close of the enrolled flows any
+    /// more, so they are switched to the fallback CCA and released.
+    pub fn unenroll(&mut self) {
+        if self.enrollment.is_none() {
+            return;
+        }
+        self.resolve_enrolled();
+        let flow_ids: Vec<u32> = self
+            .flow_manager
+            .flow_map
+            .keys()
+            .copied()
+            .filter(|flow_id| is_enrolled(*flow_id))
+            .collect();
+        let tcp_ca = self.fallback_tcp_ca.clone();
+        for flow_id in flow_ids {
+            if let Err(e) = self.migrate(flow_id, KERNEL_CCA_OBJ_ID, &tcp_ca) {
+                tracing::warn!(target: "manager:enroll", "Fail to switch flow {} to {}: {}", flow_id, tcp_ca, e);
+            }
+            let _ = self.disconnect(flow_id);
+        }
+        for (flow_id, obj_id) in std::mem::take(&mut self.enrolled) {
+            tracing::warn!(target: "manager:enroll", "Release the maps of flow {}, its socket was not found", flow_id);
+            let _ = self.detach_flow(flow_id, obj_id);
+        }
+        if let Some(enrollment) = self.enrollment.take() {
+            tracing::info!(target: "manager:enroll", "Detach enrollment of cgroups {:?}", enrollment.cgroups);
+        }
+    }
+
+    /// Create the per-flow maps of a connection enrolled by the sockops hook, then take
+    /// over its socket or let the hook switch its CCA.
+    ///
+    /// A passive connection is enrolled before it is accepted, so its socket is only
+    /// found by a later [`MortiseManager::resolve_enrolled`]. Meanwhile its maps are
+    /// only reachable by the struct_ops.
+    pub fn enroll_flow(&mut self, flow_id: u32, obj_id: u32) -> Result<()> {
+        if self.enrolled.contains_key(&flow_id) || self.get_flow_metadata(flow_id).is_some() {
+            return Ok(());
+        }
+        self.attach_flow(flow_id, obj_id, None)?;
+        self.enrolled.insert(flow_id, obj_id);
+        self.resolve_enrolled();
+        if self.enrolled.contains_key(&flow_id) {
+            let res = self
+                .enrollment
+                .as_ref()
+                .ok_or(MortiseError::FlowNotFound(flow_id))
+                .and_then(|enrollment| enrollment.ready(flow_id, true));
+            if let Err(e) = res {
+                self.enrolled.remove(&flow_id);
+                let _ = self.detach_flow(flow_id, obj_id);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take over the sockets of the pending enrolled flows that a process holds.
+    pub fn resolve_enrolled(&mut self) {
+        if self.enrolled.is_empty() {
+            return;
+        }
+        let socks = match self
+            .enrollment
+            .as_ref()
+            .map(|enrollment| enrollment.sockets())
+        {
+            Some(Ok(socks)) => socks,
+            Some(Err(e)) => {
+                tracing::warn!(target: "manager:enroll", "Fail to find the enrolled sockets: {}", e);
+                return;
+            }
+            None => return,
+        };
+        for sock in socks {
+            let obj_id = match self.enrolled.remove(&sock.flow_id) {
+                Some(obj_id) => obj_id,
+                None => continue,
             };
-            let val = Vec::from(app_info.as_bytes());
-            app_info_map.update(&key, &val, BpfMapFlags::ANY)?;
-            tracing::debug!(target: "manager:flow", "Updated map {}", app_info_map.name());
+            if let Err(e) = self.adopt_enrolled(&sock, obj_id) {
+                tracing::warn!(target: "manager:enroll", "Fail to take over flow {}: {}", sock.flow_id, e);
+                self.enrolled.insert(sock.flow_id, obj_id);
+            }
         }
-        Ok(flow_id)
+    }
+
+    /// Register an enrolled flow with the socket `sock.fd` of process `sock.pid`, and
+    /// switch it to the CCA chosen by the policy, or by the enrollment.
+    fn adopt_enrolled(&mut self, sock: &EnrollSock, obj_id: u32) -> Result<()> {
+        let flow_id = sock.flow_id;
+        let pid = sock.pid as i32;
+        let local_sk_fd = self
+            .flow_manager
+            .insert_with_id(flow_id, pid, sock.fd as i32, obj_id)?;
+        let res = self.apply_enrolled(flow_id, pid, local_sk_fd, obj_id);
+        if res.is_err() {
+            self.flow_manager.remove(flow_id);
+            // Give the CCA back to the hook
+            if let Some(enrollment) = self.enrollment.as_ref() {
+                let _ = enrollment.ready(flow_id, true);
+            }
+        }
+        res
+    }
+
+    fn apply_enrolled(
+        &mut self,
+        flow_id: u32,
+        pid: i32,
+        local_sk_fd: i32,
+        obj_id: u32,
+    ) -> Result<()> {
+        let enrollment = self
+            .enrollment
+            .as_ref()
+            .ok_or(MortiseError::FlowNotFound(flow_id))?;
+        let tcp_ca = enrollment.tcp_ca.clone();
+        // The manager sets the CCA from now on
+        enrollment.ready(flow_id, false)?;
+        let policy = self
+            .policy
+            .as_mut()
+            .and_then(|engine| engine.resolve(&flow_attrs(pid, local_sk_fd, None)));
+        let (target_obj_id, tcp_ca) = match policy {
+            Some(ref policy) => (policy.action.obj_id, policy.action.tcp_ca.clone()),
+            None => (obj_id, tcp_ca),
+        };
+        if target_obj_id == obj_id {
+            set_tcp_congestion(local_sk_fd, tcp_ca.as_bytes())?;
+        } else {
+            self.migrate(flow_id, target_obj_id, &tcp_ca)?;
+        }
+        if let Some(policy) = policy {
+            tracing::info!(target: "manager:policy", "Apply {} to flow {}: {:?}", policy.rule.as_deref().unwrap_or("default"), flow_id, policy.action);
+            if let (Some(app_info), true) =
+                (policy.action.app_info, target_obj_id != KERNEL_CCA_OBJ_ID)
+            {
+                self.set_app_info(target_obj_id, local_sk_fd, app_info)?;
+            }
+            self.flow_manager.flow_map.get_mut(&flow_id).unwrap().policy = Some(policy);
+        }
+        tracing::info!(target: "manager:enroll", "Take over flow {} from fd {} of pid {} with {}", flow_id, local_sk_fd, pid, tcp_ca);
+        Ok(())
     }
 
     pub fn disconnect(&mut self, flow_id: u32) -> Result<()> {
         if let Some(metadata) = self.flow_manager.remove(flow_id) {
-            let obj_id = metadata.obj_id;
-            let obj = self.get_object_mut(obj_id)?;
-            if let Some(option) = obj.connect_option() {
-                if !option.sk_array_maps.is_empty() {
-                    for sk_array_map in option.sk_array_maps.iter() {
-                        let map = obj
-                            .map_mut(&sk_array_map.mim)
-                            .ok_or_else(|| MortiseError::MapNotFound(sk_array_map.mim.clone()))?;
-                        let key = flow_id.to_ne_bytes();
-                        map.delete(&key)?;
-                    }
-                    obj.remove_sk_array_maps(flow_id);
-                }
-            }
+            self.detach_flow(flow_id, metadata.obj_id)?;
+        } else if let Some(obj_id) = self.enrolled.remove(&flow_id) {
+            self.detach_flow(flow_id, obj_id)?;
         }
         Ok(())
     }
@@ -0,0 +1,167 @@
+use crate::ManagerIpcOperation;
+use libbpf_rs::{Iter as BpfIter, Link as BpfLink, MapFlags as BpfMapFlags, Object as BpfObject};
+use mortise_common::{FlowOperation, MortiseError, Result};
+use plain::Plain;
+use std::io::Read;
+use std::sync::{atomic::AtomicBool, Arc};
+use tokio::sync::{mpsc, oneshot};
+
+/// Flow ids allocated by the enrollment hook start from here, so that they do not
+/// collide with the ids of the flows connected by the manager.
+pub const ENROLL_FLOW_ID_BASE: u32 = 0x8000_0000;
+
+const ENROLL_CONNECT: u32 = 1;
+const ENROLL_CLOSE: u32 = 2;
+
+const ENROLL_SKIP: u32 = 0;
+const ENROLL_APPLY: u32 = 1;
+
+/// Whether `flow_id` was allocated by the enrollment hook.
+pub fn is_enrolled(flow_id: u32) -> bool {
+    flow_id >= ENROLL_FLOW_ID_BASE
+}
+
+/// Value of `enroll_cfg` in `mortise_enroll.bpf.c`.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct EnrollConfig {
+    pub tcp_ca: [u8; 16],
+    pub next_flow_id: u32,
+    pub enabled: u32,
+}
+
+unsafe impl Plain for EnrollConfig {}
+
+impl EnrollConfig {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of_val(self),
+            )
+        }
+    }
+}
+
+/// Event of `enroll_rb` in `mortise_enroll.bpf.c`.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct EnrollEvent {
+    pub kind: u32,
+    pub flow_id: u32,
+    pub family: u32,
+    pub local_port: u32,
+    pub remote_ip4: u32,
+    pub remote_port: u32,
+    pub remote_ip6: [u32; 4],
+}
+
+unsafe impl Plain for EnrollEvent {}
+
+/// Record of `mortise_enroll_iter` in `mortise_enroll.bpf.c`: the fd `fd` of process
+/// `pid` holds the socket of flow `flow_id`.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct EnrollSock {
+    pub flow_id: u32,
+    pub pid: u32,
+    pub fd: u32,
+}
+
+unsafe impl Plain for EnrollSock {}
+
+/// Sockops hook enrolling the connections of some cgroups to object `obj_id`.
+pub struct Enrollment {
+    pub obj_id: u32,
+    pub tcp_ca: String,
+    pub cgroups: Vec<String>,
+    pub object: BpfObject,
+    pub links: Vec<BpfLink>,
+    /// Link of the iterator finding the enrolled sockets in the processes.
+    pub iter: BpfLink,
+    pub notify: Option<Arc<AtomicBool>>,
+}
+
+impl Enrollment {
+    /// Let the hook switch flow `flow_id` to `tcp_ca` once its maps exist, or only stop
+    /// waiting for them if the manager sets the CCA itself.
+    pub fn ready(&self, flow_id: u32, apply: bool) -> Result<()> {
+        let map = self
+            .object
+            .map("enroll_ready")
+            .ok_or_else(|| MortiseError::MapNotFound("enroll_ready".to_string()))?;
+        let val = if apply { ENROLL_APPLY } else { ENROLL_SKIP };
+        map.update(&flow_id.to_ne_bytes(), &val.to_ne_bytes(), BpfMapFlags::ANY)?;
+        Ok(())
+    }
+
+    /// Flow id of the enrolled socket `local_sk_fd`.
+    pub fn flow_id(&self, local_sk_fd: i32) -> Option<u32> {
+        let map = self.object.map("enroll_id_stg")?;
+        let val = map
+            .lookup(&local_sk_fd.to_ne_bytes(), BpfMapFlags::ANY)
+            .ok()??;
+        Some(u32::from_ne_bytes(val.get(..4)?.try_into().ok()?))
+    }
+
+    /// Enrolled sockets held by the processes, found by walking their fd tables.
+    pub fn sockets(&self) -> Result<Vec<EnrollSock>> {
+        let mut buf = Vec::new();
+        BpfIter::new(&self.iter)?.read_to_end(&mut buf)?;
+        let size = std::mem::size_of::<EnrollSock>();
+        Ok(buf
+            .chunks_exact(size)
+            .map(|data| {
+                let mut sock = EnrollSock::default();
+                sock.copy_from_bytes(data).unwrap();
+                sock
+            })
+            .collect())
+    }
+}
+
+impl Drop for Enrollment {
+    fn drop(&mut self) {
+        // The event thread is not joined: it may be waiting for the manager thread that
+        // is dropping the enrollment. It stops at its next poll.
+        if let Some(ref notify) = self.notify {
+            notify.store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+        self.links.clear();
+    }
+}
+
+/// Forward an event of the enrollment hook to the manager thread.
+pub fn handle_enroll_event(
+    data: &[u8],
+    obj_id: u32,
+    tx: &mpsc::Sender<ManagerIpcOperation>,
+) -> i32 {
+    let mut event = EnrollEvent::default();
+    if event.copy_from_bytes(data).is_err() {
+        tracing::warn!(target: "manager:enroll", "Skip short event of {} bytes", data.len());
+        return 0;
+    }
+    let op = match event.kind {
+        ENROLL_CONNECT => FlowOperation::Enrolled { obj_id },
+        ENROLL_CLOSE => FlowOperation::Disconnect,
+        kind => {
+            tracing::warn!(target: "manager:enroll", "Unknown event {}", kind);
+            return 0;
+        }
+    };
+    tracing::debug!(target: "manager:enroll", "Event {:?}", event);
+    let (resp, rx) = oneshot::channel();
+    let op = ManagerIpcOperation {
+        req: op.to_op(event.flow_id),
+        resp,
+        peer_uid: None,
+    };
+    if tx.blocking_send(op).is_err() {
+        return 0;
+    }
+    if let Ok(Err(e)) = rx.blocking_recv() {
+        tracing::error!(target: "manager:enroll", "Fail to handle event of flow {}: {}", event.flow_id, e);
+    }
+    0
+}
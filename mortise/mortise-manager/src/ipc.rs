@@ -1,10 +1,19 @@
+use crate::canary::CanaryActive;
 use crate::ManagerIpcOperation;
-use futures::{SinkExt, StreamExt};
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, SinkExt, StreamExt};
 use mortise_common::{
+    codec::BINARY_VERSION,
+    policy::{FlowPolicy, PolicyAction, QoEModel, Strategy},
     qoe::{AppInfo, FrameQoE},
-    read_be_u32, FlowOperation, ManagerOperation, Operation, Result,
+    read_be_u32, BatchResponse, Encoding, FlowOperation, ManagerOperation, ManagerRequest,
+    ManagerResponse, MortiseError, Operation, Result,
+};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{atomic::Ordering, Mutex},
 };
-use std::collections::{HashSet, VecDeque};
 use tokio::{
     net::UnixStream,
     sync::{mpsc, oneshot},
@@ -14,23 +23,49 @@ use tokio_util::codec::LengthDelimitedCodec;
 async fn process_request(
     req: Operation,
     manager_tx: &mpsc::Sender<ManagerIpcOperation>,
-    info: &mut PerUdsLocalInfo,
+    info: &Mutex<PerUdsLocalInfo>,
 ) -> Result<Vec<u8>> {
     let (tx, rx) = oneshot::channel();
+    let peer_uid = info.lock().unwrap().peer_uid;
     match req {
         Operation::Manager(op) => match op {
             ManagerOperation::Shutdown => {
                 let op = ManagerIpcOperation {
                     req: ManagerOperation::Shutdown.into(),
                     resp: tx,
+                    peer_uid,
                 };
                 manager_tx.send(op).await?;
-                Ok(Vec::new())
+                // Answered with the report once the flows are drained
+                rx.await?
+            }
+            ManagerOperation::RegisterShmReport { ref name, .. } => {
+                let name = name.clone();
+                let m_op = ManagerIpcOperation {
+                    req: op.into(),
+                    resp: tx,
+                    peer_uid,
+                };
+                manager_tx.send(m_op).await?;
+                let res = rx.await??;
+                info.lock().unwrap().shm_reports.insert(name);
+                Ok(res)
+            }
+            ManagerOperation::UnregisterShmReport { ref name } => {
+                info.lock().unwrap().shm_reports.remove(name);
+                let m_op = ManagerIpcOperation {
+                    req: op.into(),
+                    resp: tx,
+                    peer_uid,
+                };
+                manager_tx.send(m_op).await?;
+                rx.await?
             }
             _ => {
                 let m_op = ManagerIpcOperation {
                     req: op.into(),
                     resp: tx,
+                    peer_uid,
                 };
                 manager_tx.send(m_op).await?;
                 rx.await?
@@ -42,6 +77,7 @@ async fn process_request(
                 sk_fd,
                 pid,
                 default_app_info,
+                tag,
             } => {
                 let op = ManagerIpcOperation {
                     req: FlowOperation::Connect {
@@ -49,33 +85,57 @@ async fn process_request(
                         sk_fd,
                         pid,
                         default_app_info,
+                        tag,
                     }
                     .to_op(flow_id),
                     resp: tx,
+                    peer_uid,
                 };
                 manager_tx.send(op).await?;
                 let res = rx.await??;
                 let flow_id = read_be_u32(&mut res.as_ref());
-                info.flows.insert(flow_id);
+                info.lock().unwrap().flows.insert(flow_id);
+                if obj_id.is_none() {
+                    track_policy(flow_id, manager_tx, info).await;
+                }
                 Ok(res)
             }
+            FlowOperation::Disconnect => {
+                info.lock().unwrap().policies.remove(&flow_id);
+                let m_op = ManagerIpcOperation {
+                    req: op.to_op(flow_id),
+                    resp: tx,
+                    peer_uid,
+                };
+                manager_tx.send(m_op).await?;
+                rx.await?
+            }
             FlowOperation::QoEUpdate { qoe } => {
                 // TODO: how to leverage client QoE info
                 tracing::trace!(target: "manager:qoe", "update value: {:?}", qoe);
-                let transient_tradeoff = qoe_tradeoff(qoe.score());
-                let stable_tradeoff = info.last_stable_tradeoff;
-                info.qoe_record.push_back(qoe);
-                if info.qoe_record.len() > 5 {
-                    info.qoe_record.pop_front();
-                }
-                let mut mean_score = 0.0;
-                for q in &info.qoe_record {
-                    mean_score += q.score();
-                }
-                mean_score /= info.qoe_record.len() as f64;
-                info.last_stable_tradeoff = qoe_tradeoff(mean_score);
-                let tradeoff = (stable_tradeoff + transient_tradeoff) / 2;
-                if tradeoff != stable_tradeoff {
+                let (qoe_model, strategy) = match info.lock().unwrap().policies.get(&flow_id) {
+                    Some(action) => (action.qoe_model.unwrap_or_default(), action.strategy),
+                    None => (QoEModel::default(), None),
+                };
+                let (tradeoff, stable_tradeoff) = {
+                    let mut info = info.lock().unwrap();
+                    let transient_tradeoff = qoe_tradeoff(qoe_model.score(&qoe));
+                    let stable_tradeoff = info.last_stable_tradeoff;
+                    info.qoe_record.push_back(qoe.clone());
+                    if info.qoe_record.len() > 5 {
+                        info.qoe_record.pop_front();
+                    }
+                    let mut mean_score = 0.0;
+                    for q in &info.qoe_record {
+                        mean_score += qoe_model.score(q);
+                    }
+                    mean_score /= info.qoe_record.len() as f64;
+                    info.last_stable_tradeoff = qoe_tradeoff(mean_score);
+                    ((stable_tradeoff + transient_tradeoff) / 2, stable_tradeoff)
+                };
+                // Flows of another strategy are tuned by it, or not at all
+                let tuned = matches!(strategy, None | Some(Strategy::Qoe));
+                if tuned && tradeoff != stable_tradeoff {
                     let val = AppInfo {
                         req: tradeoff,
                         resp: 0,
@@ -88,6 +148,7 @@ async fn process_request(
                         }
                         .to_op(flow_id),
                         resp: tx,
+                        peer_uid,
                     };
                     manager_tx.send(op).await?;
                     let res = rx.await?;
@@ -95,17 +156,96 @@ async fn process_request(
                         tracing::error!(target: "manager:qoe", "Fail to update trade off: {:?}", e);
                     }
                 }
-                Ok(Vec::new())
+                // The manager keeps the per-version summaries of a canary rollout
+                if !info.lock().unwrap().canary_active.load(Ordering::Relaxed) {
+                    return Ok(Vec::new());
+                }
+                let (tx, rx) = oneshot::channel();
+                let op = ManagerIpcOperation {
+                    req: FlowOperation::QoEUpdate { qoe }.to_op(flow_id),
+                    resp: tx,
+                    peer_uid,
+                };
+                manager_tx.send(op).await?;
+                rx.await?
             }
             _ => {
                 let m_op = ManagerIpcOperation {
                     req: op.to_op(flow_id),
                     resp: tx,
+                    peer_uid,
                 };
                 manager_tx.send(m_op).await?;
                 rx.await?
             }
         },
+        Operation::Batch { ops, atomic } => {
+            // Remember which operations connect new flows, so that they can be released
+            // together with the connection, and which of them leave the CCA to the policy.
+            let connects: Vec<Option<bool>> = ops
+                .iter()
+                .map(|op| match op {
+                    Operation::Flow {
+                        op: FlowOperation::Connect { obj_id, .. },
+                        ..
+                    } => Some(obj_id.is_none()),
+                    _ => None,
+                })
+                .collect();
+            let m_op = ManagerIpcOperation {
+                req: Operation::batch(ops, atomic),
+                resp: tx,
+                peer_uid,
+            };
+            manager_tx.send(m_op).await?;
+            let res = rx.await??;
+            if connects.iter().any(|c| c.is_some()) {
+                let results: BatchResponse =
+                    serde_json::from_slice(&res).map_err(|e| MortiseError::Codec(e.to_string()))?;
+                let mut by_policy = Vec::new();
+                {
+                    let mut info = info.lock().unwrap();
+                    for (connect, r) in connects.into_iter().zip(results) {
+                        if let (Some(policy), Ok(r)) = (connect, r) {
+                            let flow_id = read_be_u32(&mut r.as_ref());
+                            info.flows.insert(flow_id);
+                            if policy {
+                                by_policy.push(flow_id);
+                            }
+                        }
+                    }
+                }
+                for flow_id in by_policy {
+                    track_policy(flow_id, manager_tx, info).await;
+                }
+            }
+            Ok(res)
+        }
+    }
+}
+
+/// Remember the QoE model and strategy the policy gave to a flow connected without
+/// choosing its CCA.
+async fn track_policy(
+    flow_id: u32,
+    manager_tx: &mpsc::Sender<ManagerIpcOperation>,
+    info: &Mutex<PerUdsLocalInfo>,
+) {
+    let (tx, rx) = oneshot::channel();
+    let op = ManagerIpcOperation {
+        req: FlowOperation::PolicyLookup.to_op(flow_id),
+        resp: tx,
+        peer_uid: info.lock().unwrap().peer_uid,
+    };
+    if manager_tx.send(op).await.is_err() {
+        return;
+    }
+    let policy = match rx.await {
+        Ok(Ok(res)) => serde_json::from_slice::<FlowPolicy>(&res).ok(),
+        _ => None,
+    };
+    if let Some(policy) = policy {
+        info.lock().unwrap().policies.insert(flow_id, policy.action);
     }
 }
 
@@ -128,16 +268,25 @@ fn qoe_tradeoff(score: f64) -> u64 {
 #[derive(Default)]
 pub struct PerUdsLocalInfo {
     pub flows: HashSet<u32>,
+    /// Policies of the flows connected without choosing their CCA.
+    pub policies: HashMap<u32, PolicyAction>,
+    pub shm_reports: HashSet<String>,
     pub qoe_record: VecDeque<FrameQoE>,
     pub last_stable_tradeoff: u64,
+    pub peer_uid: Option<u32>,
+    pub canary_active: CanaryActive,
 }
 
 impl PerUdsLocalInfo {
     pub fn new() -> Self {
         PerUdsLocalInfo {
             flows: HashSet::new(),
+            policies: HashMap::new(),
+            shm_reports: HashSet::new(),
             qoe_record: VecDeque::new(),
             last_stable_tradeoff: 0,
+            peer_uid: None,
+            canary_active: CanaryActive::default(),
         }
     }
 
@@ -149,14 +298,94 @@ impl PerUdsLocalInfo {
                 flow_id,
                 op: FlowOperation::Disconnect,
             })
+            .chain(
+                self.shm_reports
+                    .drain()
+                    .map(|name| ManagerOperation::UnregisterShmReport { name }.into()),
+            )
             .collect();
+        let info = Mutex::new(self);
         for op in ops {
-            let _ = process_request(op, manager_tx, &mut self).await;
+            let _ = process_request(op, manager_tx, &info).await;
         }
     }
 }
 
-pub async fn handle_uds(mut receiver: UnixStream, manager_tx: mpsc::Sender<ManagerIpcOperation>) {
+/// Frame received from a client, either tagged with a correlation id or a plain
+/// lockstep request.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RequestFrame {
+    Tagged(ManagerRequest),
+    Plain(Operation),
+}
+
+impl RequestFrame {
+    fn decode(encoding: Encoding, buf: &[u8]) -> Result<Self> {
+        match encoding {
+            // Only tagged requests are accepted in binary encoding
+            Encoding::Binary => encoding.decode(buf).map(RequestFrame::Tagged),
+            Encoding::Json => {
+                serde_json::from_slice(buf).map_err(|e| MortiseError::Codec(e.to_string()))
+            }
+        }
+    }
+
+    /// Correlation id of a frame that failed to decode, so that the error can still be
+    /// answered to a client waiting for it.
+    fn recover_id(encoding: Encoding, buf: &[u8]) -> Option<u64> {
+        #[derive(Deserialize)]
+        struct Id {
+            id: u64,
+        }
+        match encoding {
+            // The id is written first in binary encoding
+            Encoding::Binary => buf
+                .get(..8)
+                .map(|id| u64::from_le_bytes(id.try_into().unwrap())),
+            Encoding::Json => serde_json::from_slice::<Id>(buf).ok().map(|frame| frame.id),
+        }
+    }
+
+    fn id(&self) -> Option<u64> {
+        match self {
+            RequestFrame::Tagged(req) => Some(req.id),
+            RequestFrame::Plain(_) => None,
+        }
+    }
+
+    fn set_encoding(&self) -> Option<(Encoding, u32)> {
+        let op = match self {
+            RequestFrame::Tagged(req) => &req.op,
+            RequestFrame::Plain(op) => op,
+        };
+        match op {
+            Operation::Manager(ManagerOperation::SetEncoding { encoding, version }) => {
+                Some((*encoding, *version))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn encode_response(
+    encoding: Encoding,
+    id: Option<u64>,
+    resp: std::result::Result<Vec<u8>, String>,
+) -> Bytes {
+    match id {
+        Some(id) => encoding.encode(&ManagerResponse { id, resp }),
+        None => serde_json::to_vec(&resp).map_err(|e| MortiseError::Codec(e.to_string())),
+    }
+    .map(Into::into)
+    .unwrap()
+}
+
+pub async fn handle_uds(
+    mut receiver: UnixStream,
+    manager_tx: mpsc::Sender<ManagerIpcOperation>,
+    canary_active: CanaryActive,
+) {
     // let pid = receiver.peer_cred().unwrap().pid().unwrap();
     // tracing::debug!("Peer pid: {}", pid);
     // let pid_fd = match pid_open(pid, false) {
@@ -166,6 +395,14 @@ pub async fn handle_uds(mut receiver: UnixStream, manager_tx: mpsc::Sender<Manag
     //         return;
     //     }
     // };
+    // Operations without a peer uid run with the privileges of the manager itself
+    let peer_uid = match receiver.peer_cred() {
+        Ok(cred) => Some(cred.uid()),
+        Err(e) => {
+            tracing::error!(target: "manager:uds", "Refuse a client without credentials: {}", e);
+            return;
+        }
+    };
     let (rh, wh) = receiver.split();
     let mut reader = LengthDelimitedCodec::builder()
         .length_field_offset(0) // default value
@@ -175,32 +412,109 @@ pub async fn handle_uds(mut receiver: UnixStream, manager_tx: mpsc::Sender<Manag
     let mut writer = LengthDelimitedCodec::builder()
         .length_field_type::<u32>()
         .new_write(wh);
-    let mut info = PerUdsLocalInfo::new();
-    loop {
-        match reader.next().await {
-            None => {
-                tracing::info!(target: "manager:uds", "Connection closed");
-                info.release(&manager_tx).await;
-                break;
+    let info = Mutex::new(PerUdsLocalInfo {
+        peer_uid,
+        canary_active,
+        ..PerUdsLocalInfo::new()
+    });
+    let mut encoding = Encoding::Json;
+    // Tagged requests in flight, answered as soon as each of them completes
+    let mut in_flight = FuturesUnordered::new();
+    'conn: loop {
+        tokio::select! {
+            Some((id, resp)) = in_flight.next(), if !in_flight.is_empty() => {
+                let resp_bytes = encode_response(encoding, Some(id), resp);
+                if let Err(e) = writer.send(resp_bytes).await {
+                    tracing::error!(target: "manager:uds", "Fail to send response: {:?}", e);
+                    break;
+                }
             }
-            Some(res) => match res {
-                Ok(bytes) => {
-                    let req: Operation = serde_json::from_slice(bytes.as_ref()).unwrap();
-                    let resp = process_request(req, &manager_tx, &mut info)
-                        .await
-                        .map_err(|e| {
-                            tracing::error!(target: "manager:uds", "{}", e);
-                            e.to_string()
-                        });
-                    let resp_bytes = serde_json::to_vec(&resp).map(Into::into).unwrap();
-                    writer.send(resp_bytes).await.unwrap();
+            frame = reader.next() => match frame {
+                None => {
+                    tracing::info!(target: "manager:uds", "Connection closed");
+                    break;
                 }
-                Err(e) => {
-                    info.release(&manager_tx).await;
+                Some(Ok(bytes)) => match RequestFrame::decode(encoding, bytes.as_ref()) {
+                    Ok(frame) if frame.set_encoding().is_some() => {
+                        let (new_encoding, version) = frame.set_encoding().unwrap();
+                        // Answer the requests in flight before switching the encoding
+                        while let Some((id, resp)) = in_flight.next().await {
+                            let resp_bytes = encode_response(encoding, Some(id), resp);
+                            if let Err(e) = writer.send(resp_bytes).await {
+                                tracing::error!(target: "manager:uds", "Fail to send response: {:?}", e);
+                                break 'conn;
+                            }
+                        }
+                        let resp = if new_encoding == Encoding::Binary && version != BINARY_VERSION {
+                            Err(format!(
+                                "binary encoding version {} is not supported, expect {}",
+                                version, BINARY_VERSION
+                            ))
+                        } else {
+                            Ok(Vec::new())
+                        };
+                        let accepted = resp.is_ok();
+                        let resp_bytes = encode_response(encoding, frame.id(), resp);
+                        if let Err(e) = writer.send(resp_bytes).await {
+                            tracing::error!(target: "manager:uds", "Fail to send response: {:?}", e);
+                            break;
+                        }
+                        if !accepted {
+                            tracing::warn!(target: "manager:uds", "Refuse binary encoding version {}", version);
+                            continue;
+                        }
+                        encoding = new_encoding;
+                        tracing::debug!(target: "manager:uds", "Switch to {} encoding", encoding);
+                    }
+                    Ok(RequestFrame::Tagged(ManagerRequest { id, op })) => {
+                        let manager_tx = &manager_tx;
+                        let info = &info;
+                        in_flight.push(async move {
+                            let resp = process_request(op, manager_tx, info)
+                                .await
+                                .map_err(|e| {
+                                    tracing::error!(target: "manager:uds", "{}", e);
+                                    e.to_string()
+                                });
+                            (id, resp)
+                        });
+                    }
+                    Ok(RequestFrame::Plain(req)) => {
+                        let resp = process_request(req, &manager_tx, &info)
+                            .await
+                            .map_err(|e| {
+                                tracing::error!(target: "manager:uds", "{}", e);
+                                e.to_string()
+                            });
+                        let resp_bytes = encode_response(encoding, None, resp);
+                        if let Err(e) = writer.send(resp_bytes).await {
+                            tracing::error!(target: "manager:uds", "Fail to send response: {:?}", e);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(target: "manager:uds", "Invalid request: {}", e);
+                        let id = RequestFrame::recover_id(encoding, bytes.as_ref());
+                        if id.is_none() && encoding == Encoding::Binary {
+                            // Binary frames are always tagged, the client can not be answered
+                            break;
+                        }
+                        let resp_bytes = encode_response(encoding, id, Err(e.to_string()));
+                        if let Err(e) = writer.send(resp_bytes).await {
+                            tracing::error!(target: "manager:uds", "Fail to send response: {:?}", e);
+                            break;
+                        }
+                    }
+                },
+                Some(Err(e)) => {
                     tracing::error!(target: "manager:uds", "Error: {:?}", e);
                     break;
                 }
             },
         }
     }
+    // Requests in flight may still connect flows, wait for them before releasing
+    while in_flight.next().await.is_some() {}
+    drop(in_flight);
+    info.into_inner().unwrap().release(&manager_tx).await;
 }
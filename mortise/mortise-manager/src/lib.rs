@@ -1,18 +1,27 @@
+pub mod canary;
 pub mod core;
+pub mod enroll;
 pub mod ipc;
 pub mod object;
+pub mod policy;
+pub mod pool;
 mod private;
+pub mod shm;
+pub mod store;
+pub mod validate;
 
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::SinkExt;
 use libbpf_rs::{MapFlags as BpfMapFlags, RingBufferBuilder as BpfRingBufferBuilder};
 use mortise_common::op::PyOperation;
+use mortise_common::report::ReportEntry;
 use mortise_common::{
-    FlowOperation, ManagerIpcOperation, ManagerOperation, MortiseError, Operation, Result,
+    BatchResponse, FlowOperation, ManagerEvent, ManagerIpcOperation, ManagerOperation,
+    MortiseError, Operation, Result,
 };
 use tokio::net::UnixStream;
 use tokio::sync::mpsc;
@@ -21,6 +30,8 @@ use tokio_util::codec::LengthDelimitedCodec;
 pub use crate::core::*;
 pub use crate::ipc::handle_uds;
 pub use crate::object::*;
+pub use crate::shm::{ShmReportSink, ShmReportSinks};
+pub use crate::store::ObjectStore;
 
 pub const MORTISE_SOCK_PATH: &str = "/tmp/mortise.sock";
 pub const MORTISE_PY_PATH: &str = "/tmp/mortise-py.sock";
@@ -57,13 +68,22 @@ pub async fn connect_py() -> Option<mpsc::UnboundedSender<Vec<u8>>> {
 fn handle_op(
     m: &mut MortiseManager,
     op: Operation,
+    peer_uid: Option<u32>,
     tx: &mpsc::Sender<ManagerIpcOperation>,
     py_con: &Option<mpsc::UnboundedSender<Vec<u8>>>,
 ) -> Result<Vec<u8>> {
+    // Operations without a peer are sent by the manager itself, which runs as root
+    let loader_uid = peer_uid.unwrap_or(0);
+    // The socket of a pending enrolled flow may have been accepted since
+    if let Operation::Flow { flow_id, ref op } = op {
+        if m.enrolled.contains_key(&flow_id) && !matches!(op, FlowOperation::Disconnect) {
+            m.resolve_enrolled();
+        }
+    }
     match op {
         Operation::Manager(op) => match op {
             ManagerOperation::Load { path, option } => {
-                let obj_id = m.open_and_load_object(path, option);
+                let obj_id = m.open_and_load_object(path, option, loader_uid);
                 match &obj_id {
                     Ok(ref inner_id) => {
                         tracing::info!(target: "manager", "Load object with id {}", inner_id)
@@ -87,7 +107,7 @@ fn handle_op(
                 path,
                 option,
             } => {
-                let res = m.insert_and_load_object(obj_id, path, option);
+                let res = m.insert_and_load_object(obj_id, path, option, loader_uid);
                 match &res {
                     Ok(_) => tracing::info!(target: "manager", "Insert object with id {}", obj_id),
                     Err(ref e) => {
@@ -96,6 +116,54 @@ fn handle_op(
                 }
                 res.map(|_| Vec::new())
             }
+            ManagerOperation::Upgrade { obj_id, path } => {
+                let res = m.upgrade_object(obj_id, path, loader_uid);
+                match &res {
+                    Ok(_) => tracing::info!(target: "manager", "Upgrade object with id {}", obj_id),
+                    Err(ref e) => {
+                        tracing::error!(target: "manager", "Fail to upgrade object: {}", e)
+                    }
+                }
+                res.map(|_| Vec::new())
+            }
+            ManagerOperation::StartCanary { config } => {
+                tracing::info!(target: "manager:canary", "Start canary rollout: {:?}", config);
+                m.start_canary(config).map(|_| Vec::new())
+            }
+            ManagerOperation::PromoteCanary => {
+                let report = m.promote_canary()?;
+                tracing::info!(target: "manager:canary", "Promote canary: {:?}", report);
+                Ok(serde_json::to_vec(&report).unwrap())
+            }
+            ManagerOperation::RollbackCanary => {
+                let report = m.rollback_canary()?;
+                tracing::info!(target: "manager:canary", "Rollback canary: {:?}", report);
+                Ok(serde_json::to_vec(&report).unwrap())
+            }
+            ManagerOperation::CanaryStats => {
+                let report = m.canary_report()?;
+                Ok(serde_json::to_vec(&report).unwrap())
+            }
+            ManagerOperation::ReloadPolicy { path } => {
+                let res = m.reload_policy(path, loader_uid);
+                match &res {
+                    Ok(_) => {
+                        tracing::info!(target: "manager:policy", "Load policy {}", m.policy.as_ref().unwrap().path)
+                    }
+                    Err(ref e) => {
+                        tracing::error!(target: "manager:policy", "Fail to load policy: {}", e)
+                    }
+                }
+                res.map(|_| Vec::new())
+            }
+            ManagerOperation::PolicyStats => {
+                let report = m.policy_report()?;
+                Ok(serde_json::to_vec(&report).unwrap())
+            }
+            ManagerOperation::ListObjects => Ok(serde_json::to_vec(&m.list_objects()).unwrap()),
+            ManagerOperation::Subscribe => Err(MortiseError::Custom(
+                "Subscribe can only be sent alone".to_string(),
+            )),
             ManagerOperation::Shutdown => {
                 // Here we do nothing, since all Shutdown operations are hijacked before entering this function.
                 // m.showdown().unwrap();
@@ -116,8 +184,9 @@ fn handle_op(
                     for handle in map_hds.iter() {
                         let mut conn = py_con.clone();
                         let mut tx = tx.clone();
+                        let sinks = m.report_sinks.clone();
                         let handle_event =
-                            move |data: &[u8]| handle_report(data, &mut tx, &mut conn);
+                            move |data: &[u8]| handle_report(data, &mut tx, &mut conn, &sinks);
                         rb.add(handle, handle_event)?;
                     }
                     let rb = rb.build().unwrap();
@@ -145,6 +214,66 @@ fn handle_op(
                 m.unregister_rb()?;
                 Ok(Vec::new())
             }
+            ManagerOperation::RegisterShmReport {
+                name,
+                capacity,
+                doorbell,
+            } => {
+                let res = m.register_shm_report(name.clone(), capacity, doorbell, loader_uid);
+                match &res {
+                    Ok(_) => {
+                        tracing::info!(target: "manager:shm", "Register report ring {} of {} entries", name, capacity)
+                    }
+                    Err(ref e) => {
+                        tracing::error!(target: "manager:shm", "Fail to register report ring {}: {}", name, e)
+                    }
+                }
+                res.map(|_| Vec::new())
+            }
+            ManagerOperation::UnregisterShmReport { name } => {
+                let overruns = m.unregister_shm_report(&name)?;
+                tracing::info!(target: "manager:shm", "Unregister report ring {}, {} entries dropped", name, overruns);
+                Ok(overruns.to_be_bytes().to_vec())
+            }
+            ManagerOperation::Enroll {
+                obj_id,
+                path,
+                tcp_ca,
+                cgroups,
+            } => {
+                let handle = match m.enroll(obj_id, path, tcp_ca, cgroups, loader_uid) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        tracing::error!(target: "manager:enroll", "Fail to enroll: {}", e);
+                        return Err(e);
+                    }
+                };
+                let mut rb = BpfRingBufferBuilder::new();
+                let enroll_tx = tx.clone();
+                rb.add(&handle, move |data: &[u8]| {
+                    enroll::handle_enroll_event(data, obj_id, &enroll_tx)
+                })?;
+                let rb = rb.build().unwrap();
+                let notify_inner = Arc::new(AtomicBool::new(true));
+                let notify_manager = notify_inner.clone();
+                thread::Builder::new()
+                    .name("enroll-manager".to_string())
+                    .spawn(move || loop {
+                        rb.poll(Duration::from_millis(200)).unwrap();
+                        if !notify_inner.load(std::sync::atomic::Ordering::Relaxed) {
+                            tracing::info!(target: "manager:enroll", "Stop enrollment thread");
+                            break;
+                        }
+                    })?;
+                m.enrollment.as_mut().unwrap().notify = Some(notify_manager);
+                Ok(Vec::new())
+            }
+            ManagerOperation::Unenroll => {
+                m.unenroll();
+                Ok(Vec::new())
+            }
+            // Encoding is a property of the socket connection and handled in `handle_uds`.
+            ManagerOperation::SetEncoding { .. } => Ok(Vec::new()),
         },
         Operation::Flow { flow_id, op } => match op {
             FlowOperation::SkStgMapUpdate {
@@ -182,10 +311,15 @@ fn handle_op(
                 sk_fd,
                 pid,
                 default_app_info,
+                tag,
             } => {
-                let res = m.connect(pid, obj_id, sk_fd, default_app_info);
+                let res = m.connect(pid, obj_id, sk_fd, default_app_info, tag);
                 let flow_id = res?;
-                let r = serde_json::to_vec(&PyOperation::Connect { flow_id }).unwrap();
+                let strategy = m
+                    .get_flow_metadata(flow_id)
+                    .and_then(|metadata| metadata.policy.as_ref())
+                    .and_then(|policy| policy.action.strategy);
+                let r = serde_json::to_vec(&PyOperation::Connect { flow_id, strategy }).unwrap();
                 if let Some(ref con) = py_con {
                     tracing::info!(target: "manager:flow", "Connect flow {} to py", flow_id);
                     con.send(r).unwrap();
@@ -201,8 +335,179 @@ fn handle_op(
                 }
                 res.map(|_| Vec::new())
             }
-            FlowOperation::QoEUpdate { .. } => Ok(Vec::new()),
+            FlowOperation::Enrolled { obj_id } => {
+                if peer_uid.is_some() {
+                    return Err(MortiseError::Custom(
+                        "Enrolled is sent by the manager only".to_string(),
+                    ));
+                }
+                m.enroll_flow(flow_id, obj_id)?;
+                tracing::debug!(target: "manager:enroll", "Enroll flow {} to object {}", flow_id, obj_id);
+                let strategy = m
+                    .get_flow_metadata(flow_id)
+                    .and_then(|metadata| metadata.policy.as_ref())
+                    .and_then(|policy| policy.action.strategy);
+                let r = serde_json::to_vec(&PyOperation::Connect { flow_id, strategy }).unwrap();
+                if let Some(ref con) = py_con {
+                    con.send(r).unwrap();
+                }
+                Ok(Vec::new())
+            }
+            FlowOperation::Migrate { obj_id, tcp_ca } => {
+                m.migrate(flow_id, obj_id, &tcp_ca)?;
+                // The strategy of the flow starts over with its new CCA
+                let r = serde_json::to_vec(&PyOperation::Migrate { flow_id, obj_id }).unwrap();
+                if let Some(ref con) = py_con {
+                    con.send(r).unwrap();
+                }
+                Ok(Vec::new())
+            }
+            FlowOperation::QoEUpdate { qoe } => {
+                m.record_qoe(flow_id, &qoe);
+                Ok(Vec::new())
+            }
+            FlowOperation::PolicyLookup => {
+                let metadata = m
+                    .get_flow_metadata(flow_id)
+                    .ok_or(MortiseError::FlowNotFound(flow_id))?;
+                let policy = metadata
+                    .policy
+                    .as_ref()
+                    .ok_or(MortiseError::NoPolicy(flow_id))?;
+                Ok(serde_json::to_vec(policy).unwrap())
+            }
         },
+        Operation::Batch { ops, atomic } => handle_batch(m, ops, atomic, peer_uid, tx, py_con),
+    }
+}
+
+/// Record to restore a map element touched by an atomic batch.
+struct BatchUndo {
+    obj_id: u32,
+    map_name: String,
+    key: Vec<u8>,
+    /// Value before the update, `None` if the element did not exist.
+    val: Option<Vec<u8>>,
+}
+
+/// Prepare the undo record of an operation in an atomic batch.
+///
+/// Only operations that can be rolled back are accepted.
+fn batch_undo(m: &MortiseManager, op: &Operation) -> Result<Option<BatchUndo>> {
+    match op {
+        Operation::Manager(ManagerOperation::PingPong) => Ok(None),
+        Operation::Flow {
+            op: FlowOperation::SkStgMapLookup { .. },
+            ..
+        } => Ok(None),
+        Operation::Flow {
+            flow_id,
+            op: FlowOperation::SkStgMapUpdate { map_name, .. },
+        } => {
+            let metadata = m
+                .get_flow_metadata(*flow_id)
+                .ok_or(MortiseError::FlowNotFound(*flow_id))?;
+            let obj_id = metadata.obj_id;
+            let key = metadata.local_sk_fd.to_ne_bytes().to_vec();
+            let val = match m.lookup_map(obj_id, map_name, &key) {
+                Ok(val) => Some(val),
+                Err(MortiseError::ElemNotFound(_)) => None,
+                Err(e) => return Err(e),
+            };
+            Ok(Some(BatchUndo {
+                obj_id,
+                map_name: map_name.clone(),
+                key,
+                val,
+            }))
+        }
+        _ => Err(MortiseError::BatchNotAtomic(format!("{:?}", op))),
+    }
+}
+
+fn batch_rollback(m: &mut MortiseManager, undo: Vec<BatchUndo>) {
+    for u in undo.into_iter().rev() {
+        let res = match u.val {
+            Some(ref val) => m.update_map(u.obj_id, &u.map_name, &u.key, val, BpfMapFlags::ANY),
+            None => m.delete_map(u.obj_id, &u.map_name, &u.key),
+        };
+        if let Err(e) = res {
+            tracing::error!(target: "manager:batch", "Fail to roll back map {}: {}", u.map_name, e);
+        }
+    }
+}
+
+/// Operations that are only handled outside of the manager thread, or that can not
+/// be sent alongside others.
+fn batch_unsupported(op: &Operation) -> Option<&'static str> {
+    match op {
+        Operation::Batch { .. } => Some("Nested batch is not supported"),
+        // The QoE trade-off is computed per connection by the ipc handler
+        Operation::Flow {
+            op: FlowOperation::QoEUpdate { .. },
+            ..
+        } => Some("QoEUpdate is not supported in a batch"),
+        Operation::Manager(ManagerOperation::Shutdown) => {
+            Some("Shutdown is not supported in a batch")
+        }
+        _ => None,
+    }
+}
+
+fn handle_batch(
+    m: &mut MortiseManager,
+    ops: Vec<Operation>,
+    atomic: bool,
+    peer_uid: Option<u32>,
+    tx: &mpsc::Sender<ManagerIpcOperation>,
+    py_con: &Option<mpsc::UnboundedSender<Vec<u8>>>,
+) -> Result<Vec<u8>> {
+    tracing::debug!(target: "manager:batch", "Batch of {} operations, atomic: {}", ops.len(), atomic);
+    let mut results: BatchResponse = Vec::with_capacity(ops.len());
+    let mut undo = Vec::new();
+    for (idx, op) in ops.into_iter().enumerate() {
+        let res = if let Some(reason) = batch_unsupported(&op) {
+            Err(MortiseError::Custom(reason.to_string()))
+        } else if atomic {
+            batch_undo(m, &op).and_then(|u| {
+                let res = handle_op(m, op, peer_uid, tx, py_con)?;
+                undo.extend(u);
+                Ok(res)
+            })
+        } else {
+            handle_op(m, op, peer_uid, tx, py_con)
+        };
+        match res {
+            Ok(r) => results.push(Ok(r)),
+            Err(e) if atomic => {
+                batch_rollback(m, undo);
+                return Err(MortiseError::BatchAborted(idx, e.to_string()));
+            }
+            Err(e) => results.push(Err(e.to_string())),
+        }
+    }
+    Ok(serde_json::to_vec(&results).unwrap())
+}
+
+/// Whether `op` runs while the flows drain after a shutdown: only disconnects, the
+/// release of a client and lookups, since the struct_ops are about to be destroyed.
+fn drain_allowed(op: &Operation) -> bool {
+    match op {
+        Operation::Manager(op) => matches!(
+            op,
+            ManagerOperation::UnregisterShmReport { .. }
+                | ManagerOperation::CanaryStats
+                | ManagerOperation::PolicyStats
+                | ManagerOperation::ListObjects
+                | ManagerOperation::PingPong
+        ),
+        Operation::Flow { op, .. } => matches!(
+            op,
+            FlowOperation::Disconnect
+                | FlowOperation::SkStgMapLookup { .. }
+                | FlowOperation::PolicyLookup
+        ),
+        Operation::Batch { .. } => false,
     }
 }
 
@@ -210,27 +515,87 @@ pub fn manager(
     tx: mpsc::Sender<ManagerIpcOperation>,
     mut rx: mpsc::Receiver<ManagerIpcOperation>,
     py_con: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    config: ManagerConfig,
 ) {
     let mut m = MortiseManager::new();
-    loop {
+    m.store = config.store;
+    m.map_pool_size = config.map_pool_size;
+    m.canary_active = config.canary_active.clone();
+    m.fallback_tcp_ca = config.fallback_tcp_ca.clone();
+    let mut subscribers = Vec::new();
+    let shutdown_resp = loop {
         match rx.blocking_recv() {
-            None
-            | Some(ManagerIpcOperation {
+            None => break None,
+            Some(ManagerIpcOperation {
                 req: Operation::Manager(ManagerOperation::Shutdown),
-                resp: _,
+                resp,
+                ..
+            }) => break Some(resp),
+            Some(ManagerIpcOperation {
+                req: Operation::Manager(ManagerOperation::Subscribe),
+                resp,
+                ..
             }) => {
-                m.shutdown().unwrap();
-                tracing::info!(target: "manager:shutdown", "All struct_ops destroyed!");
-                break;
+                subscribers.push(resp);
+                // Subscribers whose client went away are dropped
+                subscribers.retain(|s| !s.is_closed());
             }
-            Some(ManagerIpcOperation { req, resp }) => {
-                let res = handle_op(&mut m, req, &tx, &py_con);
+            Some(ManagerIpcOperation {
+                req,
+                resp,
+                peer_uid,
+            }) => {
+                let res = handle_op(&mut m, req, peer_uid, &tx, &py_con);
                 if let Err(ref e) = res {
                     tracing::error!(target: "manager", "{}", e);
                 }
                 resp.send(res).unwrap();
             }
         }
+    };
+
+    // Hand the flows back to the kernel before their struct_ops disappear
+    let event = ManagerEvent::Shutdown {
+        fallback_tcp_ca: config.fallback_tcp_ca.clone(),
+        drain_ms: config.drain_timeout.as_millis() as u64,
+    };
+    let event = serde_json::to_vec(&event).unwrap();
+    tracing::info!(target: "manager:shutdown", "Notify {} subscribers", subscribers.len());
+    for subscriber in subscribers.drain(..) {
+        let _ = subscriber.send(Ok(event.clone()));
+    }
+    let mut report = m.fallback_flows(&config.fallback_tcp_ca);
+    tracing::info!(target: "manager:shutdown", "Switch {} flows to {}, wait {:?} for them to disconnect", report.migrated.len(), config.fallback_tcp_ca, config.drain_timeout);
+    let deadline = Instant::now() + config.drain_timeout;
+    while Instant::now() < deadline && !m.flow_manager.flow_map.is_empty() {
+        match rx.try_recv() {
+            Ok(ManagerIpcOperation {
+                req: Operation::Manager(ManagerOperation::Subscribe),
+                resp,
+                ..
+            }) => {
+                let _ = resp.send(Ok(event.clone()));
+            }
+            Ok(ManagerIpcOperation {
+                req,
+                resp,
+                peer_uid,
+            }) if drain_allowed(&req) => {
+                let res = handle_op(&mut m, req, peer_uid, &tx, &py_con);
+                let _ = resp.send(res);
+            }
+            Ok(ManagerIpcOperation { resp, .. }) => {
+                let _ = resp.send(Err(MortiseError::ShuttingDown));
+            }
+            Err(mpsc::error::TryRecvError::Empty) => thread::sleep(Duration::from_millis(10)),
+            Err(mpsc::error::TryRecvError::Disconnected) => break,
+        }
+    }
+    report.remaining = m.flow_manager.flow_map.len() as u64;
+    m.shutdown().unwrap();
+    tracing::info!(target: "manager:shutdown", "All struct_ops destroyed! Migrated flows {:?}, failed {:?}, unmanaged {:?}, {} still connected", report.migrated, report.failed, report.unmanaged, report.remaining);
+    if let Some(resp) = shutdown_resp {
+        let _ = resp.send(Ok(serde_json::to_vec(&report).unwrap()));
     }
 }
 
@@ -238,6 +603,7 @@ fn handle_report(
     data: &[u8],
     _tx: &mut mpsc::Sender<ManagerIpcOperation>,
     py_con: &mut Option<mpsc::UnboundedSender<Vec<u8>>>,
+    sinks: &ShmReportSinks,
 ) -> i32 {
     // data is stored as little-endian
     // let len = data.len();
@@ -249,6 +615,19 @@ fn handle_report(
         conn.send(data.into()).unwrap();
     }
 
+    // Strategies with a shared-memory ring read the decoded entries in place
+    let mut sinks = sinks.lock().unwrap();
+    if !sinks.is_empty() {
+        if data.len() < std::mem::size_of::<ReportEntry>() {
+            tracing::trace!(target: "manager:shm", "Skip short report of {} bytes", data.len());
+        } else {
+            let entry = ReportEntry::copy_from_bytes(data);
+            for sink in sinks.values_mut() {
+                sink.push(&entry);
+            }
+        }
+    }
+
     // We can also parse the data
     // let data = ReportEntry::from_bytes(data);
     // tracing::info!(target: "manager:flow", "Receive report data: {:?}", data);
@@ -262,3 +641,119 @@ fn handle_report(
     // tracing::info!(target: "manager:flow", "Ping {:?}", r);
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_rollback, handle_op, BatchUndo, FlowManager, MortiseManager};
+    use crate::pool::DEFAULT_MAP_POOL_SIZE;
+    use mortise_common::{
+        qoe::FrameQoE, BatchResponse, FlowOperation, ManagerOperation, MortiseError, Operation,
+    };
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    // Without the rlimits of `MortiseManager::new`, which need privileges.
+    fn manager() -> MortiseManager {
+        MortiseManager {
+            obj_id: 0,
+            objs: Default::default(),
+            open_objs: Default::default(),
+            rb_manager: None,
+            report_sinks: Default::default(),
+            canary: None,
+            canary_active: Default::default(),
+            policy: None,
+            enrollment: None,
+            store: None,
+            map_pool_size: DEFAULT_MAP_POOL_SIZE,
+            enrolled: Default::default(),
+            fallback_tcp_ca: "cubic".to_string(),
+            flow_manager: FlowManager::new(),
+        }
+    }
+
+    #[test]
+    fn test_batch_rollback() {
+        let mut m = manager();
+        let (tx, _rx) = mpsc::channel(1);
+        let update = |flow_id| {
+            FlowOperation::SkStgMapUpdate {
+                map_name: "sk_stg_map".to_string(),
+                val: vec![0; 16],
+                flag: 0,
+            }
+            .to_op(flow_id)
+        };
+
+        let batch = Operation::batch(vec![ManagerOperation::PingPong.into(), update(1)], false);
+        let res = handle_op(&mut m, batch, None, &tx, &None).unwrap();
+        let results: BatchResponse = serde_json::from_slice(&res).unwrap();
+        assert!(results[0].is_ok() && results[1].is_err());
+
+        let batch = Operation::batch(vec![ManagerOperation::PingPong.into(), update(1)], true);
+        match handle_op(&mut m, batch, None, &tx, &None) {
+            Err(MortiseError::BatchAborted(1, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let batch = Operation::batch(vec![ManagerOperation::Unload { obj_id: 1 }.into()], true);
+        assert!(matches!(
+            handle_op(&mut m, batch, None, &tx, &None),
+            Err(MortiseError::BatchAborted(0, _))
+        ));
+
+        let qoe = FrameQoE {
+            server_send: 0,
+            client_recv: 0,
+            server_recv: 0,
+            size: 0,
+            frame_interval: Duration::ZERO,
+            frame_id: 0,
+        };
+        let batch = Operation::batch(
+            vec![
+                FlowOperation::QoEUpdate { qoe }.to_op(1),
+                ManagerOperation::Shutdown.into(),
+            ],
+            false,
+        );
+        let res = handle_op(&mut m, batch, None, &tx, &None).unwrap();
+        let results: BatchResponse = serde_json::from_slice(&res).unwrap();
+        assert!(results.iter().all(|r| r.is_err()));
+
+        // Undo records of a vanished object are skipped
+        batch_rollback(
+            &mut m,
+            vec![BatchUndo {
+                obj_id: 1,
+                map_name: "sk_stg_map".to_string(),
+                key: vec![0; 4],
+                val: None,
+            }],
+        );
+    }
+
+    #[test]
+    fn test_reload_policy_refused() {
+        let mut m = manager();
+        let path = std::env::temp_dir().join(format!("mortise-policy-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let path = path.display().to_string();
+        match m.reload_policy(Some(path.clone()), 1000) {
+            Err(MortiseError::InvalidPolicy(e)) => assert!(e.contains(&path)),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(m.policy.is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_enrolled_from_client() {
+        let mut m = manager();
+        let (tx, _rx) = mpsc::channel(1);
+        let enrolled = FlowOperation::Enrolled { obj_id: 1 }.to_op(1);
+        match handle_op(&mut m, enrolled, Some(1000), &tx, &None) {
+            Err(MortiseError::Custom(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -1,10 +1,11 @@
+use crate::pool::InnerMapPool;
 use crate::private;
 use libbpf_rs::{
     Link as BpfLink, Map as BpfMap, MapHandle as BpfMapHandle, MapType as BpfMapType,
     Object as BpfObject, OpenMap as BpfOpenMap, OpenObject as BpfOpenObject,
     OpenProgram as BpfOpenProgram, Program as BprProgram,
 };
-use mortise_common::{ConnectOption, Result};
+use mortise_common::{store::ObjectProvenance, ConnectOption, Result};
 use rustc_hash::FxHashMap as HashMap;
 use std::os::fd::{AsFd, AsRawFd};
 
@@ -15,6 +16,8 @@ pub struct MortiseObject {
     pub links: HashMap<String, BpfLink>,
     pub option: Option<ConnectOption>,
     pub maps: HashMap<u32, Vec<BpfMapHandle>>,
+    /// Free inner maps of each `SkArrayMap` of the connect option.
+    pub pools: Vec<InnerMapPool>,
 }
 
 pub struct MortiseOpenObject {
@@ -23,6 +26,7 @@ pub struct MortiseOpenObject {
 
 pub struct MortiseManagedObject<T: MortiseObjectState> {
     pub path: String,
+    pub provenance: ObjectProvenance,
     pub object: T,
 }
 
@@ -55,9 +59,11 @@ impl MortiseManagedObject<MortiseOpenObject> {
             links: HashMap::default(),
             option,
             maps: HashMap::default(),
+            pools: Vec::new(),
         };
         let obj = MortiseManagedObject {
             path: self.path,
+            provenance: self.provenance,
             object: obj,
         };
         Ok(obj)
@@ -159,6 +165,15 @@ impl MortiseManagedObject<MortiseObject> {
         self.object.maps.insert(flow_id, sk_array_maps);
     }
 
+    /// Give the inner maps of a flow back to the pools.
+    pub fn release_sk_array_maps(&mut self, flow_id: u32) {
+        if let Some(maps) = self.object.maps.remove(&flow_id) {
+            for (pool, map) in self.object.pools.iter_mut().zip(maps) {
+                pool.give(map);
+            }
+        }
+    }
+
     pub fn remove_sk_array_maps(&mut self, flow_id: u32) {
         let maps = self.object.maps.remove(&flow_id);
         if let Some(maps) = maps {
@@ -0,0 +1,100 @@
+use mortise_common::{
+    get_sk_addrs,
+    policy::{FlowAttrs, FlowPolicy, Policy, PolicyReport, PolicyRuleHits},
+    Result,
+};
+use std::os::unix::fs::MetadataExt;
+
+/// Policy file loaded by the manager, with the number of flows each rule applied to.
+pub struct PolicyEngine {
+    pub path: String,
+    policy: Policy,
+    hits: Vec<u64>,
+    default_hits: u64,
+    misses: u64,
+}
+
+impl PolicyEngine {
+    pub fn load(path: String) -> Result<Self> {
+        let policy = Policy::from_file(&path)?;
+        Ok(PolicyEngine {
+            path,
+            hits: vec![0; policy.rules.len()],
+            policy,
+            default_hits: 0,
+            misses: 0,
+        })
+    }
+
+    /// Policy of a flow, counting the hit of the applied rule.
+    pub fn resolve(&mut self, attrs: &FlowAttrs) -> Option<FlowPolicy> {
+        match self.policy.lookup(attrs) {
+            Some(idx) => {
+                self.hits[idx] += 1;
+                let rule = &self.policy.rules[idx];
+                Some(FlowPolicy {
+                    rule: Some(rule.name.clone()),
+                    action: rule.action.clone(),
+                })
+            }
+            None => match self.policy.default {
+                Some(ref action) => {
+                    self.default_hits += 1;
+                    Some(FlowPolicy {
+                        rule: None,
+                        action: action.clone(),
+                    })
+                }
+                None => {
+                    self.misses += 1;
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn report(&self) -> PolicyReport {
+        PolicyReport {
+            path: self.path.clone(),
+            rules: self
+                .policy
+                .rules
+                .iter()
+                .zip(self.hits.iter())
+                .map(|(rule, hits)| PolicyRuleHits {
+                    name: rule.name.clone(),
+                    hits: *hits,
+                })
+                .collect(),
+            default_hits: self.default_hits,
+            misses: self.misses,
+        }
+    }
+}
+
+/// Collect the attributes of a flow from its socket and `/proc`.
+pub fn flow_attrs(pid: i32, local_sk_fd: i32, tag: Option<String>) -> FlowAttrs {
+    let (local, peer) = get_sk_addrs(local_sk_fd).unwrap_or((None, None));
+    let process = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|comm| comm.trim_end().to_string());
+    let uid = std::fs::metadata(format!("/proc/{}", pid))
+        .ok()
+        .map(|metadata| metadata.uid());
+    // Only the unified hierarchy of cgroup v2 is considered
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .ok()
+        .and_then(|cgroups| {
+            cgroups
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(|path| path.to_string()))
+        });
+    FlowAttrs {
+        dst: peer.map(|addr| addr.ip()),
+        local_port: local.map(|addr| addr.port()),
+        process,
+        uid,
+        cgroup,
+        tag,
+    }
+}
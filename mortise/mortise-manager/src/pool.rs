@@ -0,0 +1,178 @@
+use libbpf_rs::{MapHandle as BpfMapHandle, MapType as BpfMapType};
+use mortise_common::{store::MapPoolStats, Result, SkArrayMap};
+
+pub const DEFAULT_MAP_POOL_SIZE: usize = 16;
+
+/// Keys deleted at most when a flow gives its map back.
+const MAX_CLEARED_KEYS: usize = 64;
+
+/// Inner maps of one `SkArrayMap` kept across flows, so that connecting a flow does
+/// not create a map.
+///
+/// Maps are cleared when a flow gives them back, unless they hold more than
+/// [`MAX_CLEARED_KEYS`] keys: those are closed, and a new map is created when the pool
+/// runs out. The pool keeps at most `capacity` free maps, the surplus is closed.
+pub struct InnerMapPool {
+    value_size: u32,
+    max_entries: u32,
+    capacity: usize,
+    free: Vec<BpfMapHandle>,
+    created: u64,
+    reused: u64,
+}
+
+impl InnerMapPool {
+    pub fn new(spec: &SkArrayMap, capacity: usize) -> Result<Self> {
+        let mut pool = InnerMapPool {
+            value_size: spec.value_size,
+            max_entries: spec.max_entries,
+            capacity,
+            free: Vec::with_capacity(capacity),
+            created: 0,
+            reused: 0,
+        };
+        for _ in 0..capacity {
+            let map = pool.create()?;
+            pool.free.push(map);
+        }
+        Ok(pool)
+    }
+
+    fn create(&mut self) -> Result<BpfMapHandle> {
+        let opts = libbpf_rs::libbpf_sys::bpf_map_create_opts {
+            sz: std::mem::size_of::<libbpf_rs::libbpf_sys::bpf_map_create_opts>()
+                as libbpf_rs::libbpf_sys::size_t,
+            map_flags: libbpf_rs::libbpf_sys::BPF_F_NO_PREALLOC,
+            btf_fd: 0,
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+            btf_vmlinux_value_type_id: 0,
+            inner_map_fd: 0,
+            map_extra: 0,
+            numa_node: 0,
+            map_ifindex: 0,
+        };
+        let map = BpfMapHandle::create::<String>(
+            BpfMapType::Hash,
+            None,
+            4,
+            self.value_size,
+            self.max_entries,
+            &opts,
+        )
+        .map_err(|e| {
+            tracing::error!(target: "manager:pool", "Failed to create map: {}", e);
+            e
+        })?;
+        self.created += 1;
+        Ok(map)
+    }
+
+    /// A cleared inner map, created if the pool is empty.
+    pub fn take(&mut self) -> Result<BpfMapHandle> {
+        match self.free.pop() {
+            Some(map) => {
+                self.reused += 1;
+                Ok(map)
+            }
+            None => self.create(),
+        }
+    }
+
+    /// Give back the inner map of a disconnected flow.
+    pub fn give(&mut self, map: BpfMapHandle) {
+        if self.free.len() >= self.capacity {
+            return;
+        }
+        // Deleting while iterating restarts the iteration of a hash map
+        let keys: Vec<Vec<u8>> = map.keys().take(MAX_CLEARED_KEYS + 1).collect();
+        if keys.len() > MAX_CLEARED_KEYS {
+            // Closing is cheaper for the disconnect than deleting up to max_entries keys
+            tracing::debug!(target: "manager:pool", "Close inner map of more than {} keys", MAX_CLEARED_KEYS);
+            return;
+        }
+        for key in keys.iter() {
+            if let Err(e) = map.delete(key) {
+                tracing::warn!(target: "manager:pool", "Drop inner map failing to clear: {}", e);
+                return;
+            }
+        }
+        self.free.push(map);
+    }
+
+    pub fn stats(&self) -> MapPoolStats {
+        MapPoolStats {
+            free: self.free.len() as u64,
+            created: self.created,
+            reused: self.reused,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InnerMapPool, MAX_CLEARED_KEYS};
+    use libbpf_rs::MapFlags;
+    use mortise_common::{MortiseError, SkArrayMap};
+
+    /// Pool of `capacity` maps, `None` if the test may not create bpf maps.
+    fn pool(capacity: usize) -> Option<InnerMapPool> {
+        let spec = SkArrayMap {
+            mim: "mim".to_string(),
+            name: None,
+            value_size: 8,
+            max_entries: 256,
+        };
+        match InnerMapPool::new(&spec, capacity) {
+            Ok(pool) => Some(pool),
+            Err(MortiseError::BpfError(libbpf_rs::Error::System(libc::EPERM))) => {
+                eprintln!("skipped: creating bpf maps needs CAP_BPF");
+                None
+            }
+            Err(e) => panic!("Failed to create the pool: {e}"),
+        }
+    }
+
+    fn fill(map: &libbpf_rs::MapHandle, keys: u32) {
+        for key in 0..keys {
+            map.update(&key.to_ne_bytes(), &[0; 8], MapFlags::ANY)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pool_take_give() {
+        let Some(mut pool) = pool(2) else {
+            return;
+        };
+        let first = pool.take().unwrap();
+        let second = pool.take().unwrap();
+        // Created once the pool is empty
+        let third = pool.take().unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.free, stats.created, stats.reused), (0, 3, 2));
+        fill(&first, 3);
+        pool.give(first);
+        pool.give(second);
+        // Beyond capacity
+        pool.give(third);
+        assert_eq!(pool.stats().free, 2);
+        for _ in 0..2 {
+            assert!(pool.take().unwrap().keys().next().is_none());
+        }
+        assert_eq!(pool.stats().reused, 4);
+    }
+
+    #[test]
+    fn test_pool_close_full_map() {
+        let Some(mut pool) = pool(1) else {
+            return;
+        };
+        let map = pool.take().unwrap();
+        fill(&map, MAX_CLEARED_KEYS as u32 + 1);
+        pool.give(map);
+        assert_eq!(pool.stats().free, 0);
+        pool.take().unwrap();
+        assert_eq!(pool.stats().created, 2);
+    }
+}
@@ -0,0 +1,107 @@
+use mortise_common::{
+    report::ReportEntry,
+    shm::{shm_report_os_id, ReportRing, RingPush},
+    MortiseError, Result,
+};
+use rustc_hash::FxHashMap as HashMap;
+use shared_memory::{Shmem, ShmemConf};
+use std::{
+    os::unix::net::UnixDatagram,
+    sync::{Arc, Mutex},
+};
+
+/// Shared-memory report rings of the strategy consumers, written by the RingBuf thread.
+pub type ShmReportSinks = Arc<Mutex<HashMap<String, ShmReportSink>>>;
+
+/// Producer side of the report ring of one strategy consumer.
+pub struct ShmReportSink {
+    pub name: String,
+    pub doorbell_path: String,
+    ring: ReportRing,
+    doorbell: UnixDatagram,
+    // The shared memory is unlinked when the sink is dropped, keep it after the ring.
+    _shmem: Shmem,
+}
+
+// The sink is moved to the RingBuf thread behind a mutex and is the only producer of
+// its ring, the consumer lives in another process.
+unsafe impl Send for ShmReportSink {}
+
+impl ShmReportSink {
+    pub fn create(name: String, capacity: u32, doorbell_path: String) -> Result<Self> {
+        let shmem = ShmemConf::new()
+            .size(ReportRing::mem_size(capacity))
+            .os_id(shm_report_os_id(&name))
+            .create()
+            .map_err(|e| MortiseError::ShmError(e.to_string()))?;
+        let ring = unsafe { ReportRing::init(shmem.as_ptr(), shmem.len(), capacity)? };
+        let doorbell = UnixDatagram::unbound()?;
+        doorbell.set_nonblocking(true)?;
+        Ok(ShmReportSink {
+            name,
+            doorbell_path,
+            ring,
+            doorbell,
+            _shmem: shmem,
+        })
+    }
+
+    pub fn push(&mut self, entry: &ReportEntry) {
+        match self.ring.push(entry) {
+            RingPush::Wakeup => {
+                // A full doorbell queue already holds a pending wakeup
+                if let Err(e) = self.doorbell.send_to(&[1], &self.doorbell_path) {
+                    if e.kind() != std::io::ErrorKind::WouldBlock {
+                        tracing::trace!(target: "manager:shm", "Fail to ring doorbell of {}: {}", self.name, e);
+                    }
+                }
+            }
+            RingPush::Queued => {}
+            RingPush::Overrun(overruns) => {
+                if overruns.is_power_of_two() {
+                    tracing::warn!(target: "manager:shm", "Report ring of {} overrun, {} entries dropped", self.name, overruns);
+                }
+            }
+        }
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.ring.overruns()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShmReportSink;
+    use mortise_common::{report::ReportEntry, shm::ShmReportReader};
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    #[test]
+    fn test_report_round_trip() {
+        let name = format!("test-{}", std::process::id());
+        let doorbell_path = std::env::temp_dir().join(format!("mortise-{name}.sock"));
+        let _ = std::fs::remove_file(&doorbell_path);
+        let doorbell = UnixDatagram::bind(&doorbell_path).unwrap();
+        let mut sink =
+            ShmReportSink::create(name.clone(), 2, doorbell_path.display().to_string()).unwrap();
+        let mut reader = ShmReportReader::open(&name, doorbell).unwrap();
+        let mut entry = ReportEntry::default();
+        for flow_id in 1..=3 {
+            entry.flow_id = flow_id;
+            sink.push(&entry);
+        }
+        reader.wait(Some(Duration::from_secs(1))).unwrap();
+        let mut flow_ids = Vec::new();
+        assert_eq!(reader.drain(|entry| flow_ids.push(entry.flow_id)), 2);
+        assert_eq!(flow_ids, [1, 2]);
+        assert_eq!(reader.overruns(), 1);
+        assert_eq!(sink.overruns(), 1);
+        // The ring is free again
+        entry.flow_id = 4;
+        sink.push(&entry);
+        reader.wait(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(reader.drain(|entry| assert_eq!(entry.flow_id, 4)), 1);
+        let _ = std::fs::remove_file(&doorbell_path);
+    }
+}
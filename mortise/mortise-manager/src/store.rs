@@ -0,0 +1,177 @@
+//! Directory of trusted objects the manager loads by name.
+//!
+//! The directory holds the object files and a `SHA256SUMS` manifest in the format of
+//! `sha256sum`. With a public key configured, the manifest must also be signed by the
+//! matching ed25519 key in `SHA256SUMS.sig`, e.g. with
+//! `openssl pkeyutl -sign -rawin -inkey key.pem -in SHA256SUMS -out SHA256SUMS.sig`.
+//! The manifest is read again on every load, so that objects can be added while the
+//! manager runs.
+
+use ed25519_dalek::{pkcs8::DecodePublicKey, Signature, VerifyingKey};
+use mortise_common::{store::ObjectProvenance, MortiseError, Result};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const MANIFEST_NAME: &str = "SHA256SUMS";
+pub const SIGNATURE_NAME: &str = "SHA256SUMS.sig";
+
+pub struct ObjectStore {
+    pub dir: PathBuf,
+    pubkey: Option<VerifyingKey>,
+}
+
+impl ObjectStore {
+    /// Open the store at `dir`, with the PEM encoded public key verifying the manifest.
+    pub fn open(dir: String, pubkey: Option<String>) -> Result<Self> {
+        let dir = Path::new(&dir).canonicalize()?;
+        let pubkey = match pubkey {
+            Some(path) => {
+                let pem = std::fs::read_to_string(&path)?;
+                let key = VerifyingKey::from_public_key_pem(&pem).map_err(|e| {
+                    MortiseError::UntrustedObject(
+                        path.clone(),
+                        format!("invalid public key: {}", e),
+                    )
+                })?;
+                Some(key)
+            }
+            None => None,
+        };
+        let store = ObjectStore { dir, pubkey };
+        // Fail early on a missing or badly signed manifest
+        store.manifest()?;
+        Ok(store)
+    }
+
+    fn untrusted(&self, name: &str, reason: String) -> MortiseError {
+        MortiseError::UntrustedObject(name.to_string(), reason)
+    }
+
+    /// Entries `(sha256, name)` of the manifest, once its signature is checked.
+    fn manifest(&self) -> Result<Vec<(String, String)>> {
+        let manifest = std::fs::read(self.dir.join(MANIFEST_NAME))?;
+        if let Some(ref key) = self.pubkey {
+            let sig = std::fs::read(self.dir.join(SIGNATURE_NAME))?;
+            let sig = Signature::from_slice(&sig)
+                .map_err(|e| self.untrusted(MANIFEST_NAME, format!("invalid signature: {}", e)))?;
+            key.verify_strict(&manifest, &sig)
+                .map_err(|_| self.untrusted(MANIFEST_NAME, "bad signature".to_string()))?;
+        }
+        let manifest = String::from_utf8(manifest)
+            .map_err(|_| self.untrusted(MANIFEST_NAME, "not a text file".to_string()))?;
+        Ok(manifest
+            .lines()
+            .filter_map(|line| {
+                let (hash, name) = line.split_once(char::is_whitespace)?;
+                // `sha256sum` marks binary mode with a '*' before the name
+                let name = name.trim_start().trim_start_matches('*');
+                Some((hash.to_ascii_lowercase(), name.to_string()))
+            })
+            .collect())
+    }
+
+    /// Path, content and hash of the object `name`, checked against the manifest.
+    pub fn resolve(&self, name: &str) -> Result<(String, Vec<u8>, String)> {
+        let path = Path::new(name);
+        if name.is_empty()
+            || path
+                .file_name()
+                .map(|n| n != path.as_os_str())
+                .unwrap_or(true)
+        {
+            return Err(self.untrusted(
+                name,
+                format!(
+                    "objects are loaded by file name from {}",
+                    self.dir.display()
+                ),
+            ));
+        }
+        let expected = self
+            .manifest()?
+            .into_iter()
+            .find(|(_, entry)| entry == name)
+            .map(|(hash, _)| hash)
+            .ok_or_else(|| self.untrusted(name, format!("not listed in {}", MANIFEST_NAME)))?;
+        let path = self.dir.join(name).display().to_string();
+        let (data, hash) = read_object(&path)?;
+        if hash != expected {
+            return Err(self.untrusted(
+                name,
+                format!("sha256 is {} but the manifest has {}", hash, expected),
+            ));
+        }
+        Ok((path, data, hash))
+    }
+}
+
+/// Content and hash of an object file. The object is opened from this content, so that
+/// the file can not be replaced between the check and the load.
+pub fn read_object(path: &str) -> Result<(Vec<u8>, String)> {
+    let data = std::fs::read(path)?;
+    let hash = hex::encode(Sha256::digest(&data));
+    Ok((data, hash))
+}
+
+/// Check that the file at `path`, given by a client of uid `uid`, belongs to it, since
+/// the manager opens it as root. Paths of root clients are not checked.
+pub fn check_owner(path: &str, uid: u32) -> Result<()> {
+    if uid == 0 {
+        return Ok(());
+    }
+    if std::fs::metadata(path)?.uid() != uid {
+        return Err(MortiseError::NotOwner(path.to_string(), uid));
+    }
+    Ok(())
+}
+
+pub fn provenance(sha256: String, loader_uid: u32) -> ObjectProvenance {
+    ObjectProvenance {
+        sha256,
+        loader_uid,
+        loaded_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_owner, read_object, ObjectStore, MANIFEST_NAME};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_store_resolve() {
+        let dir = std::env::temp_dir().join(format!("mortise-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let obj = dir.join("cca.bpf.o");
+        std::fs::write(&obj, b"object").unwrap();
+        let (_, hash) = read_object(obj.to_str().unwrap()).unwrap();
+        std::fs::write(dir.join(MANIFEST_NAME), format!("{}  cca.bpf.o\n", hash)).unwrap();
+        let store = ObjectStore::open(dir.display().to_string(), None).unwrap();
+
+        let (_, data, _) = store.resolve("cca.bpf.o").unwrap();
+        assert_eq!(data, b"object");
+        assert!(store.resolve("../cca.bpf.o").is_err());
+        assert!(store.resolve(obj.to_str().unwrap()).is_err());
+        std::fs::write(&obj, b"tampered").unwrap();
+        assert!(store.resolve("cca.bpf.o").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_owner() {
+        let path = std::env::temp_dir().join(format!("mortise-owner-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let owner = std::fs::metadata(&path).unwrap().uid();
+        let path_str = path.to_str().unwrap();
+        assert!(check_owner(path_str, owner).is_ok());
+        assert!(check_owner(path_str, 0).is_ok());
+        assert!(check_owner(path_str, owner + 1).is_err());
+        assert!(check_owner("/nonexistent/mortise", owner + 1).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+//! Checks of a bpf object against the layout the manager relies on, so that a wrong
+//! object fails at `Load` instead of at the first `Connect` of a flow.
+//!
+//! The names of the maps and the registry entry of the object are checked on the open
+//! object, the types and sizes of the maps once the object is loaded and before its
+//! struct_ops are attached.
+
+use crate::{MortiseManagedObject, MortiseObject, MortiseOpenObject};
+use clap::ValueEnum;
+use libbpf_rs::{
+    Map as BpfMap, MapFlags as BpfMapFlags, MapHandle as BpfMapHandle, MapType as BpfMapType,
+};
+use mortise_common::{
+    qoe::AppInfo, CongestionOpt, ConnectOption, ManagerOperation, MortiseError, Result,
+};
+use std::collections::HashMap;
+use std::path::Path;
+
+const SK_STG_MAP: &str = "sk_stg_map";
+const FLOW_ID_STG: &str = "flow_id_stg";
+const REPORT_RB: &str = "rb";
+
+/// Names of the maps the manager uses for an object loaded with `option`.
+fn required_maps(option: &Option<ConnectOption>) -> Vec<&str> {
+    let mut names = vec![SK_STG_MAP, REPORT_RB];
+    if let Some(option) = option {
+        if !option.sk_array_maps.is_empty() {
+            names.push(FLOW_ID_STG);
+            names.extend(option.sk_array_maps.iter().map(|map| map.mim.as_str()));
+        }
+    }
+    names
+}
+
+/// Inner map template of a map of maps, given in `.values`. It is the only inner map
+/// right after load, under key 0 which is never a flow id.
+pub fn inner_map_template(map: &BpfMap) -> Result<Option<BpfMapHandle>> {
+    let template = map
+        .lookup(&0u32.to_ne_bytes(), BpfMapFlags::ANY)?
+        .and_then(|id| id.try_into().ok().map(u32::from_ne_bytes));
+    Ok(template.map(BpfMapHandle::from_map_id).transpose()?)
+}
+
+fn invalid(path: &str, problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(MortiseError::InvalidObject(
+        path.to_string(),
+        problems.join("; "),
+    ))
+}
+
+/// Entry of the object at `path` in the registry of known CCAs, by its file name,
+/// e.g. `mortise_copa.bpf.o` for [`CongestionOpt::MortiseCopa`].
+fn registry_entry(path: &str) -> Option<CongestionOpt> {
+    let name = Path::new(path).file_name()?.to_str()?;
+    let name = name.split('.').next()?;
+    CongestionOpt::from_str(name, false).ok()
+}
+
+/// `(mim, value_size)` of the inner maps declared by `option`.
+fn sk_array_layout(option: &Option<ConnectOption>) -> Vec<(String, u32)> {
+    let mut layout: Vec<_> = option
+        .iter()
+        .flat_map(|option| option.sk_array_maps.iter())
+        .map(|map| (map.mim.clone(), map.value_size))
+        .collect();
+    layout.sort();
+    layout
+}
+
+fn check_open(
+    path: &str,
+    has_map: impl Fn(&str) -> bool,
+    option: &Option<ConnectOption>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for name in required_maps(option) {
+        if !has_map(name) {
+            let hint = match name {
+                SK_STG_MAP => {
+                    "declare `struct app_sk_stg sk_stg_map SEC(\".maps\")` from mortise_app.h"
+                }
+                FLOW_ID_STG => "declare a socket storage `flow_id_stg` of int values",
+                REPORT_RB => "declare the ring buffer `rb` of the reports, even if unused",
+                _ => "declare the outer map or fix `mim` in the connect option",
+            };
+            problems.push(format!("map {} not found, {}", name, hint));
+        }
+    }
+    if let Some(entry) = registry_entry(path) {
+        let expected = match entry.get_load_option() {
+            ManagerOperation::Load { option, .. } => option,
+            _ => None,
+        };
+        if sk_array_layout(option) != sk_array_layout(&expected) {
+            problems.push(format!(
+                "connect option declares the inner maps {:?} but the registry entry {} expects {:?}, load it with `CongestionOpt::get_load_option`",
+                sk_array_layout(option),
+                entry,
+                sk_array_layout(&expected)
+            ));
+        }
+    }
+    problems
+}
+
+pub fn validate_open(
+    obj: &MortiseManagedObject<MortiseOpenObject>,
+    option: &Option<ConnectOption>,
+) -> Result<()> {
+    let problems = check_open(&obj.path, |name| obj.map(name).is_some(), option);
+    invalid(&obj.path, problems)
+}
+
+/// Type and sizes of a map, which is all the checks of a loaded object look at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MapLayout {
+    map_type: BpfMapType,
+    key_size: u32,
+    value_size: u32,
+}
+
+impl MapLayout {
+    fn of(map: &BpfMapHandle) -> Self {
+        MapLayout {
+            map_type: map.map_type(),
+            key_size: map.key_size(),
+            value_size: map.value_size(),
+        }
+    }
+}
+
+/// Problems of the maps of a loaded object given their layout by name, and the
+/// layout of the inner map templates of its maps of maps.
+fn check_loaded(
+    path: &str,
+    maps: &HashMap<String, MapLayout>,
+    templates: &HashMap<String, MapLayout>,
+    option: &Option<ConnectOption>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    if !maps
+        .values()
+        .any(|map| map.map_type == BpfMapType::StructOps)
+    {
+        problems.push(
+            "no struct_ops map, declare the tcp_congestion_ops in SEC(\".struct_ops\")".to_string(),
+        );
+    }
+    if let Some(map) = maps.get(SK_STG_MAP) {
+        let size = std::mem::size_of::<AppInfo>() as u32;
+        if map.map_type != BpfMapType::SkStorage {
+            problems.push(format!(
+                "{} is a {:?} map instead of a socket storage",
+                SK_STG_MAP, map.map_type
+            ));
+        } else if map.value_size != size {
+            problems.push(format!(
+                "value of {} is {} bytes but mortise_common::qoe::AppInfo is {} bytes, keep struct app_info in sync",
+                SK_STG_MAP,
+                map.value_size,
+                size
+            ));
+        }
+    }
+    if let Some(map) = maps.get(FLOW_ID_STG) {
+        if map.map_type != BpfMapType::SkStorage || map.value_size != 4 {
+            problems.push(format!(
+                "{} should be a socket storage of 4 bytes values, found a {:?} map of {} bytes values",
+                FLOW_ID_STG,
+                map.map_type,
+                map.value_size
+            ));
+        }
+    }
+    if let Some(map) = maps.get(REPORT_RB) {
+        if map.map_type != BpfMapType::RingBuf {
+            problems.push(format!(
+                "{} is a {:?} map instead of a ring buffer",
+                REPORT_RB, map.map_type
+            ));
+        }
+    }
+    for sk_array_map in option.iter().flat_map(|option| option.sk_array_maps.iter()) {
+        let name = &sk_array_map.mim;
+        let map = match maps.get(name) {
+            Some(map) => map,
+            None => continue,
+        };
+        if !matches!(
+            map.map_type,
+            BpfMapType::HashOfMaps | BpfMapType::ArrayOfMaps
+        ) {
+            problems.push(format!(
+                "{} is a {:?} map instead of a map of maps",
+                name, map.map_type
+            ));
+            continue;
+        }
+        if map.key_size != 4 {
+            problems.push(format!(
+                "key of {} is {} bytes, flow ids are 4 bytes",
+                name, map.key_size
+            ));
+        }
+        match templates.get(name) {
+            Some(inner) if inner.value_size != sk_array_map.value_size => {
+                problems.push(format!(
+                    "inner map template of {} has {} bytes values but the connect option declares {}",
+                    name,
+                    inner.value_size,
+                    sk_array_map.value_size
+                ));
+            }
+            Some(_) => {}
+            None => {
+                tracing::warn!(target: "manager:validate", "Map {} of {} has no inner map template, value size not checked", name, path);
+            }
+        }
+    }
+    problems
+}
+
+/// Layout of the maps of a loaded object by name, and of the inner map templates of
+/// the maps of maps declared by `option`.
+type ObjectLayout = (HashMap<String, MapLayout>, HashMap<String, MapLayout>);
+
+fn object_layout(
+    obj: &MortiseManagedObject<MortiseObject>,
+    option: &Option<ConnectOption>,
+) -> Result<ObjectLayout> {
+    let maps: HashMap<String, MapLayout> = obj
+        .maps_iter()
+        .map(|map| (map.name().to_string(), MapLayout::of(map)))
+        .collect();
+    let mut templates = HashMap::new();
+    for sk_array_map in option.iter().flat_map(|option| option.sk_array_maps.iter()) {
+        let map = match obj.map(&sk_array_map.mim) {
+            Some(map) => map,
+            None => continue,
+        };
+        if !matches!(
+            map.map_type(),
+            BpfMapType::HashOfMaps | BpfMapType::ArrayOfMaps
+        ) {
+            continue;
+        }
+        if let Some(inner) = inner_map_template(map)? {
+            templates.insert(sk_array_map.mim.clone(), MapLayout::of(&inner));
+        }
+    }
+    Ok((maps, templates))
+}
+
+pub fn validate_loaded(obj: &MortiseManagedObject<MortiseObject>) -> Result<()> {
+    let option = obj.connect_option();
+    let (maps, templates) = object_layout(obj, &option)?;
+    let problems = check_loaded(&obj.path, &maps, &templates, &option);
+    invalid(&obj.path, problems)
+}
+
+/// Why an object of layout `new` can not take over the flows of an object of layout
+/// `old` attached by the struct_ops maps `links`, or the names of the socket storages
+/// to migrate.
+fn check_upgrade_layout(
+    links: &[&str],
+    option: &Option<ConnectOption>,
+    old: &ObjectLayout,
+    new: &ObjectLayout,
+) -> std::result::Result<Vec<String>, String> {
+    let ((old_maps, old_templates), (new_maps, new_templates)) = (old, new);
+    for name in links {
+        match new_maps.get(*name) {
+            Some(map) if map.map_type == BpfMapType::StructOps => {}
+            _ => return Err(format!("struct_ops map {} not found", name)),
+        }
+    }
+    for sk_array_map in option.iter().flat_map(|option| option.sk_array_maps.iter()) {
+        let name = &sk_array_map.mim;
+        let old_map = old_maps
+            .get(name)
+            .ok_or_else(|| format!("map {} not found in the loaded object", name))?;
+        let new_map = new_maps
+            .get(name)
+            .ok_or_else(|| format!("map {} not found", name))?;
+        if new_map.map_type != old_map.map_type {
+            return Err(format!(
+                "type of map {} changed from {:?} to {:?}",
+                name, old_map.map_type, new_map.map_type
+            ));
+        }
+        if new_map.key_size != old_map.key_size {
+            return Err(format!(
+                "key size of map {} changed from {} to {}",
+                name, old_map.key_size, new_map.key_size
+            ));
+        }
+        // The inner maps of the flows are moved as they are into the new outer map
+        let new_inner = new_templates
+            .get(name)
+            .ok_or_else(|| format!("map {} has no inner map template", name))?;
+        let sizes = (new_inner.key_size, new_inner.value_size);
+        let old_sizes = match old_templates.get(name) {
+            Some(old_inner) => (old_inner.key_size, old_inner.value_size),
+            None => (4, sk_array_map.value_size),
+        };
+        if sizes != old_sizes {
+            return Err(format!(
+                "inner maps of {} changed from {:?} to {:?} bytes of key and value",
+                name, old_sizes, sizes
+            ));
+        }
+    }
+    let mut sk_stg_maps = Vec::new();
+    for (name, old_map) in old_maps
+        .iter()
+        .filter(|(_, map)| map.map_type == BpfMapType::SkStorage)
+    {
+        let new_map = new_maps
+            .get(name)
+            .ok_or_else(|| format!("socket storage {} not found", name))?;
+        if new_map.map_type != BpfMapType::SkStorage {
+            return Err(format!("map {} is no longer a socket storage", name));
+        }
+        if new_map.value_size != old_map.value_size {
+            return Err(format!(
+                "value size of socket storage {} changed from {} to {}",
+                name, old_map.value_size, new_map.value_size
+            ));
+        }
+        sk_stg_maps.push(name.clone());
+    }
+    sk_stg_maps.sort();
+    Ok(sk_stg_maps)
+}
+
+/// Check that `new_obj` can take over the flows of `old_obj`, and returns the names of
+/// the socket storage maps to migrate.
+pub fn check_upgrade(
+    obj_id: u32,
+    old_obj: &MortiseManagedObject<MortiseObject>,
+    new_obj: &MortiseManagedObject<MortiseObject>,
+) -> Result<Vec<String>> {
+    let option = old_obj.connect_option();
+    let links: Vec<&str> = old_obj.links().keys().map(String::as_str).collect();
+    let old = object_layout(old_obj, &option)?;
+    let new = object_layout(new_obj, &option)?;
+    check_upgrade_layout(&links, &option, &old, &new)
+        .map_err(|reason| MortiseError::IncompatibleUpgrade(obj_id, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_loaded, check_open, check_upgrade_layout, MapLayout, ObjectLayout};
+    use libbpf_rs::MapType as BpfMapType;
+    use mortise_common::{ConnectOption, SkArrayMap};
+    use std::collections::HashMap;
+
+    fn option() -> Option<ConnectOption> {
+        let sk_array_maps = [("mim_rtt", 16), ("mim_increase", 8)]
+            .into_iter()
+            .map(|(mim, value_size)| SkArrayMap {
+                name: None,
+                mim: mim.to_string(),
+                value_size,
+                max_entries: 100000,
+            })
+            .collect();
+        Some(ConnectOption { sk_array_maps })
+    }
+
+    fn layout(map_type: BpfMapType, key_size: u32, value_size: u32) -> MapLayout {
+        MapLayout {
+            map_type,
+            key_size,
+            value_size,
+        }
+    }
+
+    #[test]
+    fn test_check_open() {
+        let all = ["sk_stg_map", "rb", "flow_id_stg", "mim_rtt", "mim_increase"];
+        let path = "/tmp/mortise_copa.bpf.o";
+        assert!(check_open(path, |name| all.contains(&name), &option()).is_empty());
+        for missing in all {
+            let problems = check_open(
+                path,
+                |name| name != missing && all.contains(&name),
+                &option(),
+            );
+            assert_eq!(problems.len(), 1, "{:?}", problems);
+            assert!(problems[0].starts_with(&format!("map {} not found", missing)));
+        }
+        // Without inner maps only the app info and the reports are used
+        assert!(check_open(
+            "/tmp/other.bpf.o",
+            |name| name == "sk_stg_map" || name == "rb",
+            &None
+        )
+        .is_empty());
+        // The registry knows the inner maps of mortise_copa
+        let problems = check_open(path, |_| true, &None);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("registry entry mortise_copa"));
+        let mut wrong = option();
+        wrong.as_mut().unwrap().sk_array_maps[0].value_size = 8;
+        assert_eq!(check_open(path, |_| true, &wrong).len(), 1);
+    }
+
+    /// Layout of a loaded mortise_copa.
+    fn copa() -> ObjectLayout {
+        let maps = [
+            ("copa", layout(BpfMapType::StructOps, 4, 256)),
+            ("sk_stg_map", layout(BpfMapType::SkStorage, 4, 16)),
+            ("flow_id_stg", layout(BpfMapType::SkStorage, 4, 4)),
+            ("rb", layout(BpfMapType::RingBuf, 0, 0)),
+            ("mim_rtt", layout(BpfMapType::HashOfMaps, 4, 4)),
+            ("mim_increase", layout(BpfMapType::HashOfMaps, 4, 4)),
+        ]
+        .into_iter()
+        .map(|(name, layout)| (name.to_string(), layout))
+        .collect();
+        let templates = [
+            ("mim_rtt", layout(BpfMapType::Array, 4, 16)),
+            ("mim_increase", layout(BpfMapType::Array, 4, 8)),
+        ]
+        .into_iter()
+        .map(|(name, layout)| (name.to_string(), layout))
+        .collect();
+        (maps, templates)
+    }
+
+    #[test]
+    fn test_check_loaded() {
+        let (mut maps, mut templates) = copa();
+        let path = "mortise_copa.bpf.o";
+        assert!(check_loaded(path, &maps, &templates, &option()).is_empty());
+
+        templates.get_mut("mim_rtt").unwrap().value_size = 8;
+        let problems = check_loaded(path, &maps, &templates, &option());
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("inner map template of mim_rtt has 8 bytes"));
+        templates.get_mut("mim_rtt").unwrap().value_size = 16;
+
+        maps.get_mut("sk_stg_map").unwrap().value_size = 8;
+        maps.get_mut("flow_id_stg").unwrap().value_size = 8;
+        let problems = check_loaded(path, &maps, &templates, &option());
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("value of sk_stg_map is 8 bytes"));
+        assert!(problems[1].starts_with("flow_id_stg should be a socket storage"));
+
+        maps.remove("copa");
+        maps.get_mut("sk_stg_map").unwrap().value_size = 16;
+        maps.get_mut("flow_id_stg").unwrap().value_size = 4;
+        let problems = check_loaded(path, &maps, &templates, &option());
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("no struct_ops map"));
+    }
+
+    #[test]
+    fn test_check_upgrade() {
+        let links = ["copa"];
+        let old = copa();
+        let check = |new: &ObjectLayout| check_upgrade_layout(&links, &option(), &old, new);
+        assert_eq!(check(&copa()).unwrap(), ["flow_id_stg", "sk_stg_map"]);
+
+        let mut new = copa();
+        new.0.remove("copa");
+        assert_eq!(check(&new).unwrap_err(), "struct_ops map copa not found");
+
+        let mut new = copa();
+        new.0.get_mut("mim_rtt").unwrap().key_size = 8;
+        assert!(check(&new)
+            .unwrap_err()
+            .starts_with("key size of map mim_rtt"));
+
+        let mut new = copa();
+        new.1.get_mut("mim_increase").unwrap().value_size = 16;
+        assert!(check(&new)
+            .unwrap_err()
+            .starts_with("inner maps of mim_increase changed from (4, 8) to (4, 16)"));
+
+        let mut new = copa();
+        new.1.remove("mim_rtt");
+        assert!(check(&new).unwrap_err().contains("no inner map template"));
+
+        let mut new = copa();
+        new.0.get_mut("sk_stg_map").unwrap().value_size = 8;
+        assert!(check(&new)
+            .unwrap_err()
+            .starts_with("value size of socket storage sk_stg_map"));
+
+        let mut new = copa();
+        new.0.remove("flow_id_stg");
+        assert_eq!(
+            check(&new).unwrap_err(),
+            "socket storage flow_id_stg not found"
+        );
+
+        // Without templates, the old inner maps have the size of the connect option
+        let old = (copa().0, HashMap::new());
+        assert!(check_upgrade_layout(&links, &option(), &old, &copa()).is_ok());
+    }
+}
@@ -76,17 +76,15 @@ async fn main() {
     for i in 0..total_num {
         let port = opts.base_port + i;
         let result_dir = &config.common.result_directory;
-        let (mode, _default_app_info_args, default_app_info_display) =
+        let (mode, default_app_info_args, default_app_info_display) =
             match config.sender.default_app_info {
                 Some(ref app_info) => {
                     let i = i % (config.sender.mode_cnt * default_app_info_cnt);
                     let mode = i / default_app_info_cnt;
                     let mode = mode_to_str(mode);
-                    let default_app_info_args = format!(
-                        "--default-app-info {}",
-                        app_info[i as usize % default_app_info_cnt as usize]
-                    );
-                    let default_app_info_display = format!("-{}", app_info[i as usize]);
+                    let app_info = app_info[(i % default_app_info_cnt) as usize];
+                    let default_app_info_args = format!("--default-app-info {app_info}");
+                    let default_app_info_display = format!("-{app_info}");
                     (mode, default_app_info_args, default_app_info_display)
                 }
                 None => (
@@ -102,7 +100,7 @@ async fn main() {
         let _app = &config.sender.app;
         let tcp_ca = &config.sender.tcp_ca;
         let out_log = format!("{result_dir}/{tcp_ca}-{port}-{i}.log");
-        let stat_csv = format!("{result_dir}/{port}.csv");
+        let stat_csv = format!("{result_dir}/{port}{default_app_info_display}.csv");
         let pcap_args = if config.sender.pcap {
             format!(
                 "--pcap {result_dir}/pcap/{mode}{default_app_info_display}-{tcp_ca}-{port}.pcap"
@@ -110,7 +108,7 @@ async fn main() {
         } else {
             "".to_string()
         };
-        let cmd = format!("mm-delay {delay} mm-loss downlink {loss} mm-link {trace_file} {trace_file} --uplink-queue={queue} --downlink-queue={queue} --uplink-queue-args={buffer_size} --downlink-queue-args={buffer_size} --downlink-log={out_log} -- ./scripts/run_sender.sh {pcap_args} -e {port} -o {stat_csv} -C {tcp_ca}");
+        let cmd = format!("mm-delay {delay} mm-loss downlink {loss} mm-link {trace_file} {trace_file} --uplink-queue={queue} --downlink-queue={queue} --uplink-queue-args={buffer_size} --downlink-queue-args={buffer_size} --downlink-log={out_log} -- ./scripts/run_sender.sh {pcap_args} -e {port} -o {stat_csv} -C {tcp_ca} {default_app_info_args}");
         tasks.push(tokio::spawn(async move {
             let f = || async {
                 let output = Command::new("sh").arg("-c").arg(&cmd).output();
@@ -18,6 +18,8 @@ import socketserver
 
 socket_path = "/tmp/mortise-py.sock"
 server_address = "/tmp/mortise.sock"
+# `BINARY_VERSION` in mortise-common/src/codec.rs
+BINARY_VERSION = 1
 
 
 def add_callsite_info(logger, method_name, event_dict):
@@ -55,36 +57,75 @@ structlog.configure(
 logger = structlog.get_logger()
 
 
-def send_and_receive_message(sock, message_dict):
-    # Convert the message to bytes
-    message = json.dumps(message_dict)
-    message_bytes = message.encode("utf-8")
-
-    # Get the length of the message in bytes
-    message_length = len(message_bytes)
-
-    # Pack the length as a 32-bit unsigned integer
-    message_length_packed = struct.pack("!I", message_length)
-
-    # Send the length followed by the message
+def send_frame(sock, message_bytes):
+    # Pack the length as a 32-bit unsigned integer, followed by the message
+    message_length_packed = struct.pack("!I", len(message_bytes))
     sock.sendall(message_length_packed + message_bytes)
 
+
+def recv_frame(sock):
     # Receive a message in the same format
     data_length_packed = sock.recv(4)  # Receive 4 bytes for the length
     data_length = struct.unpack("!I", data_length_packed)[0]  # Unpack the length
 
     # Receive the data
-    data_bytes = sock.recv(data_length)  # Receive data of `data_length` length
+    return sock.recv(data_length)  # Receive data of `data_length` length
+
+
+def set_binary_encoding(sock):
+    """
+    Switch the connection to the binary encoding of manager, keep json if refused.
+    """
+    message = {"Manager": {"SetEncoding": {"encoding": "Binary", "version": BINARY_VERSION}}}
+    send_frame(sock, json.dumps(message).encode("utf-8"))
+    resp = json.loads(recv_frame(sock).decode("utf-8"))
+    if "Ok" not in resp:
+        logger.warning(f"Manager refused binary encoding: {resp}")
+        return False
+    return True
+
 
-    # Deserialize the data
-    data_dict = json.loads(data_bytes.decode("utf-8"))
-    # logger.info(f"Received: {data_dict}")
+def encode_map_update(req_id, message_dict):
+    """
+    Binary `ManagerRequest` of a `SkStgMapUpdate`, see `test_binary_map_update_layout`
+    in mortise-common/src/codec.rs.
+    """
+    flow = message_dict["Flow"]
+    update = flow["op"]["SkStgMapUpdate"]
+    map_name = update["map_name"].encode("utf-8")
+    val = bytes(update["val"])
+    # id, `Operation::Flow`, flow_id, `FlowOperation::SkStgMapUpdate`
+    return (
+        struct.pack("<QIII", req_id, 1, flow["flow_id"], 0)
+        + struct.pack("<I", len(map_name))
+        + map_name
+        + struct.pack("<I", len(val))
+        + val
+        + struct.pack("<Q", update["flag"])
+    )
+
+
+def send_and_receive_message(sock, message_dict, req_id=None):
+    if req_id is None:
+        send_frame(sock, json.dumps(message_dict).encode("utf-8"))
+        # Deserialize the data
+        data_dict = json.loads(recv_frame(sock).decode("utf-8"))
+        # logger.info(f"Received: {data_dict}")
+    else:
+        send_frame(sock, encode_map_update(req_id, message_dict))
+        # id followed by the tag of the result
+        data_bytes = recv_frame(sock)
+        if data_bytes[8] != 0:
+            logger.warning(f"Failed to update: {data_bytes[13:].decode('utf-8')}")
 
 
 def worker(tx, rx, flow_id, sock):
     logger = structlog.get_logger()
     tx.close()
     flow_controller = FlowCtrl("FILE")
+    # Trade-off updates are sent for every decision, use the compact encoding
+    binary = set_binary_encoding(sock)
+    req_id = 0
     while True:
         try:
             message = rx.recv()
@@ -94,7 +135,8 @@ def worker(tx, rx, flow_id, sock):
             flow_controller.add_data(message)
             message_dict = flow_controller.process()
             if message_dict != None:
-                send_and_receive_message(sock, message_dict)
+                req_id += 1
+                send_and_receive_message(sock, message_dict, req_id if binary else None)
         except EOFError:
             logger.info(f"Exit process for {flow_id}.")
             sock.close()
@@ -148,6 +190,34 @@ flow_locks = {}
 # mortise_server_connected = False
 
 
+def stop_flow(flow_id):
+    """
+    Stop the worker of a flow, returns whether it had one.
+    """
+    if flow_id not in flow_locks:
+        return False
+    lock = flow_locks[flow_id]
+    with lock:
+        if flow_id in flow_manager:
+            chan = flow_manager[flow_id]
+            chan.close()
+            del flow_manager[flow_id]
+        del flow_locks[flow_id]
+    return True
+
+
+def start_flow(flow_id):
+    mtx = multiprocessing.Lock()
+    with mtx:
+        flow_locks[flow_id] = mtx
+        tx, rx = multiprocessing.Pipe()
+        sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        sock.connect(server_address)
+        p = Process(target=worker, args=(tx, rx, flow_id, sock))
+        p.start()
+        flow_manager[flow_id] = tx
+
+
 class ThreadedUnixStreamHandler(socketserver.BaseRequestHandler):
     def handle(self):
         conn = self.request
@@ -164,38 +234,21 @@ class ThreadedUnixStreamHandler(socketserver.BaseRequestHandler):
                     ctrl_data = json.loads(data.decode("utf-8"))
                     logger.debug(ctrl_data)
                     if "Disconnect" in ctrl_data:
-                        flow_disconnect = ctrl_data["Disconnect"]
-                        flow_id = flow_disconnect["flow_id"]
-                        if flow_id in flow_locks:
-                            lock = flow_locks[flow_id]
-                            with lock:
-                                if flow_id in flow_manager:
-                                    chan = flow_manager[flow_id]
-                                    chan.close()
-                                    del flow_manager[flow_id]
-                                del flow_locks[flow_id]
+                        stop_flow(ctrl_data["Disconnect"]["flow_id"])
                     elif "Connect" in ctrl_data:
                         flow_id = ctrl_data["Connect"]["flow_id"]
                         # print(flow_id, type(flow_id))
-                        if flow_id in flow_locks:
-                            lock = flow_locks[flow_id]
-                            with lock:
-                                if flow_id in flow_manager:
-                                    chan = flow_manager[flow_id]
-                                    chan.close()
-                                    del flow_manager[flow_id]
-                                del flow_locks[flow_id]
+                        if stop_flow(flow_id):
                             logger.info(f"Disconnected existing flow {flow_id}")
-                        mtx = multiprocessing.Lock()
-                        with mtx:
-                            flow_locks[flow_id] = mtx
-                            tx, rx = multiprocessing.Pipe()
-                            sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
-                            sock.connect(server_address)
-                            p = Process(target=worker, args=(tx, rx, flow_id, sock))
-                            p.start()
-                            flow_manager[flow_id] = tx
-                        # print(f"Flow {flow_id} disconnected.")
+                        # Flows given another strategy by their policy are not ours
+                        if ctrl_data["Connect"].get("strategy") in (None, "report"):
+                            start_flow(flow_id)
+                    elif "Migrate" in ctrl_data:
+                        # The history of the previous CCA is meaningless for the new one
+                        flow_id = ctrl_data["Migrate"]["flow_id"]
+                        stop_flow(flow_id)
+                        if ctrl_data["Migrate"]["obj_id"] != 0:
+                            start_flow(flow_id)
                 else:
                     # if not mortise_server_connected:
                     #     sock.connect(server_address)
@@ -3,17 +3,27 @@ use bytes::Bytes;
 use clap::Parser;
 use csv::Writer;
 use futures::SinkExt;
-use mortise_common::get_clock_ns;
+use mortise_common::{get_clock_ns, get_tcp_info_total_retrans, Encoding};
 use speedy::{Readable as _, Writable};
+use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use tokio::io::AsyncBufReadExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::mpsc;
 use tokio::time::{Duration, Instant};
 use tokio_stream::StreamExt;
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use traffic::bulk::{BulkFrame, BulkInterval};
+use traffic::dispatch::{DispatchPolicy, Dispatcher};
+use traffic::mux::MuxFrame;
+use traffic::video::{Abr, AbrPolicy, Playback, DEFAULT_LADDER_KBPS};
 use traffic::*;
 
 const MAHIMAHI_IP: &str = "MAHIMAHI_BASE";
@@ -33,12 +43,78 @@ struct CommandArgs {
     connect: Option<String>,
     #[clap(long, short)]
     port: Option<u16>,
-    #[clap(short = 'C', long, value_enum, default_value_t = transport::CongestionOpt::Cubic)]
-    congestion: transport::CongestionOpt,
+    /// CCA of each connection, the list is repeated if shorter than the connections
+    #[clap(short = 'C', long, value_enum, value_delimiter = ',', default_values_t = [transport::CongestionOpt::Cubic])]
+    congestion: Vec<transport::CongestionOpt>,
     #[clap(short, long, default_value = "result.csv")]
     output: String,
     #[clap(short, long)]
     workload: Option<PathBuf>,
+    /// Replay the workload as HTTP/1.1 requests, to a server started with `--http`
+    #[clap(long)]
+    http: bool,
+    /// Let the server answer the requests on multiplexed streams
+    #[clap(long, conflicts_with = "http")]
+    mux: bool,
+    /// Parallel connections sharing the workload
+    #[clap(long, default_value_t = 1)]
+    connections: u16,
+    /// Choice of the connection of each request
+    #[clap(long, value_enum, default_value_t = DispatchPolicy::RoundRobin)]
+    dispatch: DispatchPolicy,
+    /// Requests smaller than this many bytes are small for the size-based dispatch
+    #[clap(long, default_value_t = 100_000)]
+    small_size: u32,
+    /// Application run over one connection instead of the workload
+    #[clap(long, value_enum, conflicts_with_all = ["http", "mux", "workload"])]
+    app: Option<AppOpt>,
+    /// ABR of the video application
+    #[clap(long, value_enum, default_value_t = AbrPolicy::Buffer)]
+    abr: AbrPolicy,
+    /// Bitrate ladder of the video in kbps, in increasing order
+    #[clap(long, value_delimiter = ',', default_values_t = DEFAULT_LADDER_KBPS)]
+    ladder: Vec<u64>,
+    /// Playback time of a video segment
+    #[clap(long, default_value_t = 4000)]
+    segment_ms: u64,
+    /// Segments of the video
+    #[clap(long, default_value_t = 50)]
+    segments: u32,
+    /// Video the player buffers at most ahead of the playback
+    #[clap(long, default_value_t = 30_000)]
+    max_buffer_ms: u64,
+    /// Frames per second of the webrtc application
+    #[clap(long, default_value_t = 30)]
+    fps: u64,
+    /// Target bitrate of the frames in kbps, which the server lowers on late frames
+    #[clap(long, default_value_t = 2500)]
+    bitrate_kbps: u64,
+    /// Frames arriving later than this after being sent are dropped
+    #[clap(long, default_value_t = 120)]
+    deadline_ms: u64,
+    /// Seconds the server sends frames or bulk data for
+    #[clap(long, default_value_t = 30)]
+    duration: u64,
+    /// Upload the payloads of the workload, with the CCA set on the client's sockets
+    /// which are enrolled with manager
+    #[clap(long, conflicts_with_all = ["http", "mux", "app"])]
+    upload: bool,
+    /// Encoding of the messages to manager when uploading, binary if manager supports it
+    #[clap(long, value_enum, default_value_t = Encoding::Json)]
+    ipc_encoding: Encoding,
+    /// Kernel CCA of the uploading connections while the manager is unavailable
+    #[clap(long, default_value = "cubic")]
+    fallback_cca: String,
+    /// Fixed value of the tunable of the CCA for the whole connections, instead of the
+    /// one chosen by the policy of manager. HTTP connections have no handshake to carry it
+    #[clap(long, conflicts_with = "http")]
+    default_app_info: Option<u64>,
+    /// Bytes the server sends in bulk, instead of sending for the duration
+    #[clap(long)]
+    bytes: Option<u64>,
+    /// Interval of the bulk reports
+    #[clap(long, default_value_t = 1000)]
+    interval_ms: u64,
 }
 
 fn parse_sk_addr(opts: &CommandArgs) -> Result<(SocketAddr, Option<SocketAddr>)> {
@@ -93,8 +169,157 @@ async fn main() -> Result<()> {
 
     let (server_addr, client_addr) = parse_sk_addr(&opts)?;
 
+    match opts.app {
+        Some(AppOpt::Video) => {
+            let stream = connect(server_addr, client_addr, 0).await?;
+            return transmit_video(stream, &opts, cancel_token).await;
+        }
+        Some(AppOpt::WebRTC) => {
+            let stream = connect(server_addr, client_addr, 0).await?;
+            return transmit_frames(stream, &opts, cancel_token).await;
+        }
+        Some(AppOpt::Bulk) => {
+            let stream = connect(server_addr, client_addr, 0).await?;
+            return transmit_bulk(stream, &opts, cancel_token).await;
+        }
+        None => {}
+    }
+
+    // The uploading connections are the ones tuned by manager
+    let manager = if opts.upload {
+        let (manager_tx, manager_rx) = mpsc::channel(32);
+        let link_config = ManagerLinkConfig::new(opts.ipc_encoding, opts.fallback_cca.clone());
+        let link_stats = Arc::new(ManagerLinkStats::default());
+        let inner_link_stats = link_stats.clone();
+        let alive = CancellationToken::new();
+        let inner_alive = alive.clone();
+        let handle = tokio::spawn(async move {
+            manager_ipc(manager_rx, link_config, inner_link_stats).await;
+            inner_alive.cancel();
+        });
+        let link = ManagerLink {
+            tx: manager_tx,
+            alive,
+        };
+        Some((link, handle, link_stats))
+    } else {
+        None
+    };
+
+    let connections = opts.connections.max(1);
+    let mut dispatcher = Dispatcher::new(opts.dispatch, connections as usize, opts.small_size);
+    let dispatched = CancellationToken::new();
+    let mut senders = Vec::new();
+    let mut tasks = Vec::new();
+    for conn in 0..connections {
+        let stream = connect(server_addr, client_addr, conn).await?;
+        let (req_tx, req_rx) = mpsc::unbounded_channel();
+        senders.push(req_tx);
+        let client_conn = ClientConn {
+            conn: conn as u32,
+            stream,
+            congestion: opts.congestion[conn as usize % opts.congestion.len()].clone(),
+            req_rx,
+            outstanding: dispatcher.outstanding(conn as usize),
+            manager: manager.as_ref().map(|(link, _, _)| link.clone()),
+            default_app_info: opts.default_app_info,
+        };
+        let task = if opts.http {
+            tokio::spawn(transmit_http(
+                client_conn,
+                server_addr.to_string(),
+                dispatched.clone(),
+                cancel_token.clone(),
+            ))
+        } else {
+            tokio::spawn(transmit(
+                client_conn,
+                opts.mux,
+                opts.upload,
+                dispatched.clone(),
+                cancel_token.clone(),
+            ))
+        };
+        tasks.push(task);
+    }
+
+    let traces = match opts.workload {
+        Some(ref path) => read_trace_file(path).await?,
+        None => Vec::new(),
+    };
+    tracing::info!(target: "sender", "Wait for Ctrl-C or transmission finished...");
+    let mut now = Instant::now();
+    for (id, (gap, size, hint)) in (1_u32..).zip(traces) {
+        tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => {
+                tracing::info!(target: "sender:send", "Cancel signal received!");
+                break;
+            }
+            _ = tokio::time::sleep_until(now + gap) => {}
+        }
+        now += gap;
+        let conn = dispatcher.dispatch(size);
+        if senders[conn].send((id, size, hint)).is_err() {
+            dispatcher.outstanding(conn).fetch_sub(1, Ordering::Relaxed);
+            tracing::warn!(target: "sender:send", "Connection {} is closed, drop request {}", conn, id);
+        }
+    }
+    drop(senders);
+    dispatched.cancel();
+
+    let mut stats = Vec::new();
+    let mut total_retrans = 0;
+    for (conn, task) in tasks.into_iter().enumerate() {
+        match task.await? {
+            Ok((conn_stats, conn_retrans)) => {
+                stats.extend(conn_stats);
+                total_retrans += conn_retrans;
+            }
+            Err(e) => tracing::error!(target: "sender", "Connection {} failed: {:?}", conn, e),
+        }
+    }
+    if let Some((link, handle, link_stats)) = manager {
+        if !link.alive.is_cancelled() {
+            link.tx.send((0, ClientIpcOperation::Shutdown)).await?;
+        }
+        handle.await?;
+        tracing::info!(target: "sender", "Manager link closed; {}", link_stats);
+    }
+    stats.sort_by_key(|stat| stat.id);
+    // Summary row with the retransmissions of all connections
+    stats.push(ClientRequestStats {
+        id: 0,
+        size: 0,
+        client_send: 0,
+        server_recv: total_retrans,
+        client_first_byte: 0,
+        client_recv: 0,
+        conn: 0,
+    });
+
+    // output stats to csv
+    tracing::info!(
+        "All requests finished. Statistics are saved to {}",
+        opts.output
+    );
+    write_stat_csv(opts.output, stats).await?;
+    Ok(())
+}
+
+/// Open connection `conn` of the client. Only the first one is bound to the egress port,
+/// the others get an ephemeral port of the same address: the executor gives consecutive
+/// egress ports to its clients, so the ports after ours belong to the next clients.
+async fn connect(
+    server_addr: SocketAddr,
+    client_addr: Option<SocketAddr>,
+    conn: u16,
+) -> Result<TcpStream> {
     let stream = match client_addr {
-        Some(addr) => {
+        Some(mut addr) => {
+            if conn > 0 {
+                addr.set_port(0);
+            }
             let socket = TcpSocket::new_v4()?;
             socket.bind(addr)?;
             socket.connect(server_addr).await?
@@ -102,85 +327,171 @@ async fn main() -> Result<()> {
         None => TcpStream::connect(server_addr).await?,
     };
     stream.set_nodelay(true)?;
+    Ok(stream)
+}
 
-    tracing::info!(target: "sender", "Wait for Ctrl-C or transmission finished...");
-    transmit(opts, stream, cancel_token).await?;
+/// A connection of the client, sending the requests `(id, size, hint)` dispatched to it.
+struct ClientConn {
+    conn: u32,
+    stream: TcpStream,
+    congestion: transport::CongestionOpt,
+    req_rx: mpsc::UnboundedReceiver<(u32, u32, Option<RequestHint>)>,
+    outstanding: Arc<AtomicU64>,
+    /// Link to manager, which enrolls the connection when uploading.
+    manager: Option<ManagerLink>,
+    default_app_info: Option<u64>,
+}
 
-    Ok(())
+#[derive(Clone)]
+struct ManagerLink {
+    tx: mpsc::Sender<(u64, ClientIpcOperation)>,
+    /// Cancelled once `manager_ipc` is gone.
+    alive: CancellationToken,
 }
 
+/// Send the requests of a connection with the framed protocol, until all requests are
+/// dispatched and answered. Returns the stats of the requests and the retransmissions
+/// of the connection.
+///
+/// When uploading, the client sends the payloads, so its socket gets the CCA and is
+/// enrolled with manager for the lifetime of the connection.
 async fn transmit(
-    opts: CommandArgs,
-    stream: TcpStream,
+    client_conn: ClientConn,
+    mux: bool,
+    upload: bool,
+    dispatched: CancellationToken,
     cancel_token: CancellationToken,
-) -> Result<()> {
-    let (rh, wh) = stream.into_split();
-    let mut writer = LengthDelimitedCodec::builder()
-        .length_field_type::<u32>()
-        .new_write(wh);
-    let mut reader = LengthDelimitedCodec::builder()
-        .length_field_type::<u32>()
-        .new_read(rh);
-    let connect_opt = ClientRequestOpt::Connect(ClientConnectOpt {
-        congestion: opts.congestion,
-    });
-    let b: Bytes = { connect_opt.write_to_vec().map(Into::into).unwrap() };
-    writer.send(b).await.unwrap();
-    match reader.next().await {
-        Some(Ok(b)) => {
-            let _ = ServerResponse::read_from_buffer(&b)?;
-        }
-        Some(Err(e)) => {
-            return Err(anyhow::anyhow!("Error reading response: {:?}", e));
+) -> Result<(Vec<ClientRequestStats>, u64)> {
+    let ClientConn {
+        conn,
+        stream,
+        congestion,
+        mut req_rx,
+        outstanding,
+        manager,
+        default_app_info,
+    } = client_conn;
+    let fd = stream.as_raw_fd();
+    let flow_id = conn as u64 + 1;
+    let flow = match manager {
+        Some(link) if upload => {
+            let (obj_id, tcp_ca) = congestion.get_tcp_ca();
+            socket2::SockRef::from(&stream).set_tcp_congestion(tcp_ca)?;
+            let socket = FlowSocket {
+                fd,
+                obj_id,
+                tcp_ca,
+                default_app_info,
+            };
+            connect_flow(&link.tx, &link.alive, flow_id, socket, false).await?;
+            Some((link, obj_id))
         }
-        None => {
-            return Err(anyhow::anyhow!("No response received"));
-        }
-    }
-    let traces = match opts.workload {
-        Some(ref path) => read_trace_file(path).await?,
-        None => Vec::new(),
+        _ => None,
     };
-    let mut cnt = traces.len();
-    let writer_cancel_token = cancel_token.clone();
+    let base_retrans = get_tcp_info_total_retrans(fd)?;
+    let app = if upload {
+        ConnectApp::Upload
+    } else {
+        ConnectApp::Requests(RequestsOpt {
+            mux,
+            default_app_info,
+        })
+    };
+    let connect_opt = ClientConnectOpt { congestion, app };
+    let (mut writer, mut reader) = handshake(stream, connect_opt).await?;
     let w = tokio::spawn(async move {
-        let mut now = Instant::now();
-        let mut id = 1_u32;
-        for (gap, size) in traces {
-            tokio::select! {
-                biased;
-                _ = writer_cancel_token.cancelled() => {
-                    tracing::info!(target: "sender:send", "Cancel signal received!");
-                    break;
-                }
-                _ = tokio::time::sleep_until(now + gap) => {}
-            }
+        while let Some((id, size, hint)) = req_rx.recv().await {
             let client_send = get_clock_ns() as u64;
-            now += gap;
-            let req = ClientRequestOpt::Request(ClientRequest {
+            let req = ClientRequest {
                 size,
                 client_send,
                 id,
-            });
-            id += 1;
+                hint,
+            };
+            let req = if upload {
+                ClientRequestOpt::Upload(req)
+            } else {
+                ClientRequestOpt::Request(req)
+            };
             let b: Bytes = req.write_to_vec().map(Into::into).unwrap();
-            writer.send(b).await.unwrap();
+            if let Err(e) = writer.send(b).await {
+                tracing::error!(target: "sender:send", "Error sending request: {:?}", e);
+                break;
+            }
+            if !upload {
+                continue;
+            }
+            if let Err(e) = body::write_zeros(writer.get_mut(), size as u64).await {
+                tracing::error!(target: "sender:send", "Error sending payload: {:?}", e);
+                break;
+            }
         }
         writer
     });
 
     let mut stats = Vec::new();
-    while cnt > 0 {
+    // Streams of the multiplexed responses, with the arrival of their head and the bytes left
+    let mut streams: HashMap<u32, (ServerResponse, u64, u64)> = HashMap::new();
+    while !(dispatched.is_cancelled() && outstanding.load(Ordering::Relaxed) == 0) {
         let resp = tokio::select! {
             biased;
             _ = cancel_token.cancelled() => {
                 tracing::info!(target: "sender:recv", "Cancel signal received!");
                 break;
             }
+            _ = dispatched.cancelled(), if !dispatched.is_cancelled() => continue,
             resp = reader.next() => resp
         };
-        let client_recv = get_clock_ns() as u64;
+        let client_first_byte = get_clock_ns() as u64;
         match resp {
+            Some(Ok(frame)) if mux => {
+                let (id, len) = match MuxFrame::read_from_buffer(&frame) {
+                    Ok(MuxFrame::Head(resp)) => {
+                        let (id, size) = (resp.id, resp.size);
+                        streams.insert(id, (resp, client_first_byte, size));
+                        (id, 0)
+                    }
+                    Ok(MuxFrame::Data { id, len }) => {
+                        let body = tokio::select! {
+                            biased;
+                            _ = cancel_token.cancelled() => {
+                                tracing::info!(target: "sender:recv", "Cancel signal received!");
+                                break;
+                            }
+                            body = body::skip_body(&mut reader, len as u64) => body
+                        };
+                        if let Err(e) = body {
+                            tracing::error!(target: "sender:recv", "Error reading response body: {:?}", e);
+                            cancel_token.cancel();
+                            break;
+                        }
+                        (id, len as u64)
+                    }
+                    Err(e) => {
+                        tracing::error!(target: "sender:recv", "Error parsing response: {:?}", e);
+                        cancel_token.cancel();
+                        break;
+                    }
+                };
+                let Some(stream) = streams.get_mut(&id) else {
+                    tracing::warn!(target: "sender:recv", "Data of unknown stream {}", id);
+                    continue;
+                };
+                stream.2 = stream.2.saturating_sub(len);
+                if stream.2 == 0 {
+                    let (resp, client_first_byte, _) = streams.remove(&id).unwrap();
+                    stats.push(ClientRequestStats {
+                        id: resp.id,
+                        size: resp.size as u32,
+                        client_send: resp.client_send,
+                        server_recv: resp.server_recv,
+                        client_first_byte,
+                        client_recv: get_clock_ns() as u64,
+                        conn,
+                    });
+                    outstanding.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
             Some(Ok(resp)) => {
                 let resp = match ServerResponse::read_from_buffer(&resp) {
                     Ok(resp) => resp,
@@ -190,14 +501,29 @@ async fn transmit(
                         break;
                     }
                 };
+                let body = tokio::select! {
+                    biased;
+                    _ = cancel_token.cancelled() => {
+                        tracing::info!(target: "sender:recv", "Cancel signal received!");
+                        break;
+                    }
+                    body = body::skip_body(&mut reader, resp.size) => body
+                };
+                if let Err(e) = body {
+                    tracing::error!(target: "sender:recv", "Error reading response body: {:?}", e);
+                    cancel_token.cancel();
+                    break;
+                }
                 stats.push(ClientRequestStats {
                     id: resp.id,
-                    size: resp.data.len() as u32,
+                    size: resp.size as u32,
                     client_send: resp.client_send,
                     server_recv: resp.server_recv,
-                    client_recv,
+                    client_first_byte,
+                    client_recv: get_clock_ns() as u64,
+                    conn,
                 });
-                cnt -= 1;
+                outstanding.fetch_sub(1, Ordering::Relaxed);
             }
             Some(Err(err)) => {
                 tracing::error!(target: "sender:recv", "Error reading response: {:?}", err);
@@ -215,29 +541,450 @@ async fn transmit(
     // Post process: disconnect with server
     // Collect summarized stats from server
     let mut writer = w.await?;
+    let mut total_retrans = finish(&mut writer, &mut reader).await?;
+    if let Some((link, obj_id)) = flow {
+        total_retrans = (get_tcp_info_total_retrans(fd)? - base_retrans) as u64;
+        // The socket is still open, so that manager can release it
+        disconnect_flow(&link.tx, &link.alive, flow_id, obj_id).await?;
+    }
+    Ok((stats, total_retrans))
+}
+
+type FramedWriter = FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>;
+type FramedReader = FramedRead<OwnedReadHalf, LengthDelimitedCodec>;
+
+/// Split `stream` into its framed halves and connect to the server with `connect_opt`.
+async fn handshake(
+    stream: TcpStream,
+    connect_opt: ClientConnectOpt,
+) -> Result<(FramedWriter, FramedReader)> {
+    let (rh, wh) = stream.into_split();
+    let mut writer = LengthDelimitedCodec::builder()
+        .length_field_type::<u32>()
+        .new_write(wh);
+    let mut reader = LengthDelimitedCodec::builder()
+        .length_field_type::<u32>()
+        .new_read(rh);
+    let connect_opt = ClientRequestOpt::Connect(connect_opt);
+    let b: Bytes = { connect_opt.write_to_vec().map(Into::into).unwrap() };
+    writer.send(b).await?;
+    match reader.next().await {
+        Some(Ok(b)) => {
+            let resp = ServerResponse::read_from_buffer(&b)?;
+            body::skip_body(&mut reader, resp.size).await?;
+        }
+        Some(Err(e)) => {
+            return Err(anyhow::anyhow!("Error reading response: {:?}", e));
+        }
+        None => {
+            return Err(anyhow::anyhow!("No response received"));
+        }
+    }
+    Ok((writer, reader))
+}
+
+/// Disconnect from the server, which answers with the retransmissions of the connection.
+async fn finish(writer: &mut FramedWriter, reader: &mut FramedReader) -> Result<u64> {
     let req = ClientRequestOpt::Finish;
     let b: Bytes = req.write_to_vec().map(Into::into)?;
     writer.send(b).await?;
-    let resp = reader.next().await.unwrap()?;
+    let resp = reader
+        .next()
+        .await
+        .ok_or(anyhow::anyhow!("No response received"))??;
     let resp = ServerResponse::read_from_buffer(&resp)?;
-    stats.push(ClientRequestStats {
-        id: resp.id,
-        size: 0,
-        client_send: resp.client_send,
-        server_recv: resp.server_recv,
-        client_recv: 0,
-    });
+    Ok(resp.server_recv)
+}
 
-    // output stats to csv
+/// Stats of a downloaded video segment.
+struct SegmentStats {
+    id: u32,
+    rung: usize,
+    bitrate_kbps: u64,
+    size: u64,
+    client_send: u64,
+    client_recv: u64,
+    download: Duration,
+    buffer: Duration,
+    stall: Duration,
+    qoe: f64,
+}
+
+/// Stream a video of `opts.segments` segments, each at the rung picked by the ABR, and
+/// acknowledge each segment so that the server reports its QoE to manager.
+async fn transmit_video(
+    stream: TcpStream,
+    opts: &CommandArgs,
+    cancel_token: CancellationToken,
+) -> Result<()> {
+    if opts.ladder.is_empty() || opts.ladder.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(anyhow::anyhow!("The ladder must be increasing bitrates"));
+    }
+    let segment = Duration::from_millis(opts.segment_ms);
+    let max_buffer = Duration::from_millis(opts.max_buffer_ms);
+    let connect_opt = ClientConnectOpt {
+        congestion: opts.congestion[0].clone(),
+        app: ConnectApp::Video(VideoOpt {
+            segment,
+            default_app_info: opts.default_app_info,
+        }),
+    };
+    let (mut writer, mut reader) = handshake(stream, connect_opt).await?;
+    let mut abr = Abr::new(opts.abr, opts.ladder.clone(), max_buffer);
+    let mut playback = Playback::new(segment);
+    let mut stats = Vec::new();
+    let start = Instant::now();
+    tracing::info!(target: "sender", "Wait for Ctrl-C or video finished...");
+    for id in 1..=opts.segments {
+        // The player waits for room in its buffer
+        let idle = playback.idle(max_buffer);
+        if !idle.is_zero() {
+            tokio::select! {
+                biased;
+                _ = cancel_token.cancelled() => break,
+                _ = tokio::time::sleep(idle) => {}
+            }
+            playback.drain(idle);
+        }
+        let rung = abr.choose(playback.buffer());
+        let bitrate_kbps = abr.bitrate_kbps(rung);
+        let size = bitrate_kbps * opts.segment_ms / 8;
+        let client_send = get_clock_ns() as u64;
+        let sent_at = Instant::now();
+        let req = ClientRequestOpt::Request(ClientRequest {
+            id,
+            size: size as u32,
+            client_send,
+            hint: None,
+        });
+        writer.send(req.write_to_vec().map(Into::into)?).await?;
+        let resp = tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => break,
+            resp = reader.next() => resp,
+        };
+        let resp = match resp {
+            Some(resp) => ServerResponse::read_from_buffer(&resp?)?,
+            None => {
+                tracing::warn!(target: "sender:recv", "No more response!");
+                break;
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => break,
+            body = body::skip_body(&mut reader, resp.size) => body?,
+        }
+        let client_recv = get_clock_ns() as u64;
+        let download = sent_at.elapsed();
+        let ack = ClientRequestOpt::Ack(ChunkAck {
+            id: id as u64,
+            server_send: resp.server_recv,
+            client_recv,
+            size,
+        });
+        writer.send(ack.write_to_vec().map(Into::into)?).await?;
+        abr.record(size, download);
+        let played = playback.on_segment(bitrate_kbps, download, start.elapsed());
+        stats.push(SegmentStats {
+            id,
+            rung,
+            bitrate_kbps,
+            size,
+            client_send,
+            client_recv,
+            download,
+            buffer: played.buffer,
+            stall: played.stall,
+            qoe: played.qoe,
+        });
+    }
+    let total_retrans = finish(&mut writer, &mut reader).await?;
+
+    let segments = stats.len().max(1) as f64;
     tracing::info!(
-        "All requests finished. Statistics are saved to {}",
-        opts.output
+        target: "sender",
+        "Video finished: startup {:?}, {} stalls of {:?} in total, {} switches, mean bitrate {:.0} kbps, mean QoE {:.3}, {} retransmissions",
+        playback.startup.unwrap_or_default(),
+        playback.stalls,
+        playback.total_stall,
+        playback.switches,
+        stats.iter().map(|s| s.bitrate_kbps as f64).sum::<f64>() / segments,
+        stats.iter().map(|s| s.qoe).sum::<f64>() / segments,
+        total_retrans
     );
-    write_stat_csv(opts.output, stats).await?;
+    tracing::info!("Statistics are saved to {}", opts.output);
+    write_video_csv(&opts.output, stats)?;
     Ok(())
 }
 
-async fn read_trace_file<P: AsRef<Path>>(path: P) -> Result<Vec<(Duration, u32)>> {
+/// Stats of a received real-time frame.
+struct FrameStats {
+    id: u64,
+    size: u64,
+    server_send: u64,
+    client_recv: u64,
+    delay: Duration,
+    late: bool,
+}
+
+/// Receive the real-time frames of the server and acknowledge each of them, counting the
+/// frames later than the deadline as dropped.
+async fn transmit_frames(
+    stream: TcpStream,
+    opts: &CommandArgs,
+    cancel_token: CancellationToken,
+) -> Result<()> {
+    let deadline = Duration::from_millis(opts.deadline_ms);
+    let duration = Duration::from_secs(opts.duration);
+    let connect_opt = ClientConnectOpt {
+        congestion: opts.congestion[0].clone(),
+        app: ConnectApp::WebRTC(WebRTCOpt {
+            frame_interval: Duration::from_micros(1_000_000 / opts.fps.max(1)),
+            bitrate_kbps: opts.bitrate_kbps,
+            deadline,
+            duration,
+            default_app_info: opts.default_app_info,
+        }),
+    };
+    let (mut writer, mut reader) = handshake(stream, connect_opt).await?;
+    let mut stats = Vec::new();
+    tracing::info!(target: "sender", "Wait for Ctrl-C or frames finished...");
+    loop {
+        let frame = tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => break,
+            frame = reader.next() => frame,
+        };
+        let chunk = match frame {
+            Some(frame) => DataChunk::read_from_buffer(&frame?)?,
+            None => break,
+        };
+        tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => break,
+            body = body::skip_body(&mut reader, chunk.size) => body?,
+        }
+        let client_recv = get_clock_ns() as u64;
+        let ack = ChunkAck {
+            id: chunk.id,
+            server_send: chunk.server_send,
+            client_recv,
+            size: chunk.size,
+        };
+        writer.send(ack.write_to_vec().map(Into::into)?).await?;
+        let delay = Duration::from_nanos(client_recv.saturating_sub(chunk.server_send));
+        stats.push(FrameStats {
+            id: chunk.id,
+            size: chunk.size,
+            server_send: chunk.server_send,
+            client_recv,
+            delay,
+            late: delay > deadline,
+        });
+    }
+    // The server stops reading acks once the client closes
+    writer.close().await?;
+
+    let frames = stats.len().max(1);
+    let dropped = stats.iter().filter(|stat| stat.late).count();
+    let mut delays: Vec<Duration> = stats.iter().map(|stat| stat.delay).collect();
+    delays.sort();
+    tracing::info!(
+        target: "sender",
+        "Frames finished: {} received, {} dropped, mean delay {:?}, p95 delay {:?}, mean bitrate {:.0} kbps",
+        stats.len(),
+        dropped,
+        delays.iter().sum::<Duration>() / frames as u32,
+        delays.get(delays.len() * 95 / 100).copied().unwrap_or_default(),
+        stats.iter().map(|stat| stat.size).sum::<u64>() as f64 * 8.0 / 1000.0 / duration.as_secs_f64().max(1e-3)
+    );
+    tracing::info!("Statistics are saved to {}", opts.output);
+    write_frame_csv(&opts.output, stats)?;
+    Ok(())
+}
+
+/// Report of an interval of a bulk test, with the bytes the client received meanwhile.
+struct IntervalStats {
+    interval: BulkInterval,
+    bytes_received: u64,
+}
+
+/// Receive bulk data until the server is done, logging its reports like iperf3.
+async fn transmit_bulk(
+    stream: TcpStream,
+    opts: &CommandArgs,
+    cancel_token: CancellationToken,
+) -> Result<()> {
+    let connect_opt = ClientConnectOpt {
+        congestion: opts.congestion[0].clone(),
+        app: ConnectApp::Bulk(BulkOpt {
+            duration: Duration::from_secs(opts.duration),
+            bytes: opts.bytes.unwrap_or(0),
+            interval: Duration::from_millis(opts.interval_ms),
+            default_app_info: opts.default_app_info,
+        }),
+    };
+    let (mut writer, mut reader) = handshake(stream, connect_opt).await?;
+    let mut stats = Vec::new();
+    let mut received = 0;
+    tracing::info!(target: "sender", "Wait for Ctrl-C or bulk transfer finished...");
+    loop {
+        let frame = tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => break,
+            frame = reader.next() => frame,
+        };
+        let frame = match frame {
+            Some(frame) => BulkFrame::read_from_buffer(&frame?)?,
+            None => break,
+        };
+        match frame {
+            BulkFrame::Data { size } => {
+                tokio::select! {
+                    biased;
+                    _ = cancel_token.cancelled() => break,
+                    body = body::skip_body(&mut reader, size) => body?,
+                }
+                received += size;
+            }
+            BulkFrame::Report(interval) => {
+                let length = interval.length.as_secs_f64().max(1e-6);
+                tracing::info!(
+                    target: "sender",
+                    "[{:7.2}-{:7.2} s] goodput {:8.2} Mbps, received {:8.2} Mbps, rtt {:6.1} ms, cwnd {:5}, retrans {:4}, notsent {}",
+                    (interval.end - interval.length).as_secs_f64(),
+                    interval.end.as_secs_f64(),
+                    interval.goodput_kbps() / 1000.0,
+                    received as f64 * 8.0 / 1_000_000.0 / length,
+                    interval.rtt_us as f64 / 1000.0,
+                    interval.cwnd,
+                    interval.retrans,
+                    interval.notsent
+                );
+                stats.push(IntervalStats {
+                    interval,
+                    bytes_received: std::mem::take(&mut received),
+                });
+            }
+        }
+    }
+    // The server waits for the client to close
+    writer.close().await?;
+
+    let elapsed = stats
+        .last()
+        .map(|stat| stat.interval.end.as_secs_f64())
+        .unwrap_or_default()
+        .max(1e-6);
+    let acked: u64 = stats.iter().map(|stat| stat.interval.bytes_acked).sum();
+    let retrans: u32 = stats.iter().map(|stat| stat.interval.retrans).sum();
+    tracing::info!(
+        target: "sender",
+        "Bulk finished: {} bytes in {:.2} s, goodput {:.2} Mbps, {} retransmissions",
+        acked,
+        elapsed,
+        acked as f64 * 8.0 / 1_000_000.0 / elapsed,
+        retrans
+    );
+    tracing::info!("Statistics are saved to {}", opts.output);
+    write_bulk_csv(&opts.output, stats)?;
+    Ok(())
+}
+
+/// Send the requests of a connection as HTTP/1.1 requests, see [`transmit`].
+async fn transmit_http(
+    client_conn: ClientConn,
+    host: String,
+    dispatched: CancellationToken,
+    cancel_token: CancellationToken,
+) -> Result<(Vec<ClientRequestStats>, u64)> {
+    let ClientConn {
+        conn,
+        stream,
+        mut req_rx,
+        outstanding,
+        ..
+    } = client_conn;
+    let (rh, mut wh) = stream.into_split();
+    let mut reader = http::HttpResponseReader::new(rh);
+    let w = tokio::spawn(async move {
+        while let Some((id, size, hint)) = req_rx.recv().await {
+            let client_send = get_clock_ns() as u64;
+            let req = http::bytes_request(&host, id, size, client_send, hint.as_ref());
+            if let Err(e) = wh.write_all(&req).await {
+                tracing::error!(target: "sender:send", "Error sending request: {:?}", e);
+                break;
+            }
+        }
+        // Requests are answered in order, the server closes once the last is answered
+        let _ = wh.shutdown().await;
+    });
+
+    let mut stats = Vec::new();
+    let mut total_retrans = 0;
+    while !(dispatched.is_cancelled() && outstanding.load(Ordering::Relaxed) == 0) {
+        let resp = tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => {
+                tracing::info!(target: "sender:recv", "Cancel signal received!");
+                break;
+            }
+            _ = dispatched.cancelled(), if !dispatched.is_cancelled() => continue,
+            resp = reader.next_head() => resp
+        };
+        let client_first_byte = get_clock_ns() as u64;
+        match resp {
+            Ok(Some(resp)) => {
+                let body = tokio::select! {
+                    biased;
+                    _ = cancel_token.cancelled() => {
+                        tracing::info!(target: "sender:recv", "Cancel signal received!");
+                        break;
+                    }
+                    body = reader.skip_body(resp.content_length) => body
+                };
+                if let Err(e) = body {
+                    tracing::error!(target: "sender:recv", "Error reading response body: {:?}", e);
+                    cancel_token.cancel();
+                    break;
+                }
+                if !resp.status.is_success() {
+                    tracing::warn!(target: "sender:recv", "Request {:?} failed: {}", resp.request_id, resp.status);
+                }
+                total_retrans = resp.total_retrans.unwrap_or(total_retrans);
+                stats.push(ClientRequestStats {
+                    id: resp.request_id.unwrap_or_default(),
+                    size: resp.content_length as u32,
+                    client_send: resp.client_send.unwrap_or_default(),
+                    server_recv: resp.server_recv.unwrap_or_default(),
+                    client_first_byte,
+                    client_recv: get_clock_ns() as u64,
+                    conn,
+                });
+                outstanding.fetch_sub(1, Ordering::Relaxed);
+            }
+            Ok(None) => {
+                tracing::warn!(target: "sender:recv", "No more response!");
+                cancel_token.cancel();
+                break;
+            }
+            Err(err) => {
+                tracing::error!(target: "sender:recv", "Error reading response: {:?}", err);
+                cancel_token.cancel();
+                break;
+            }
+        }
+    }
+    w.await?;
+    Ok((stats, total_retrans))
+}
+
+/// Read the requests of a workload, one `<gap_ms> <size>` per line, optionally followed
+/// by the hints `<deadline_ms> [<priority> [<rate_cap_kbps>]]` of the request.
+async fn read_trace_file<P: AsRef<Path>>(
+    path: P,
+) -> Result<Vec<(Duration, u32, Option<RequestHint>)>> {
     let mut traces = Vec::new();
     let file = tokio::fs::File::open(path).await?;
     let mut lines = tokio::io::BufReader::new(file).lines();
@@ -252,7 +999,23 @@ async fn read_trace_file<P: AsRef<Path>>(path: P) -> Result<Vec<(Duration, u32)>
             .next()
             .and_then(|s| s.parse::<u32>().ok())
             .ok_or(anyhow::anyhow!("Invalid trace file format"))?;
-        traces.push((time, size));
+        let hints = parts
+            .map(|s| s.parse::<u64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| anyhow::anyhow!("Invalid trace file format"))?;
+        let hint = match hints[..] {
+            [] => None,
+            [deadline_ms, ref rest @ ..] if rest.len() <= 2 => Some(
+                RequestHint::new(
+                    deadline_ms,
+                    rest.first().copied().unwrap_or(0),
+                    rest.get(1).copied().unwrap_or(0),
+                )
+                .map_err(|e| anyhow::anyhow!("Invalid trace file format: {}", e))?,
+            ),
+            _ => return Err(anyhow::anyhow!("Invalid trace file format")),
+        };
+        traces.push((time, size, hint));
     }
     Ok(traces)
 }
@@ -266,6 +1029,9 @@ async fn write_stat_csv(path: String, stats: Vec<ClientRequestStats>) -> Result<
         "server_recv",
         "client_recv",
         "rct",
+        "client_first_byte",
+        "ttfb",
+        "conn",
     ])?;
     for stat in stats {
         wtr.write_record(&[
@@ -275,6 +1041,105 @@ async fn write_stat_csv(path: String, stats: Vec<ClientRequestStats>) -> Result<
             stat.server_recv.to_string(),
             stat.client_recv.to_string(),
             ((stat.client_recv - stat.client_send) / 1_000_000).to_string(),
+            stat.client_first_byte.to_string(),
+            ((stat.client_first_byte - stat.client_send) / 1_000_000).to_string(),
+            stat.conn.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_video_csv(path: &str, stats: Vec<SegmentStats>) -> Result<()> {
+    let mut wtr = Writer::from_path(path)?;
+    wtr.write_record([
+        "id",
+        "rung",
+        "bitrate_kbps",
+        "size",
+        "client_send",
+        "client_recv",
+        "download_ms",
+        "buffer_ms",
+        "stall_ms",
+        "qoe",
+    ])?;
+    for stat in stats {
+        wtr.write_record(&[
+            stat.id.to_string(),
+            stat.rung.to_string(),
+            stat.bitrate_kbps.to_string(),
+            stat.size.to_string(),
+            stat.client_send.to_string(),
+            stat.client_recv.to_string(),
+            stat.download.as_millis().to_string(),
+            stat.buffer.as_millis().to_string(),
+            stat.stall.as_millis().to_string(),
+            format!("{:.3}", stat.qoe),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_frame_csv(path: &str, stats: Vec<FrameStats>) -> Result<()> {
+    let mut wtr = Writer::from_path(path)?;
+    wtr.write_record([
+        "id",
+        "size",
+        "server_send",
+        "client_recv",
+        "delay_ms",
+        "dropped",
+    ])?;
+    for stat in stats {
+        wtr.write_record(&[
+            stat.id.to_string(),
+            stat.size.to_string(),
+            stat.server_send.to_string(),
+            stat.client_recv.to_string(),
+            format!("{:.3}", stat.delay.as_secs_f64() * 1000.0),
+            (stat.late as u8).to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_bulk_csv(path: &str, stats: Vec<IntervalStats>) -> Result<()> {
+    let mut wtr = Writer::from_path(path)?;
+    wtr.write_record([
+        "start_ms",
+        "end_ms",
+        "bytes_sent",
+        "bytes_acked",
+        "bytes_received",
+        "goodput_kbps",
+        "delivery_rate_kbps",
+        "rtt_us",
+        "rttvar_us",
+        "cwnd",
+        "retrans",
+        "notsent",
+    ])?;
+    for IntervalStats {
+        interval,
+        bytes_received,
+    } in stats
+    {
+        wtr.write_record(&[
+            (interval.end - interval.length).as_millis().to_string(),
+            interval.end.as_millis().to_string(),
+            interval.bytes_sent.to_string(),
+            interval.bytes_acked.to_string(),
+            bytes_received.to_string(),
+            format!("{:.1}", interval.goodput_kbps()),
+            (interval.delivery_rate * 8 / 1000).to_string(),
+            interval.rtt_us.to_string(),
+            interval.rttvar_us.to_string(),
+            interval.cwnd.to_string(),
+            interval.retrans.to_string(),
+            interval.notsent.to_string(),
         ])?;
     }
     wtr.flush()?;
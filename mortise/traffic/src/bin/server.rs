@@ -1,18 +1,30 @@
 use anyhow::Result;
 use clap::Parser;
-use futures::{SinkExt, StreamExt};
-use mortise_common::{get_clock_ns, get_tcp_info_total_retrans, MortiseError};
+use futures::{FutureExt, SinkExt, StreamExt};
+use libbpf_rs::MapFlags as BpfMapFlags;
+use mortise_common::qoe::FrameQoE;
+use mortise_common::sync::AtomicRawCell;
+use mortise_common::{get_clock_ns, get_tcp_info_total_retrans, CongestionOpt, Encoding};
 use socket2::{Domain, Socket, Type};
 use speedy::{Readable, Writable};
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio::task::JoinSet;
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use traffic::body::{BodySource, CHUNK_SIZE};
+use traffic::bulk::{BulkFrame, BulkSampler};
+use traffic::mux::{MuxChunk, MuxFrame, MuxQueue, MuxScheduler};
+use traffic::realtime::{one_way_delay, FrameRateController};
+use traffic::transport::{run_rate_controller, TransportOpt};
 use traffic::*;
 
 #[derive(Debug, Parser, Clone)]
@@ -24,6 +36,42 @@ struct CommandArgs {
     bind: Option<String>,
     #[clap(long, short, default_value_t = 5000)]
     port: u16,
+    /// Encoding of the messages to manager, binary if manager supports it
+    #[clap(long, value_enum, default_value_t = Encoding::Json)]
+    ipc_encoding: Encoding,
+    /// Serve connections before manager sets up their flows, which start with the
+    /// default parameters of the CCA
+    #[clap(long)]
+    async_connect: bool,
+    /// Kernel CCA of the connections while the manager is unavailable
+    #[clap(long, default_value = "cubic")]
+    fallback_cca: String,
+    /// Speak HTTP/1.1 instead of the framed protocol
+    #[clap(long)]
+    http: bool,
+    /// CCA of the HTTP connections, whose clients can not choose it
+    #[clap(short = 'C', long, value_enum, default_value_t = CongestionOpt::Cubic, requires = "http")]
+    congestion: CongestionOpt,
+    /// Directory served over HTTP besides `/bytes/{size}`
+    #[clap(long, requires = "http")]
+    root: Option<PathBuf>,
+    /// File sent with sendfile as the body of the responses instead of zeros
+    #[clap(long)]
+    body_file: Option<PathBuf>,
+    /// Order of the chunks of the responses to multiplexing clients
+    #[clap(long, value_enum, default_value_t = MuxScheduler::Fifo)]
+    scheduler: MuxScheduler,
+}
+
+/// Options of the served connections.
+#[derive(Clone)]
+struct ServeOpt {
+    async_connect: bool,
+    /// CCA of the HTTP connections
+    congestion: CongestionOpt,
+    root: Option<Arc<PathBuf>>,
+    source: BodySource,
+    scheduler: MuxScheduler,
 }
 
 #[tokio::main]
@@ -42,10 +90,13 @@ async fn main() -> Result<()> {
         ctrlc_cancel_token.cancel();
     })
     .expect("Error setting Ctrl-C handler");
+    let link_config = ManagerLinkConfig::new(opts.ipc_encoding, opts.fallback_cca.clone());
+    let link_stats = Arc::new(ManagerLinkStats::default());
+    let inner_link_stats = link_stats.clone();
     let manager_ipc_alive = CancellationToken::new();
     let manager_ipc_avlie_inner = manager_ipc_alive.clone();
     let manager_handle = tokio::spawn(async move {
-        manager_ipc(manager_rx).await;
+        manager_ipc(manager_rx, link_config, inner_link_stats).await;
         manager_ipc_avlie_inner.cancel();
     });
 
@@ -76,6 +127,19 @@ async fn main() -> Result<()> {
     let listener: TcpListener = TcpListener::from_std(listener.into()).unwrap();
     tracing::info!(target: "server", "Listens on {}. Wait for ctrl-c to shutdown...", addr);
     let client_conn_id = AtomicU64::new(1);
+    let serve_opt = ServeOpt {
+        async_connect: opts.async_connect,
+        congestion: opts.congestion.clone(),
+        root: match opts.root {
+            Some(ref root) => Some(Arc::new(root.canonicalize()?)),
+            None => None,
+        },
+        source: match opts.body_file {
+            Some(ref path) => BodySource::open(path)?,
+            None => BodySource::Zeros,
+        },
+        scheduler: opts.scheduler,
+    };
 
     let mut set = JoinSet::new();
     loop {
@@ -96,8 +160,17 @@ async fn main() -> Result<()> {
                 let id = client_conn_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 let manager_tx = manager_tx.clone();
                 let alive_token = manager_ipc_alive.clone();
+                let serve_opt = serve_opt.clone();
+                if opts.http {
+                    set.spawn(async move {
+                        if let Err(e) = process_http(stream, manager_tx, alive_token, id, serve_opt).await {
+                            tracing::error!("Failed to process connection; error = {e}");
+                        }
+                    });
+                    continue;
+                }
                 set.spawn(async move {
-                    if let Err(e) = process(stream, manager_tx, alive_token, id).await {
+                    if let Err(e) = process(stream, manager_tx, alive_token, id, serve_opt).await {
                         tracing::error!("Failed to process connection; error = {e}");
                     }
                 });
@@ -111,7 +184,7 @@ async fn main() -> Result<()> {
     manager_handle.await?;
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     set.shutdown().await;
-    tracing::info!("Shutdown finished");
+    tracing::info!("Shutdown finished; {}", link_stats);
     Ok(())
 }
 
@@ -120,6 +193,7 @@ async fn process(
     manager_tx: mpsc::Sender<(u64, ClientIpcOperation)>,
     alive_token: CancellationToken,
     id: u64,
+    serve_opt: ServeOpt,
 ) -> anyhow::Result<()> {
     let mut framed_read = LengthDelimitedCodec::builder()
         .length_field_type::<u32>()
@@ -145,7 +219,18 @@ async fn process(
             }
         },
     };
+    if let ConnectApp::Upload = connect_opt.app {
+        // The client sends the data, so its socket is tuned and this one is left alone
+        let mut framed_client = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_framed(framed_read.into_inner());
+        return serve_upload(&mut framed_client).await;
+    }
     let (obj_id, tcp_ca) = connect_opt.congestion.get_tcp_ca();
+    let frame_interval = match &connect_opt.app {
+        ConnectApp::Video(opt) => opt.segment,
+        _ => Duration::ZERO,
+    };
     let stream = framed_read.into_inner();
     let std_sk = stream.into_std()?;
     let sk = socket2::Socket::from(std_sk);
@@ -156,93 +241,495 @@ async fn process(
 
     let total_retrans = get_tcp_info_total_retrans(fd)?;
 
-    // Inform manager with new connection
-    if !alive_token.is_cancelled() {
-        let (tmp_tx, tmp_rx) = tokio::sync::oneshot::channel();
-        manager_tx
-            .send((
-                id,
-                ClientIpcOperation::Connect {
-                    obj_id,
-                    sk_raw_fd: fd,
-                    default_app_info: None,
-                    resp: tmp_tx,
+    let socket = FlowSocket {
+        fd,
+        obj_id,
+        tcp_ca,
+        default_app_info: connect_opt.app.default_app_info(),
+    };
+    connect_flow(
+        &manager_tx,
+        &alive_token,
+        id,
+        socket,
+        serve_opt.async_connect,
+    )
+    .await?;
+
+    let hints = HintSink {
+        manager_tx: &manager_tx,
+        alive_token: &alive_token,
+        id,
+        obj_id,
+    };
+    // The flow is disconnected however serving ends
+    let res: anyhow::Result<()> = async {
+        let mut framed_client = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_framed(stream);
+        let resp = ServerResponse {
+            id: 0,
+            client_send: 0,
+            server_recv: get_clock_ns() as u64,
+            size: 0,
+        };
+        let resp_bytes = resp.write_to_vec().map(Into::into).unwrap();
+        framed_client.send(resp_bytes).await?;
+        match &connect_opt.app {
+            ConnectApp::WebRTC(opt) => {
+                return serve_frames(
+                    framed_client,
+                    &manager_tx,
+                    &alive_token,
+                    id,
+                    &connect_opt.congestion,
+                    opt,
+                    fd,
+                )
+                .await;
+            }
+            ConnectApp::Bulk(opt) => {
+                if let Err(e) = serve_bulk(&mut framed_client, &serve_opt, opt, fd).await {
+                    tracing::error!("Fail to serve bulk connection {}: {:?}", id, e);
+                }
+                return Ok(());
+            }
+            ConnectApp::Requests(opt) if opt.mux => {
+                return serve_mux(&mut framed_client, &serve_opt, hints, fd, total_retrans).await;
+            }
+            _ => {}
+        }
+        loop {
+            match framed_client.next().await {
+                None => {
+                    break;
+                }
+                Some(res) => match res {
+                    Ok(bytes) => {
+                        let server_recv = get_clock_ns() as u64;
+                        let req = ClientRequestOpt::read_from_buffer(bytes.as_ref())?;
+                        match req {
+                            ClientRequestOpt::Connect(_) | ClientRequestOpt::Upload(_) => (),
+                            ClientRequestOpt::Request(request) => {
+                                if let Some(hint) = request.hint {
+                                    hints.push(request.size as u64, hint).await;
+                                }
+                                let resp = ServerResponse {
+                                    id: request.id,
+                                    client_send: request.client_send,
+                                    server_recv,
+                                    size: request.size as u64,
+                                };
+                                let resp_bytes = resp.write_to_vec().map(Into::into).unwrap();
+                                framed_client.send(resp_bytes).await?;
+                                serve_opt
+                                    .source
+                                    .write_body(framed_client.get_mut(), resp.size)
+                                    .await?;
+                            }
+                            ClientRequestOpt::Ack(ack) => {
+                                report_qoe(
+                                    &manager_tx,
+                                    &alive_token,
+                                    id,
+                                    obj_id,
+                                    ack,
+                                    frame_interval,
+                                )
+                                .await;
+                            }
+                            ClientRequestOpt::Finish => {
+                                send_finish(&mut framed_client, fd, total_retrans).await?;
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Fail to process request from client: {:?}", e);
+                        break;
+                    }
                 },
-            ))
-            .await
-            .unwrap();
-        tmp_rx.await.unwrap().map_err(MortiseError::Custom)?;
+            }
+        }
+        Ok(())
     }
+    .await;
+    disconnect_flow(&manager_tx, &alive_token, id, obj_id).await?;
+    res
+}
 
-    let mut framed_client = LengthDelimitedCodec::builder()
-        .length_field_type::<u32>()
-        .new_framed(stream);
+/// Answer `Finish` with the delta of total_retrans.
+async fn send_finish(
+    framed_client: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    fd: i32,
+    total_retrans: u32,
+) -> anyhow::Result<()> {
+    let total_retrans = get_tcp_info_total_retrans(fd)? - total_retrans;
     let resp = ServerResponse {
         id: 0,
         client_send: 0,
-        server_recv: get_clock_ns() as u64,
-        data: Vec::new(),
+        server_recv: total_retrans as u64,
+        size: 0,
     };
     let resp_bytes = resp.write_to_vec().map(Into::into).unwrap();
     framed_client.send(resp_bytes).await?;
-    loop {
-        match framed_client.next().await {
-            None => {
+    Ok(())
+}
+
+/// Flow of connection `id`, whose CCA takes the hints of its requests.
+#[derive(Clone, Copy)]
+struct HintSink<'a> {
+    manager_tx: &'a mpsc::Sender<(u64, ClientIpcOperation)>,
+    alive_token: &'a CancellationToken,
+    id: u64,
+    obj_id: u32,
+}
+
+impl HintSink<'_> {
+    /// Push the hints of a request into the CCA as its response of `size` bytes starts.
+    /// The updates are not waited for, CCAs without the maps of the hints keep running
+    /// without them.
+    async fn push(self, size: u64, hint: RequestHint) {
+        if self.alive_token.is_cancelled() {
+            return;
+        }
+        let updates = [
+            (HINT_MAP, hint.app_info(size)),
+            (RATE_CAP_MAP, hint.rate_cap()),
+        ];
+        for (map_name, val) in updates {
+            let (tmp_tx, tmp_rx) = tokio::sync::oneshot::channel();
+            let op = ClientIpcOperation::MapUpdate {
+                obj_id: self.obj_id,
+                map_name: map_name.to_string(),
+                val,
+                flag: BpfMapFlags::ANY,
+                resp: tmp_tx,
+            };
+            if self.manager_tx.send((self.id, op)).await.is_err() {
+                return;
+            }
+            let id = self.id;
+            tokio::spawn(async move {
+                if let Ok(Err(e)) = tmp_rx.await {
+                    tracing::debug!(target: "server", "Hint of connection {} not pushed into {}: {}", id, map_name, e);
+                }
+            });
+        }
+    }
+}
+
+/// Feed the QoE of a response acknowledged by the client to manager.
+async fn report_qoe(
+    manager_tx: &mpsc::Sender<(u64, ClientIpcOperation)>,
+    alive_token: &CancellationToken,
+    id: u64,
+    obj_id: u32,
+    ack: ChunkAck,
+    frame_interval: Duration,
+) {
+    if alive_token.is_cancelled() {
+        return;
+    }
+    let qoe = FrameQoE {
+        server_send: ack.server_send,
+        client_recv: ack.client_recv,
+        server_recv: get_clock_ns() as u64,
+        size: ack.size,
+        frame_interval,
+        frame_id: ack.id,
+    };
+    let (tmp_tx, tmp_rx) = tokio::sync::oneshot::channel();
+    let op = ClientIpcOperation::QoEUpdate {
+        obj_id,
+        qoe,
+        resp: tmp_tx,
+    };
+    // manager_ipc is gone at shutdown
+    if manager_tx.send((id, op)).await.is_err() {
+        return;
+    }
+    if let Ok(Err(e)) = tmp_rx.await {
+        tracing::debug!(target: "server", "QoE of connection {} not recorded: {}", id, e);
+    }
+}
+
+/// Receive the payloads of an uploading client, answering each with an empty response
+/// once it is received.
+async fn serve_upload(
+    framed_client: &mut Framed<TcpStream, LengthDelimitedCodec>,
+) -> anyhow::Result<()> {
+    let resp = ServerResponse {
+        id: 0,
+        client_send: 0,
+        server_recv: get_clock_ns() as u64,
+        size: 0,
+    };
+    framed_client
+        .send(resp.write_to_vec().map(Into::into)?)
+        .await?;
+    while let Some(bytes) = framed_client.next().await {
+        let bytes = bytes?;
+        match ClientRequestOpt::read_from_buffer(bytes.as_ref())? {
+            ClientRequestOpt::Upload(request) => {
+                body::skip_framed_body(framed_client, request.size as u64).await?;
+                let resp = ServerResponse {
+                    id: request.id,
+                    client_send: request.client_send,
+                    server_recv: get_clock_ns() as u64,
+                    size: 0,
+                };
+                framed_client
+                    .send(resp.write_to_vec().map(Into::into)?)
+                    .await?;
+            }
+            // The client counts the retransmissions of its own socket
+            ClientRequestOpt::Finish => {
+                let resp = ServerResponse {
+                    id: 0,
+                    client_send: 0,
+                    server_recv: 0,
+                    size: 0,
+                };
+                framed_client
+                    .send(resp.write_to_vec().map(Into::into)?)
+                    .await?;
                 break;
             }
-            Some(res) => match res {
-                Ok(bytes) => {
-                    let server_recv = get_clock_ns() as u64;
-                    let req = ClientRequestOpt::read_from_buffer(bytes.as_ref())?;
-                    match req {
-                        ClientRequestOpt::Connect(_) => (),
-                        ClientRequestOpt::Request(request) => {
-                            let resp = ServerResponse {
-                                id: request.id,
-                                client_send: request.client_send,
-                                server_recv,
-                                data: vec![0; request.size as usize],
-                            };
-                            let resp_bytes = resp.write_to_vec().map(Into::into).unwrap();
-                            framed_client.send(resp_bytes).await?;
-                        }
-                        // collect delta of total_retrans
-                        ClientRequestOpt::Finish => {
-                            let total_retrans = get_tcp_info_total_retrans(fd)? - total_retrans;
-                            let resp = ServerResponse {
-                                id: 0,
-                                client_send: 0,
-                                server_recv: total_retrans as u64,
-                                data: Vec::new(),
-                            };
-                            let resp_bytes = resp.write_to_vec().map(Into::into).unwrap();
-                            framed_client.send(resp_bytes).await?;
-                            break;
-                        }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Send real-time frames until the duration asked by the client is over, adapting their
+/// bitrate from the acks of the client.
+async fn serve_frames(
+    framed_client: Framed<TcpStream, LengthDelimitedCodec>,
+    manager_tx: &mpsc::Sender<(u64, ClientIpcOperation)>,
+    alive_token: &CancellationToken,
+    id: u64,
+    congestion: &CongestionOpt,
+    opt: &WebRTCOpt,
+    fd: i32,
+) -> anyhow::Result<()> {
+    let fps = 1_000_000 / (opt.frame_interval.as_micros() as u64).max(1);
+    let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+    let ctrl = FrameRateController::new(fps, opt.bitrate_kbps, opt.deadline, opt.duration, ack_rx);
+    let (op_tx, op_rx) = mpsc::unbounded_channel();
+    let (ready_tx, ready_rx) = mpsc::unbounded_channel();
+    let (rh, wh) = framed_client.into_inner().into_split();
+    let transport_info = Arc::new(AtomicRawCell::new(Box::default()));
+    let sender = tokio::spawn(handle_send(wh, op_rx, ready_tx, transport_info.clone()));
+    let controller = tokio::spawn(run_rate_controller(ctrl, op_tx, ready_rx));
+    let transport_opt = TransportOpt {
+        frame: fps,
+        mode: if alive_token.is_cancelled() {
+            ModeOpt::Origin
+        } else {
+            ModeOpt::Mortise
+        },
+        sk_fd: fd,
+        congestion: congestion.clone(),
+        app: AppOpt::WebRTC,
+    };
+    let stats = handle_recv(rh, manager_tx.clone(), id, transport_opt, Some(ack_tx)).await;
+    controller.await?;
+    sender.await?;
+
+    let late = stats
+        .values()
+        .filter(|stat| one_way_delay(&stat.qoe) > opt.deadline)
+        .count();
+    let score =
+        stats.values().map(|stat| stat.qoe.score()).sum::<f64>() / stats.len().max(1) as f64;
+    let written = transport_info
+        .swap_null()
+        .map(|info| info.total_write_bytes)
+        .unwrap_or(0);
+    tracing::info!(
+        target: "server",
+        "Connection {}: {} frames acked, {} late, mean QoE {:.3}, {} bytes written",
+        id,
+        stats.len(),
+        late,
+        score,
+        written
+    );
+    Ok(())
+}
+
+/// Send data until the duration or the byte count asked by the client is reached, with a
+/// report of the `tcp_info` of the socket every interval.
+async fn serve_bulk(
+    framed_client: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    serve_opt: &ServeOpt,
+    opt: &BulkOpt,
+    fd: i32,
+) -> anyhow::Result<()> {
+    let mut sampler = BulkSampler::new(fd)?;
+    let start = tokio::time::Instant::now();
+    let interval = opt.interval.max(Duration::from_millis(10));
+    let mut next_report = start + interval;
+    let mut offset = 0;
+    loop {
+        let remaining = if opt.bytes > 0 {
+            opt.bytes - offset
+        } else if start.elapsed() < opt.duration {
+            CHUNK_SIZE as u64
+        } else {
+            0
+        };
+        if remaining == 0 {
+            break;
+        }
+        let size = remaining.min(CHUNK_SIZE as u64);
+        let frame = BulkFrame::Data { size }.write_to_vec().map(Into::into)?;
+        framed_client.send(frame).await?;
+        serve_opt
+            .source
+            .write_chunk(framed_client.get_mut(), offset, size)
+            .await?;
+        sampler.sent(size);
+        offset += size;
+        // Reports wait for the chunk being written, which is short enough
+        let now = tokio::time::Instant::now();
+        if now >= next_report {
+            let frame = BulkFrame::Report(sampler.sample()?)
+                .write_to_vec()
+                .map(Into::into)?;
+            framed_client.send(frame).await?;
+            while next_report <= now {
+                next_report += interval;
+            }
+        }
+    }
+    let frame = BulkFrame::Report(sampler.sample()?)
+        .write_to_vec()
+        .map(Into::into)?;
+    framed_client.send(frame).await?;
+    // The client closes once it read everything
+    framed_client.get_mut().shutdown().await?;
+    while let Some(frame) = framed_client.next().await {
+        frame?;
+    }
+    Ok(())
+}
+
+/// Serve the requests of a multiplexed connection, interleaving the chunks of the
+/// responses in flight in the order of the scheduler.
+async fn serve_mux(
+    framed_client: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    serve_opt: &ServeOpt,
+    hints: HintSink<'_>,
+    fd: i32,
+    total_retrans: u32,
+) -> anyhow::Result<()> {
+    let mut queue = MuxQueue::new(serve_opt.scheduler);
+    loop {
+        // Wait for requests only when there is nothing to send
+        let frame = if queue.is_empty() {
+            framed_client.next().await
+        } else if let Some(frame) = framed_client.next().now_or_never() {
+            frame
+        } else {
+            match queue.next_chunk() {
+                Some(MuxChunk::Head(head, hint)) => {
+                    if let Some(hint) = hint {
+                        hints.push(head.size, hint).await;
                     }
+                    let frame = MuxFrame::Head(head).write_to_vec().map(Into::into)?;
+                    framed_client.send(frame).await?;
                 }
-                Err(e) => {
-                    tracing::error!("Fail to process request from client: {:?}", e);
-                    break;
+                Some(MuxChunk::Data { id, offset, len }) => {
+                    let frame = MuxFrame::Data { id, len }.write_to_vec().map(Into::into)?;
+                    framed_client.send(frame).await?;
+                    serve_opt
+                        .source
+                        .write_chunk(framed_client.get_mut(), offset, len as u64)
+                        .await?;
                 }
-            },
+                None => {}
+            }
+            continue;
+        };
+        let bytes = match frame {
+            None => break,
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) => {
+                tracing::error!("Fail to process request from client: {:?}", e);
+                break;
+            }
+        };
+        let server_recv = get_clock_ns() as u64;
+        match ClientRequestOpt::read_from_buffer(bytes.as_ref())? {
+            ClientRequestOpt::Connect(_)
+            | ClientRequestOpt::Ack(_)
+            | ClientRequestOpt::Upload(_) => (),
+            ClientRequestOpt::Request(request) => queue.push(
+                ServerResponse {
+                    id: request.id,
+                    client_send: request.client_send,
+                    server_recv,
+                    size: request.size as u64,
+                },
+                request.hint,
+            ),
+            ClientRequestOpt::Finish => {
+                send_finish(framed_client, fd, total_retrans).await?;
+                break;
+            }
         }
     }
+    Ok(())
+}
 
-    // Inform manager with disconnection
-    if !alive_token.is_cancelled() {
-        let (tmp_tx, tmp_rx) = tokio::sync::oneshot::channel();
-        manager_tx
-            .send((
-                id,
-                ClientIpcOperation::Disconnect {
-                    obj_id,
-                    resp: tmp_tx,
-                },
-            ))
-            .await
-            .unwrap();
-        tmp_rx.await.unwrap().map_err(MortiseError::Custom)?;
+async fn process_http(
+    mut stream: TcpStream,
+    manager_tx: mpsc::Sender<(u64, ClientIpcOperation)>,
+    alive_token: CancellationToken,
+    id: u64,
+    serve_opt: ServeOpt,
+) -> anyhow::Result<()> {
+    let (obj_id, tcp_ca) = serve_opt.congestion.get_tcp_ca();
+    socket2::SockRef::from(&stream).set_tcp_congestion(tcp_ca)?;
+    let fd = stream.as_raw_fd();
+    let total_retrans = get_tcp_info_total_retrans(fd)?;
+
+    let socket = FlowSocket {
+        fd,
+        obj_id,
+        tcp_ca,
+        default_app_info: None,
+    };
+    connect_flow(
+        &manager_tx,
+        &alive_token,
+        id,
+        socket,
+        serve_opt.async_connect,
+    )
+    .await?;
+    let hints = HintSink {
+        manager_tx: &manager_tx,
+        alive_token: &alive_token,
+        id,
+        obj_id,
+    };
+    let res = http::serve_http(
+        &mut stream,
+        serve_opt.root.as_deref().map(PathBuf::as_path),
+        &serve_opt.source,
+        || {
+            get_tcp_info_total_retrans(fd)
+                .map(|retrans| (retrans - total_retrans) as u64)
+                .unwrap_or(0)
+        },
+        |size, hint| hints.push(size, hint),
+    )
+    .await;
+    if let Err(e) = res {
+        tracing::error!("Fail to serve HTTP connection {}: {:?}", id, e);
     }
-    Ok(())
+    disconnect_flow(&manager_tx, &alive_token, id, obj_id).await
 }
@@ -0,0 +1,188 @@
+//! Bodies of the responses, written after their header frame in chunks of at most
+//! `CHUNK_SIZE` bytes, so that neither side holds a whole response in memory.
+//!
+//! Bodies are zeros from a shared buffer, or the content of a file sent with
+//! `sendfile(2)`, starting over from its beginning when a body is longer than the file.
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, FramedRead, LengthDelimitedCodec};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+static ZEROS: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+
+/// Content of the bodies written by a server.
+#[derive(Debug, Clone, Default)]
+pub enum BodySource {
+    #[default]
+    Zeros,
+    File(Arc<std::fs::File>, u64),
+}
+
+impl BodySource {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "body file is empty",
+            ));
+        }
+        Ok(BodySource::File(Arc::new(file), len))
+    }
+
+    /// Write a body of `size` bytes to `stream`.
+    pub async fn write_body(&self, stream: &mut TcpStream, size: u64) -> io::Result<()> {
+        self.write_chunk(stream, 0, size).await
+    }
+
+    /// Write `len` bytes of a body from `offset`.
+    pub async fn write_chunk(
+        &self,
+        stream: &mut TcpStream,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<()> {
+        match self {
+            BodySource::Zeros => write_zeros(stream, len).await,
+            BodySource::File(file, file_len) => {
+                send_file(stream, file, *file_len, offset, len).await
+            }
+        }
+    }
+}
+
+pub async fn write_zeros<W: AsyncWrite + Unpin>(writer: &mut W, size: u64) -> io::Result<()> {
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(CHUNK_SIZE as u64) as usize;
+        writer.write_all(&ZEROS[..len]).await?;
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+/// Send `count` bytes of `file`, of `file_len` bytes, from `offset` with `sendfile(2)`.
+/// Bytes can not be sent from an empty file.
+pub async fn send_file(
+    stream: &TcpStream,
+    file: &std::fs::File,
+    file_len: u64,
+    offset: u64,
+    count: u64,
+) -> io::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    if file_len == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let sk_fd = stream.as_raw_fd();
+    let file_fd = file.as_raw_fd();
+    let mut offset = (offset % file_len) as libc::off_t;
+    let mut remaining = count;
+    while remaining > 0 {
+        let len = remaining
+            .min(file_len - offset as u64)
+            .min(CHUNK_SIZE as u64) as usize;
+        stream.writable().await?;
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            let n = unsafe { libc::sendfile(sk_fd, file_fd, &mut offset, len) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(n as u64)
+        });
+        match sent {
+            // The file shrank since it was opened
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                remaining -= n;
+                if offset as u64 >= file_len {
+                    offset = 0;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Read and discard a body of `size` bytes written after the last frame of `reader`.
+pub async fn skip_body<R: AsyncRead + Unpin>(
+    reader: &mut FramedRead<R, LengthDelimitedCodec>,
+    size: u64,
+) -> io::Result<()> {
+    // The codec may have read past its frame
+    let remaining = skip_buffered(reader.read_buffer_mut(), size);
+    discard(reader.get_mut(), remaining).await
+}
+
+/// Read and discard a body of `size` bytes written after the last frame of `framed`.
+pub async fn skip_framed_body<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, LengthDelimitedCodec>,
+    size: u64,
+) -> io::Result<()> {
+    let remaining = skip_buffered(framed.read_buffer_mut(), size);
+    discard(framed.get_mut(), remaining).await
+}
+
+/// Drop the buffered bytes of a body of `size` bytes, returning the bytes left to read.
+fn skip_buffered(buffered: &mut BytesMut, size: u64) -> u64 {
+    let len = size.min(buffered.len() as u64);
+    buffered.advance(len as usize);
+    size - len
+}
+
+async fn discard<R: AsyncRead + Unpin>(reader: &mut R, size: u64) -> io::Result<()> {
+    let mut remaining = size;
+    let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+    while remaining > 0 {
+        buf.clear();
+        let want = remaining.min(CHUNK_SIZE as u64) as usize;
+        let n = reader.read_buf(&mut (&mut buf).limit(want)).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::send_file;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_send_file() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let path = std::env::temp_dir().join(format!("mortise-body-{}", std::process::id()));
+
+        std::fs::write(&path, b"").unwrap();
+        let empty = std::fs::File::open(&path).unwrap();
+        send_file(&stream, &empty, 0, 0, 0).await.unwrap();
+        assert!(send_file(&stream, &empty, 0, 0, 4).await.is_err());
+
+        // Bodies longer than the file start over from its beginning
+        std::fs::write(&path, b"abc").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        send_file(&stream, &file, 3, 1, 7).await.unwrap();
+        let mut buf = [0; 7];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"bcabcab");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
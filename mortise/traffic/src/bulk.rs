@@ -0,0 +1,102 @@
+//! Bulk throughput test, like iperf3.
+//!
+//! The server sends continuously for a duration or a byte count. Every report interval
+//! it samples the `tcp_info` of its socket and sends the interval as a report frame
+//! between two data frames, so that the client records the sender's view next to the
+//! goodput it received.
+
+use mortise_common::{get_tcp_info, Result};
+use speedy::{Readable, Writable};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Frame of a bulk connection.
+#[derive(Debug, Clone, Readable, Writable)]
+pub enum BulkFrame {
+    /// `size` bytes of data, written after the frame.
+    Data {
+        size: u64,
+    },
+    Report(BulkInterval),
+}
+
+/// Sender side of a report interval.
+#[derive(Debug, Clone, Default, Readable, Writable)]
+pub struct BulkInterval {
+    /// Time since the start of the test at the end of the interval.
+    pub end: Duration,
+    /// Time the interval lasted.
+    pub length: Duration,
+    /// Bytes written by the server during the interval.
+    pub bytes_sent: u64,
+    /// Bytes acknowledged by the client during the interval.
+    pub bytes_acked: u64,
+    /// Delivery rate estimated by the kernel, in bytes per second.
+    pub delivery_rate: u64,
+    pub rtt_us: u32,
+    pub rttvar_us: u32,
+    pub cwnd: u32,
+    /// Retransmissions during the interval.
+    pub retrans: u32,
+    /// Bytes written but not sent yet.
+    pub notsent: u32,
+}
+
+impl BulkInterval {
+    /// Acknowledged bytes per second of the interval, in kbps.
+    pub fn goodput_kbps(&self) -> f64 {
+        self.bytes_acked as f64 * 8.0 / 1000.0 / self.length.as_secs_f64().max(1e-6)
+    }
+}
+
+/// Samples the `tcp_info` of a socket at the end of each interval.
+pub struct BulkSampler {
+    fd: i32,
+    start: Instant,
+    last: Instant,
+    bytes_sent: u64,
+    last_acked: u64,
+    last_retrans: u32,
+}
+
+impl BulkSampler {
+    pub fn new(fd: i32) -> Result<Self> {
+        let tcp_info = get_tcp_info(fd)?;
+        let now = Instant::now();
+        Ok(BulkSampler {
+            fd,
+            start: now,
+            last: now,
+            bytes_sent: 0,
+            last_acked: tcp_info.tcpi_bytes_acked,
+            last_retrans: tcp_info.tcpi_total_retrans,
+        })
+    }
+
+    /// Count `size` bytes written to the socket.
+    pub fn sent(&mut self, size: u64) {
+        self.bytes_sent += size;
+    }
+
+    /// Close the current interval.
+    pub fn sample(&mut self) -> Result<BulkInterval> {
+        let tcp_info = get_tcp_info(self.fd)?;
+        let now = Instant::now();
+        let interval = BulkInterval {
+            end: now - self.start,
+            length: now - self.last,
+            bytes_sent: std::mem::take(&mut self.bytes_sent),
+            bytes_acked: tcp_info.tcpi_bytes_acked - self.last_acked,
+            delivery_rate: tcp_info.tcpi_delivery_rate,
+            rtt_us: tcp_info.tcpi_rtt,
+            rttvar_us: tcp_info.tcpi_rttvar,
+            cwnd: tcp_info.tcpi_snd_cwnd,
+            retrans: tcp_info.tcpi_total_retrans - self.last_retrans,
+            notsent: tcp_info.tcpi_notsent_bytes,
+        };
+        self.last = now;
+        self.last_acked = tcp_info.tcpi_bytes_acked;
+        self.last_retrans = tcp_info.tcpi_total_retrans;
+        Ok(interval)
+    }
+}
@@ -0,0 +1,90 @@
+//! Dispatch of the requests of a client over its parallel connections.
+
+use clap::ValueEnum;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[clap(rename_all = "kebab-case")]
+pub enum DispatchPolicy {
+    /// Connections in turn.
+    #[default]
+    RoundRobin,
+    /// Connection with the fewest requests in flight, the first one on ties.
+    LeastOutstanding,
+    /// Small requests on the first connection, the others in turn on the rest.
+    SizeBased,
+}
+
+pub struct Dispatcher {
+    policy: DispatchPolicy,
+    outstanding: Vec<Arc<AtomicU64>>,
+    /// Requests below this size are small for `SizeBased`.
+    small_size: u32,
+    next: usize,
+}
+
+impl Dispatcher {
+    pub fn new(policy: DispatchPolicy, connections: usize, small_size: u32) -> Self {
+        Dispatcher {
+            policy,
+            outstanding: (0..connections.max(1))
+                .map(|_| Arc::new(AtomicU64::new(0)))
+                .collect(),
+            small_size,
+            next: 0,
+        }
+    }
+
+    /// Requests in flight on connection `conn`, which decrements it as they complete.
+    pub fn outstanding(&self, conn: usize) -> Arc<AtomicU64> {
+        self.outstanding[conn].clone()
+    }
+
+    /// Pick the connection of a request of `size` bytes and count the request in flight.
+    pub fn dispatch(&mut self, size: u32) -> usize {
+        let n = self.outstanding.len();
+        let conn = match self.policy {
+            DispatchPolicy::RoundRobin => {
+                self.next += 1;
+                (self.next - 1) % n
+            }
+            DispatchPolicy::LeastOutstanding => (0..n)
+                .min_by_key(|&conn| self.outstanding[conn].load(Ordering::Relaxed))
+                .unwrap(),
+            DispatchPolicy::SizeBased if n == 1 || size < self.small_size => 0,
+            DispatchPolicy::SizeBased => {
+                self.next += 1;
+                1 + (self.next - 1) % (n - 1)
+            }
+        };
+        self.outstanding[conn].fetch_add(1, Ordering::Relaxed);
+        conn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DispatchPolicy, Dispatcher};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_dispatch() {
+        let mut rr = Dispatcher::new(DispatchPolicy::RoundRobin, 3, 0);
+        let conns: Vec<usize> = (0..4).map(|_| rr.dispatch(1)).collect();
+        assert_eq!(conns, vec![0, 1, 2, 0]);
+
+        let mut least = Dispatcher::new(DispatchPolicy::LeastOutstanding, 2, 0);
+        assert_eq!(least.dispatch(1), 0);
+        assert_eq!(least.dispatch(1), 1);
+        least.outstanding(1).fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(least.dispatch(1), 1);
+
+        let mut size = Dispatcher::new(DispatchPolicy::SizeBased, 3, 1000);
+        let conns: Vec<usize> = [10, 5000, 5000, 20, 5000]
+            .into_iter()
+            .map(|s| size.dispatch(s))
+            .collect();
+        assert_eq!(conns, vec![0, 1, 2, 0, 1]);
+    }
+}
@@ -0,0 +1,492 @@
+//! HTTP/1.1 mode of the traffic server and client.
+//!
+//! The server answers `GET /bytes/{size}` with a body of `size` bytes, and `GET /{path}` with
+//! the file at `path` of its root directory if it has one. Connections are kept alive and
+//! requests may be pipelined, so that curl or wrk can drive the server as well.
+//!
+//! Besides the standard headers, a response echoes the `x-request-id` and
+//! `x-client-send` headers of its request, and carries the time the server handled the
+//! request in `x-server-recv` and the retransmissions of the connection so far in
+//! `x-total-retrans`. The client thus records the same statistics as with the framed
+//! protocol. The hints of a request are carried by the `x-deadline-ms`, `x-priority` and
+//! `x-rate-cap-kbps` headers.
+
+use bytes::{Buf, BytesMut};
+use http::{Method, StatusCode};
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::body::{send_file, BodySource};
+use crate::RequestHint;
+
+pub const BYTES_PREFIX: &str = "/bytes/";
+pub const REQUEST_ID: &str = "x-request-id";
+pub const CLIENT_SEND: &str = "x-client-send";
+pub const SERVER_RECV: &str = "x-server-recv";
+pub const TOTAL_RETRANS: &str = "x-total-retrans";
+pub const DEADLINE_MS: &str = "x-deadline-ms";
+pub const PRIORITY: &str = "x-priority";
+pub const RATE_CAP_KBPS: &str = "x-rate-cap-kbps";
+
+const MAX_HEADERS: usize = 32;
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+fn invalid_data<E>(e: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+fn header_value<T: std::str::FromStr>(value: &[u8]) -> Option<T> {
+    std::str::from_utf8(value).ok()?.trim().parse().ok()
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub path: String,
+    pub keep_alive: bool,
+    pub request_id: Option<u32>,
+    pub client_send: Option<u64>,
+    pub hint: Option<RequestHint>,
+}
+
+/// Parse and consume the head of the first request in `buf`, `None` if it is incomplete.
+/// Requests with a body or invalid hints are refused.
+pub fn parse_request(buf: &mut BytesMut) -> std::io::Result<Option<HttpRequest>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(buf).map_err(invalid_data)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial if buf.len() > MAX_HEAD_LEN => {
+            return Err(invalid_data("request head too large"))
+        }
+        httparse::Status::Partial => return Ok(None),
+    };
+    let method =
+        Method::from_bytes(req.method.unwrap_or_default().as_bytes()).map_err(invalid_data)?;
+    let mut request = HttpRequest {
+        method,
+        path: req.path.unwrap_or("/").to_string(),
+        // Connections of HTTP/1.0 are closed unless asked otherwise
+        keep_alive: req.version == Some(1),
+        request_id: None,
+        client_send: None,
+        hint: None,
+    };
+    // Deadline, priority and rate cap of the hint
+    let mut hint: [Option<u64>; 3] = [None; 3];
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("connection") {
+            if header.value.eq_ignore_ascii_case(b"close") {
+                request.keep_alive = false;
+            } else if header.value.eq_ignore_ascii_case(b"keep-alive") {
+                request.keep_alive = true;
+            }
+        } else if header.name.eq_ignore_ascii_case(REQUEST_ID) {
+            request.request_id = header_value(header.value);
+        } else if header.name.eq_ignore_ascii_case(CLIENT_SEND) {
+            request.client_send = header_value(header.value);
+        } else if let Some(i) = [DEADLINE_MS, PRIORITY, RATE_CAP_KBPS]
+            .iter()
+            .position(|name| header.name.eq_ignore_ascii_case(name))
+        {
+            hint[i] = Some(header_value(header.value).ok_or_else(|| invalid_data("invalid hint"))?);
+        } else if (header.name.eq_ignore_ascii_case("content-length")
+            && header_value::<u64>(header.value) != Some(0))
+            || header.name.eq_ignore_ascii_case("transfer-encoding")
+        {
+            return Err(invalid_data("request bodies are not supported"));
+        }
+    }
+    if hint.iter().any(Option::is_some) {
+        let [deadline_ms, priority, rate_cap_kbps] = hint.map(Option::unwrap_or_default);
+        request.hint =
+            Some(RequestHint::new(deadline_ms, priority, rate_cap_kbps).map_err(invalid_data)?);
+    }
+    buf.advance(len);
+    Ok(Some(request))
+}
+
+/// Decode the `%XX` escapes of a request path, `None` if one is invalid or the path
+/// is not UTF-8 or holds a NUL once decoded.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// File of `root` at the request `path`, percent-decoded. Paths leaving the root, also
+/// through symbolic links, are refused. `root` must be canonical.
+pub fn resolve_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode(path.split(['?', '#']).next()?)?;
+    let relative = Path::new(path.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let full = root.join(relative).canonicalize().ok()?;
+    (full.starts_with(root) && full.is_file()).then_some(full)
+}
+
+fn response_head(
+    status: StatusCode,
+    content_length: u64,
+    keep_alive: bool,
+    headers: &[(&str, String)],
+) -> Vec<u8> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nDate: {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or(""),
+        httpdate::fmt_http_date(SystemTime::now()),
+        content_length,
+        if keep_alive { "keep-alive" } else { "close" },
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+enum Body {
+    /// Bytes of the body source of the server.
+    Bytes(u64),
+    File(std::fs::File, u64),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(len) | Body::File(_, len) => *len,
+        }
+    }
+}
+
+async fn open_body(request: &HttpRequest, root: Option<&Path>) -> Result<Body, StatusCode> {
+    if let Some(size) = request.path.strip_prefix(BYTES_PREFIX) {
+        return size
+            .parse()
+            .map(Body::Bytes)
+            .map_err(|_| StatusCode::BAD_REQUEST);
+    }
+    let path = root
+        .and_then(|root| resolve_path(root, &request.path))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?
+        .into_std()
+        .await;
+    let len = file
+        .metadata()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+    Ok(Body::File(file, len))
+}
+
+/// Serve the HTTP requests of a connection until the client closes it.
+///
+/// `total_retrans` gives the retransmissions of the connection since it was accepted,
+/// and `push_hint` takes the hint of a request with the size of its response.
+pub async fn serve_http<F, H, Fut>(
+    stream: &mut TcpStream,
+    root: Option<&Path>,
+    source: &BodySource,
+    total_retrans: F,
+    mut push_hint: H,
+) -> std::io::Result<()>
+where
+    F: Fn() -> u64,
+    H: FnMut(u64, RequestHint) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut buf = BytesMut::with_capacity(8 * 1024);
+    loop {
+        let request = match parse_request(&mut buf) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                if stream.read_buf(&mut buf).await? == 0 {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!(target: "server:http", "Invalid request: {}", e);
+                let head = response_head(StatusCode::BAD_REQUEST, 0, false, &[]);
+                stream.write_all(&head).await?;
+                return stream.shutdown().await;
+            }
+        };
+        let server_recv = mortise_common::get_clock_ns() as u64;
+        let mut headers = vec![
+            (SERVER_RECV, server_recv.to_string()),
+            (TOTAL_RETRANS, total_retrans().to_string()),
+        ];
+        if let Some(id) = request.request_id {
+            headers.push((REQUEST_ID, id.to_string()));
+        }
+        if let Some(client_send) = request.client_send {
+            headers.push((CLIENT_SEND, client_send.to_string()));
+        }
+        let body = if request.method == Method::GET || request.method == Method::HEAD {
+            open_body(&request, root).await
+        } else {
+            headers.push(("Allow", "GET, HEAD".to_string()));
+            Err(StatusCode::METHOD_NOT_ALLOWED)
+        };
+        match body {
+            Ok(body) => {
+                if let Some(hint) = request.hint {
+                    push_hint(body.len(), hint).await;
+                }
+                let head = response_head(StatusCode::OK, body.len(), request.keep_alive, &headers);
+                stream.write_all(&head).await?;
+                match body {
+                    _ if request.method == Method::HEAD => {}
+                    Body::Bytes(size) => source.write_body(stream, size).await?,
+                    Body::File(file, len) => send_file(stream, &file, len, 0, len).await?,
+                }
+            }
+            Err(status) => {
+                tracing::debug!(target: "server:http", "{} {}: {}", request.method, request.path, status);
+                let head = response_head(status, 0, request.keep_alive, &headers);
+                stream.write_all(&head).await?;
+            }
+        }
+        stream.flush().await?;
+        if !request.keep_alive {
+            return stream.shutdown().await;
+        }
+    }
+}
+
+/// Request of `size` bytes with its hint, tagged for the statistics of the client.
+pub fn bytes_request(
+    host: &str,
+    id: u32,
+    size: u32,
+    client_send: u64,
+    hint: Option<&RequestHint>,
+) -> Vec<u8> {
+    let mut head = format!(
+        "GET {}{} HTTP/1.1\r\nHost: {}\r\n{}: {}\r\n{}: {}\r\n",
+        BYTES_PREFIX, size, host, REQUEST_ID, id, CLIENT_SEND, client_send
+    );
+    if let Some(hint) = hint {
+        head.push_str(&format!(
+            "{}: {}\r\n{}: {}\r\n{}: {}\r\n",
+            DEADLINE_MS,
+            hint.deadline_ms,
+            PRIORITY,
+            hint.priority,
+            RATE_CAP_KBPS,
+            hint.rate_cap_kbps
+        ));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub content_length: u64,
+    pub request_id: Option<u32>,
+    pub client_send: Option<u64>,
+    pub server_recv: Option<u64>,
+    pub total_retrans: Option<u64>,
+}
+
+/// Reader of the responses of a connection, in the order of the requests.
+pub struct HttpResponseReader<R> {
+    reader: R,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> HttpResponseReader<R> {
+    pub fn new(reader: R) -> Self {
+        HttpResponseReader {
+            reader,
+            buf: BytesMut::with_capacity(64 * 1024),
+        }
+    }
+
+    /// Read the head of the next response, `None` if the connection is closed before it.
+    /// Its body must be skipped before the next head.
+    pub async fn next_head(&mut self) -> std::io::Result<Option<HttpResponse>> {
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut resp = httparse::Response::new(&mut headers);
+            match resp.parse(&self.buf).map_err(invalid_data)? {
+                httparse::Status::Complete(len) => {
+                    let mut response = HttpResponse {
+                        status: StatusCode::from_u16(resp.code.unwrap_or_default())
+                            .map_err(invalid_data)?,
+                        content_length: 0,
+                        request_id: None,
+                        client_send: None,
+                        server_recv: None,
+                        total_retrans: None,
+                    };
+                    for header in resp.headers.iter() {
+                        let name = header.name;
+                        if name.eq_ignore_ascii_case("content-length") {
+                            response.content_length = header_value(header.value)
+                                .ok_or_else(|| invalid_data("invalid content-length"))?;
+                        } else if name.eq_ignore_ascii_case(REQUEST_ID) {
+                            response.request_id = header_value(header.value);
+                        } else if name.eq_ignore_ascii_case(CLIENT_SEND) {
+                            response.client_send = header_value(header.value);
+                        } else if name.eq_ignore_ascii_case(SERVER_RECV) {
+                            response.server_recv = header_value(header.value);
+                        } else if name.eq_ignore_ascii_case(TOTAL_RETRANS) {
+                            response.total_retrans = header_value(header.value);
+                        }
+                    }
+                    self.buf.advance(len);
+                    return Ok(Some(response));
+                }
+                httparse::Status::Partial if self.buf.len() > MAX_HEAD_LEN => {
+                    return Err(invalid_data("response head too large"))
+                }
+                httparse::Status::Partial => {
+                    if self.reader.read_buf(&mut self.buf).await? == 0 {
+                        if self.buf.is_empty() {
+                            return Ok(None);
+                        }
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read and discard a body of `len` bytes.
+    pub async fn skip_body(&mut self, len: u64) -> std::io::Result<()> {
+        let mut remaining = len;
+        loop {
+            let len = remaining.min(self.buf.len() as u64);
+            self.buf.advance(len as usize);
+            remaining -= len;
+            if remaining == 0 {
+                return Ok(());
+            }
+            // Keep the buffer bounded by the chunks read
+            self.buf.clear();
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Read the next response and discard its body, `None` if the connection is closed
+    /// before it.
+    pub async fn next_response(&mut self) -> std::io::Result<Option<HttpResponse>> {
+        let Some(response) = self.next_head().await? else {
+            return Ok(None);
+        };
+        self.skip_body(response.content_length).await?;
+        Ok(Some(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bytes_request, parse_request, percent_decode, resolve_path};
+    use crate::RequestHint;
+    use bytes::BytesMut;
+    use http::Method;
+
+    #[test]
+    fn test_parse_request() {
+        let hint = RequestHint::new(100, 3, 5000).unwrap();
+        let mut buf = BytesMut::from(&b"GET /bytes/10 HTTP/1.1\r\nx-request-id: 3\r\n"[..]);
+        assert!(parse_request(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"Connection: close\r\n\r\nHEAD / HTTP/1.0\r\n\r\n");
+        let request = parse_request(&mut buf).unwrap().unwrap();
+        assert_eq!(request.method, Method::GET);
+        assert_eq!(request.path, "/bytes/10");
+        assert_eq!(request.request_id, Some(3));
+        assert!(!request.keep_alive);
+        // Pipelined requests are parsed one at a time
+        let request = parse_request(&mut buf).unwrap().unwrap();
+        assert_eq!(request.method, Method::HEAD);
+        assert!(!request.keep_alive);
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody"[..]);
+        assert!(parse_request(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"[..]);
+        assert!(parse_request(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nContent-Length: 0\r\n\r\n"[..]);
+        assert!(parse_request(&mut buf).unwrap().is_some());
+        let mut buf = BytesMut::from(&bytes_request("h", 1, 10, 0, Some(&hint))[..]);
+        assert_eq!(parse_request(&mut buf).unwrap().unwrap().hint, Some(hint));
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nx-priority: 256\r\n\r\n"[..]);
+        assert!(parse_request(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nx-deadline-ms: soon\r\n\r\n"[..]);
+        assert!(parse_request(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"GET /"[..]);
+        buf.extend_from_slice(&[b'a'; super::MAX_HEAD_LEN]);
+        assert!(parse_request(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let dir = std::env::temp_dir().join(format!("mortise-http-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a b"), b"").unwrap();
+        std::fs::write(root.join("sub/file"), b"").unwrap();
+        std::fs::write(dir.join("secret"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("outside")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub/file"), root.join("inside")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(resolve_path(&root, "/a%20b"), Some(root.join("a b")));
+        assert_eq!(
+            resolve_path(&root, "/sub/file?x=1"),
+            Some(root.join("sub/file"))
+        );
+        assert_eq!(resolve_path(&root, "/inside"), Some(root.join("sub/file")));
+        assert!(resolve_path(&root, "/sub").is_none());
+        assert!(resolve_path(&root, "/../secret").is_none());
+        assert!(resolve_path(&root, "/sub/../../secret").is_none());
+        assert!(resolve_path(&root, "/%2e%2e/secret").is_none());
+        assert!(resolve_path(&root, "//etc/passwd").is_none());
+        assert!(resolve_path(&root, &format!("/{}", dir.join("secret").display())).is_none());
+        assert!(resolve_path(&root, "/outside").is_none());
+        assert!(resolve_path(&root, "/a%2").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/a%20b%2Fc").as_deref(), Some("/a b/c"));
+        assert_eq!(percent_decode("/%C3%A9").as_deref(), Some("/\u{e9}"));
+        assert!(percent_decode("/%zz").is_none());
+        assert!(percent_decode("/%00").is_none());
+        assert!(percent_decode("/%FF").is_none());
+    }
+}
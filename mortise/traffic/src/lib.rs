@@ -1,17 +1,33 @@
 use std::collections::HashMap;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::ValueEnum;
 use futures::{SinkExt, StreamExt};
 use libbpf_rs::MapFlags as BpfMapFlags;
 use mortise_common::qoe::{AppInfo, FrameQoE};
-use mortise_common::{read_be_u32, FlowOperation};
+use mortise_common::{
+    codec::BINARY_VERSION, read_be_u32, set_tcp_congestion, Encoding, FlowOperation, ManagerEvent,
+    ManagerOperation, ManagerResponse, MortiseError, Operation,
+};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::oneshot;
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
 
+pub mod body;
+pub mod bulk;
+pub mod dispatch;
+pub mod http;
+pub mod mux;
+pub mod realtime;
 pub mod transport;
 pub mod utils;
+pub mod video;
 pub use transport::io::*;
 pub use utils::*;
 
@@ -59,7 +75,10 @@ pub enum ClientIpcOperation {
     Shutdown,
     Connect {
         obj_id: u32,
-        sk_raw_fd: i32,
+        /// Duplicate of the socket, so that its descriptor is not reused while relayed
+        sk: Arc<OwnedFd>,
+        /// CCA of the socket, set again when it is enrolled after a fallback
+        tcp_ca: Vec<u8>,
         default_app_info: Option<u64>,
         resp: oneshot::Sender<std::result::Result<(), String>>,
     },
@@ -74,15 +93,306 @@ pub enum ClientIpcOperation {
     },
 }
 
-pub async fn manager_ipc(mut rx: Receiver<(u64, ClientIpcOperation)>) {
-    let mut stream = match UnixStream::connect("/tmp/mortise.sock").await {
-        Ok(s) => s,
-        Err(e) => {
-            tracing::error!(target: "sender:manager", "Failed to connect to manager: {}", e);
-            return;
+/// Handler of a response from manager, which may also update the flow id map.
+///
+/// The response is `None` if the manager became unavailable before answering.
+type PendingHandler =
+    Box<dyn FnOnce(Option<std::result::Result<Vec<u8>, String>>, &mut HashMap<u64, u32>) + Send>;
+
+const MANAGER_UNAVAILABLE: &str = "Manager unavailable";
+
+fn or_unavailable(
+    response: Option<std::result::Result<Vec<u8>, String>>,
+) -> std::result::Result<Vec<u8>, String> {
+    response.unwrap_or_else(|| Err(MANAGER_UNAVAILABLE.to_string()))
+}
+
+/// Settings of the link of `manager_ipc` to manager.
+#[derive(Debug, Clone)]
+pub struct ManagerLinkConfig {
+    /// Unix socket the manager listens on.
+    pub sock_path: String,
+    pub encoding: Encoding,
+    /// Kernel CCA of the connections while the manager is unavailable.
+    pub fallback_tcp_ca: String,
+    /// Delay before the first reconnection, doubled up to `max_backoff` on each failure.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ManagerLinkConfig {
+    pub fn new(encoding: Encoding, fallback_tcp_ca: String) -> Self {
+        ManagerLinkConfig {
+            sock_path: "/tmp/mortise.sock".to_string(),
+            encoding,
+            fallback_tcp_ca,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
         }
-    };
-    let (rh, wh) = stream.split();
+    }
+}
+
+/// State and counters of the link to manager, shared with the server.
+#[derive(Debug, Default)]
+pub struct ManagerLinkStats {
+    up: AtomicBool,
+    /// Times the link was established.
+    pub connects: AtomicU64,
+    /// Connections enrolled again after the manager came back.
+    pub reenrolled: AtomicU64,
+    /// Connections switched to the fallback CCA.
+    pub fallbacks: AtomicU64,
+}
+
+impl ManagerLinkStats {
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Display for ManagerLinkStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "manager link {}, {} connects, {} re-enrolled, {} fallbacks",
+            if self.is_up() { "up" } else { "down" },
+            self.connects.load(Ordering::Relaxed),
+            self.reenrolled.load(Ordering::Relaxed),
+            self.fallbacks.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Fallback CCA of the connections, applied through their socket.
+#[derive(Clone)]
+struct Fallback {
+    tcp_ca: String,
+    stats: Arc<ManagerLinkStats>,
+}
+
+impl Fallback {
+    fn apply(&self, id: u64, sk_raw_fd: i32) -> std::result::Result<(), String> {
+        set_tcp_congestion(sk_raw_fd, self.tcp_ca.as_bytes()).map_err(|e| {
+            tracing::error!(target: "sender:manager", "Failed to set {} on connection {}: {}", self.tcp_ca, id, e);
+            e.to_string()
+        })?;
+        self.stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(target: "sender:manager", "Connection {} falls back to {}", id, self.tcp_ca);
+        Ok(())
+    }
+}
+
+/// Connection with a bpf CCA, enrolled again when the manager comes back.
+///
+/// Its socket stays open until its `Disconnect` is answered, even if the server closed it.
+struct OwnedConn {
+    obj_id: u32,
+    sk: Arc<OwnedFd>,
+    tcp_ca: Vec<u8>,
+    default_app_info: Option<u64>,
+}
+
+/// Track the connections with a bpf CCA, the connection released by a disconnect is
+/// returned.
+fn track(
+    owned: &mut HashMap<u64, OwnedConn>,
+    id: u64,
+    op: &ClientIpcOperation,
+) -> Option<OwnedConn> {
+    match op {
+        ClientIpcOperation::Connect {
+            obj_id,
+            sk,
+            tcp_ca,
+            default_app_info,
+            ..
+        } if *obj_id != 0 => owned.insert(
+            id,
+            OwnedConn {
+                obj_id: *obj_id,
+                sk: sk.clone(),
+                tcp_ca: tcp_ca.clone(),
+                default_app_info: *default_app_info,
+            },
+        ),
+        ClientIpcOperation::Disconnect { .. } => owned.remove(&id),
+        _ => None,
+    }
+}
+
+/// Keep `conn` open until the response handled by `handler`.
+fn hold(conn: Option<OwnedConn>, handler: PendingHandler) -> PendingHandler {
+    match conn {
+        Some(conn) => Box::new(move |response, flow_id_map| {
+            handler(response, flow_id_map);
+            drop(conn);
+        }),
+        None => handler,
+    }
+}
+
+/// Translate a client operation into the request to manager and the handler of its response.
+///
+/// Returns `None` if the operation is answered locally.
+fn prepare_request(
+    id: u64,
+    op: ClientIpcOperation,
+    pid: i32,
+    flow_id_map: &HashMap<u64, u32>,
+    fallback: &Fallback,
+) -> Option<(Operation, PendingHandler)> {
+    let flow_id = *flow_id_map.get(&id).unwrap_or(&0);
+    match op {
+        ClientIpcOperation::Load { .. } | ClientIpcOperation::Shutdown => None,
+        ClientIpcOperation::MapUpdate {
+            obj_id,
+            map_name,
+            val,
+            flag,
+            resp,
+        } => {
+            if obj_id == 0 {
+                let _ = resp.send(Ok(()));
+                return None;
+            }
+            let req = FlowOperation::SkStgMapUpdate {
+                map_name,
+                val: Vec::from(val.as_bytes()),
+                flag: flag.bits(),
+            }
+            .to_op(flow_id);
+            let handler: PendingHandler = Box::new(move |response, _| {
+                let _ = resp.send(or_unavailable(response).map(|_| ()));
+            });
+            Some((req, handler))
+        }
+        ClientIpcOperation::MapLookup {
+            obj_id,
+            map_name,
+            resp,
+        } => {
+            if obj_id == 0 {
+                let _ = resp.send(Ok(Vec::new()));
+                return None;
+            }
+            let req = FlowOperation::SkStgMapLookup { map_name }.to_op(flow_id);
+            let handler: PendingHandler = Box::new(move |response, _| {
+                let _ = resp.send(or_unavailable(response));
+            });
+            Some((req, handler))
+        }
+        ClientIpcOperation::Connect {
+            obj_id,
+            sk,
+            default_app_info,
+            resp,
+            ..
+        } => {
+            if obj_id == 0 {
+                let _ = resp.send(Ok(()));
+                return None;
+            }
+            let req = FlowOperation::Connect {
+                pid,
+                obj_id: Some(obj_id),
+                sk_fd: sk.as_raw_fd(),
+                default_app_info,
+                tag: None,
+            }
+            .to_op(0);
+            let fallback = fallback.clone();
+            let handler: PendingHandler = Box::new(move |response, flow_id_map| match response {
+                Some(Ok(r)) => {
+                    let remote_flow_id = read_be_u32(&mut r.as_ref());
+                    flow_id_map.insert(id, remote_flow_id);
+                    let _ = resp.send(Ok(()));
+                }
+                Some(Err(e)) => {
+                    let _ = resp.send(Err(e));
+                }
+                // Serve the connection with the kernel CCA until manager comes back
+                None => {
+                    let _ = resp.send(fallback.apply(id, sk.as_raw_fd()));
+                }
+            });
+            Some((req, handler))
+        }
+        ClientIpcOperation::Disconnect { obj_id, resp } => {
+            if obj_id == 0 {
+                let _ = resp.send(Ok(()));
+                return None;
+            }
+            let req = FlowOperation::Disconnect {}.to_op(flow_id);
+            let handler: PendingHandler = Box::new(move |response, flow_id_map| {
+                flow_id_map.remove(&id);
+                // The flow is gone with an unavailable manager
+                let _ = resp.send(response.unwrap_or(Ok(Vec::new())).map(|_| ()));
+            });
+            Some((req, handler))
+        }
+        ClientIpcOperation::QoEUpdate { obj_id, qoe, resp } => {
+            if obj_id == 0 {
+                let _ = resp.send(Ok(Vec::new()));
+                return None;
+            }
+            let req = FlowOperation::QoEUpdate { qoe }.to_op(flow_id);
+            let handler: PendingHandler = Box::new(move |response, _| {
+                let _ = resp.send(or_unavailable(response));
+            });
+            Some((req, handler))
+        }
+    }
+}
+
+/// Connect the served connection `id` again to a restarted manager. The socket is
+/// switched back to its CCA first, and falls back again if the manager refuses it.
+fn reenroll_request(
+    id: u64,
+    conn: &OwnedConn,
+    pid: i32,
+    fallback: &Fallback,
+) -> Option<(Operation, PendingHandler)> {
+    if let Err(e) = set_tcp_congestion(conn.sk.as_raw_fd(), &conn.tcp_ca) {
+        tracing::warn!(target: "sender:manager", "Connection {} stays on {}: {}", id, fallback.tcp_ca, e);
+        return None;
+    }
+    let req = FlowOperation::Connect {
+        pid,
+        obj_id: Some(conn.obj_id),
+        sk_fd: conn.sk.as_raw_fd(),
+        default_app_info: conn.default_app_info,
+        tag: None,
+    }
+    .to_op(0);
+    let sk = conn.sk.clone();
+    let fallback = fallback.clone();
+    let handler: PendingHandler = Box::new(move |response, flow_id_map| match response {
+        Some(Ok(r)) => {
+            let remote_flow_id = read_be_u32(&mut r.as_ref());
+            flow_id_map.insert(id, remote_flow_id);
+            fallback.stats.reenrolled.fetch_add(1, Ordering::Relaxed);
+        }
+        response => {
+            tracing::warn!(target: "sender:manager", "Failed to re-enroll connection {}: {}", id, or_unavailable(response).unwrap_err());
+            let _ = fallback.apply(id, sk.as_raw_fd());
+        }
+    });
+    Some((req, handler))
+}
+
+type ManagerReader = FramedRead<OwnedReadHalf, LengthDelimitedCodec>;
+type ManagerWriter = FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>;
+
+/// Connect to manager at `sock_path` and try to switch the connection to `encoding`. The
+/// connection stays in JSON if manager does not support it, the encoding in use is returned.
+async fn connect_manager(
+    sock_path: &str,
+    encoding: Encoding,
+) -> std::result::Result<(ManagerReader, ManagerWriter, Encoding), String> {
+    let stream = UnixStream::connect(sock_path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (rh, wh) = stream.into_split();
     let mut reader = LengthDelimitedCodec::builder()
         .length_field_offset(0) // default value
         .length_field_type::<u32>()
@@ -91,153 +401,814 @@ pub async fn manager_ipc(mut rx: Receiver<(u64, ClientIpcOperation)>) {
     let mut writer = LengthDelimitedCodec::builder()
         .length_field_type::<u32>()
         .new_write(wh);
+    if encoding != Encoding::Json {
+        let req: Operation = ManagerOperation::SetEncoding {
+            encoding,
+            version: BINARY_VERSION,
+        }
+        .into();
+        let req_bytes = serde_json::to_vec(&req).map_err(|e| e.to_string())?;
+        writer
+            .send(req_bytes.into())
+            .await
+            .map_err(|e| e.to_string())?;
+        let resp_bytes = reader
+            .next()
+            .await
+            .ok_or_else(|| "Manager closed the connection".to_string())?
+            .map_err(|e| e.to_string())?;
+        let response: std::result::Result<Vec<u8>, String> =
+            serde_json::from_slice(resp_bytes.as_ref()).map_err(|e| e.to_string())?;
+        if let Err(e) = response {
+            tracing::warn!(target: "sender:manager", "Manager refused {} encoding, keep json: {}", encoding, e);
+            return Ok((reader, writer, Encoding::Json));
+        }
+    }
+    Ok((reader, writer, encoding))
+}
+
+/// Why the relay to manager stopped.
+enum RelayEnd {
+    Shutdown,
+    LinkLost,
+}
+
+/// Relay client operations to manager.
+///
+/// The link to manager is established again with backoff whenever it is lost, and the
+/// connections still open are enrolled again once it is back. Meanwhile they run the
+/// kernel CCA `fallback_tcp_ca`, and their connects are answered once they do.
+/// The link state is logged and kept in `stats`.
+pub async fn manager_ipc(
+    mut rx: Receiver<(u64, ClientIpcOperation)>,
+    config: ManagerLinkConfig,
+    stats: Arc<ManagerLinkStats>,
+) {
     let pid = std::process::id() as i32;
     tracing::debug!(target: "sender:manager", "sender manager pid: {}", pid);
-    let mut flow_id_map = HashMap::new();
+    let fallback = Fallback {
+        tcp_ca: config.fallback_tcp_ca.clone(),
+        stats: stats.clone(),
+    };
+    let mut owned: HashMap<u64, OwnedConn> = HashMap::new();
+    let mut backoff = config.min_backoff;
     loop {
-        let (id, op) = match rx.recv().await {
-            None => break,
-            Some((id, op)) => (id, op),
-        };
-        match op {
-            ClientIpcOperation::Load { .. } => {
-                continue;
+        match connect_manager(&config.sock_path, config.encoding).await {
+            Ok((reader, writer, encoding)) => {
+                stats.up.store(true, Ordering::Relaxed);
+                stats.connects.fetch_add(1, Ordering::Relaxed);
+                tracing::info!(target: "sender:manager", "Manager link up, re-enroll {} connections; {}", owned.len(), stats);
+                backoff = config.min_backoff;
+                let end = relay(
+                    &mut rx, reader, writer, &mut owned, pid, encoding, &fallback,
+                )
+                .await;
+                stats.up.store(false, Ordering::Relaxed);
+                if let RelayEnd::Shutdown = end {
+                    return;
+                }
+                tracing::warn!(target: "sender:manager", "Manager link down, {} connections fall back to {}; {}", owned.len(), fallback.tcp_ca, stats);
             }
-            ClientIpcOperation::Shutdown => {
-                break;
+            Err(e) => {
+                tracing::warn!(target: "sender:manager", "Manager unavailable: {}, retry in {:?}", e, backoff);
             }
-            ClientIpcOperation::MapUpdate {
-                obj_id,
-                map_name,
-                val,
-                flag,
-                resp,
-            } => {
-                if obj_id == 0 {
-                    resp.send(Ok(())).unwrap();
-                    continue;
-                }
-                let flow_id = *flow_id_map.get(&id).unwrap_or(&0);
-                let req = FlowOperation::SkStgMapUpdate {
-                    map_name,
-                    val: Vec::from(val.as_bytes()),
-                    flag: flag.bits(),
-                }
-                .to_op(flow_id);
-                let req_bytes = serde_json::to_vec(&req).map(Into::into).unwrap();
-                writer.send(req_bytes).await.unwrap();
-                let resp_bytes = reader.next().await.unwrap().unwrap();
-                let response: std::result::Result<Vec<u8>, String> =
-                    serde_json::from_slice(resp_bytes.as_ref()).unwrap();
-                match response {
-                    Ok(_) => {
-                        resp.send(Ok(())).unwrap();
-                    }
-                    Err(e) => {
-                        resp.send(Err(e)).unwrap();
+        }
+        // Serve the connections with the fallback CCA until the next attempt
+        let retry = tokio::time::sleep(backoff);
+        tokio::pin!(retry);
+        let mut no_flows = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                op = rx.recv() => {
+                    let (id, op) = match op {
+                        None | Some((_, ClientIpcOperation::Shutdown)) => return,
+                        Some((id, op)) => (id, op),
+                    };
+                    track(&mut owned, id, &op);
+                    if let Some((_, handler)) = prepare_request(id, op, pid, &no_flows, &fallback) {
+                        handler(None, &mut no_flows);
                     }
                 }
             }
-            ClientIpcOperation::MapLookup {
-                obj_id,
-                map_name,
-                resp,
-            } => {
-                if obj_id == 0 {
-                    resp.send(Ok(Vec::new())).unwrap();
-                    continue;
-                }
-                let flow_id = *flow_id_map.get(&id).unwrap_or(&0);
-                let req = FlowOperation::SkStgMapLookup { map_name }.to_op(flow_id);
-                let req_bytes = serde_json::to_vec(&req).map(Into::into).unwrap();
-                writer.send(req_bytes).await.unwrap();
-                let resp_bytes = reader.next().await.unwrap().unwrap();
-                let response: std::result::Result<Vec<u8>, String> =
-                    serde_json::from_slice(resp_bytes.as_ref()).unwrap();
-                match response {
-                    Ok(val) => {
-                        resp.send(Ok(val)).unwrap();
+        }
+        backoff = (backoff * 2).min(config.max_backoff);
+    }
+}
+
+/// Relay operations over one link to manager, until it is lost or shut down.
+///
+/// Each request is tagged with a correlation id, so that operations of different
+/// connections are kept in flight at the same time and answered out of order.
+/// The operations of a connection whose connect is still in flight are held back
+/// until the manager answers it, so that a server need not wait for the connect.
+/// The relay subscribes to the events of manager, and switches its flows to the
+/// fallback CCA of manager and disconnects them when it shuts down.
+async fn relay(
+    rx: &mut Receiver<(u64, ClientIpcOperation)>,
+    mut reader: ManagerReader,
+    mut writer: ManagerWriter,
+    owned: &mut HashMap<u64, OwnedConn>,
+    pid: i32,
+    encoding: Encoding,
+    fallback: &Fallback,
+) -> RelayEnd {
+    let mut flow_id_map = HashMap::new();
+    // Handlers of the requests in flight, with the connection id and whether it connects
+    let mut pending: HashMap<u64, (u64, bool, PendingHandler)> = HashMap::new();
+    // Operations of the connections whose connect is in flight
+    let mut connecting: HashMap<u64, Vec<ClientIpcOperation>> = HashMap::new();
+    // Connections released by a disconnect held back with them
+    let mut closing: HashMap<u64, OwnedConn> = HashMap::new();
+    let mut req_id = 1_u64;
+    let subscribe_id = req_id;
+    let subscribe: Operation = ManagerOperation::Subscribe.into();
+    let subscribe_bytes = encoding
+        .encode(&subscribe.with_id(subscribe_id))
+        .map(Into::into)
+        .unwrap();
+    if let Err(e) = writer.send(subscribe_bytes).await {
+        tracing::error!(target: "sender:manager", "Failed to subscribe: {}", e);
+        return RelayEnd::LinkLost;
+    }
+    // Requests to send, starting with the connections served by the fallback CCA
+    let mut ready: Vec<(u64, bool, Operation, PendingHandler)> = owned
+        .iter()
+        .filter_map(|(id, conn)| {
+            reenroll_request(*id, conn, pid, fallback).map(|(req, h)| (*id, true, req, h))
+        })
+        .collect();
+    let end = 'relay: loop {
+        let mut queued = std::mem::take(&mut ready).into_iter();
+        while let Some((id, is_connect, req, handler)) = queued.next() {
+            req_id += 1;
+            let req_bytes = encoding
+                .encode(&req.with_id(req_id))
+                .map(Into::into)
+                .unwrap();
+            if is_connect {
+                connecting.insert(id, Vec::new());
+            }
+            pending.insert(req_id, (id, is_connect, handler));
+            if let Err(e) = writer.send(req_bytes).await {
+                tracing::error!(target: "sender:manager", "Failed to send request: {}", e);
+                ready.extend(queued);
+                break 'relay RelayEnd::LinkLost;
+            }
+        }
+        tokio::select! {
+            op = rx.recv() => {
+                let (id, op) = match op {
+                    None | Some((_, ClientIpcOperation::Shutdown)) => break RelayEnd::Shutdown,
+                    Some((id, op)) => (id, op),
+                };
+                let released = track(owned, id, &op);
+                match connecting.get_mut(&id) {
+                    Some(deferred) => {
+                        if let Some(conn) = released {
+                            closing.insert(id, conn);
+                        }
+                        deferred.push(op);
                     }
-                    Err(e) => {
-                        resp.send(Err(e)).unwrap();
+                    None => {
+                        let is_connect = matches!(op, ClientIpcOperation::Connect { .. });
+                        if let Some((req, handler)) = prepare_request(id, op, pid, &flow_id_map, fallback) {
+                            ready.push((id, is_connect, req, hold(released, handler)));
+                        }
                     }
                 }
             }
-            ClientIpcOperation::Connect {
-                obj_id,
-                sk_raw_fd,
-                default_app_info,
-                resp,
-            } => {
-                if obj_id == 0 {
-                    resp.send(Ok(())).unwrap();
-                    continue;
-                }
-                let req = FlowOperation::Connect {
-                    pid,
-                    obj_id,
-                    sk_fd: sk_raw_fd,
-                    default_app_info,
-                }
-                .to_op(0);
-                let req_bytes = serde_json::to_vec(&req).map(Into::into).unwrap();
-                writer.send(req_bytes).await.unwrap();
-                let resp_bytes = reader.next().await.unwrap().unwrap();
-                let response: std::result::Result<Vec<u8>, String> =
-                    serde_json::from_slice(resp_bytes.as_ref()).unwrap();
-                match response {
-                    Ok(r) => {
-                        let remote_flow_id = read_be_u32(&mut r.as_ref());
-                        flow_id_map.insert(id, remote_flow_id);
-                        resp.send(Ok(())).unwrap();
+            frame = reader.next() => {
+                let resp_bytes = match frame {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(e)) => {
+                        tracing::error!(target: "sender:manager", "Failed to receive response: {}", e);
+                        break RelayEnd::LinkLost;
                     }
+                    None => {
+                        tracing::error!(target: "sender:manager", "Manager closed the connection");
+                        break RelayEnd::LinkLost;
+                    }
+                };
+                let response: ManagerResponse = match encoding.decode(resp_bytes.as_ref()) {
+                    Ok(r) => r,
                     Err(e) => {
-                        resp.send(Err(e)).unwrap();
+                        tracing::error!(target: "sender:manager", "Invalid response: {}", e);
+                        continue;
+                    }
+                };
+                if response.id == subscribe_id {
+                    let event = response
+                        .resp
+                        .and_then(|r| serde_json::from_slice(&r).map_err(|e| e.to_string()));
+                    match event {
+                        Ok(ManagerEvent::Shutdown { fallback_tcp_ca, drain_ms }) => {
+                            tracing::info!(target: "sender:manager", "Manager shuts down, {} flows fall back to {} and disconnect within {} ms", flow_id_map.len(), fallback_tcp_ca, drain_ms);
+                            let fallback = Fallback {
+                                tcp_ca: fallback_tcp_ca,
+                                stats: fallback.stats.clone(),
+                            };
+                            for (id, flow_id) in flow_id_map.drain() {
+                                if let Some(conn) = owned.get(&id) {
+                                    let _ = fallback.apply(id, conn.sk.as_raw_fd());
+                                }
+                                let req = FlowOperation::Disconnect.to_op(flow_id);
+                                ready.push((id, false, req, Box::new(|_, _| {})));
+                            }
+                        }
+                        Err(e) => {
+                            tracing::warn!(target: "sender:manager", "Subscription refused: {}", e)
+                        }
                     }
-                }
-            }
-            ClientIpcOperation::Disconnect { obj_id, resp } => {
-                if obj_id == 0 {
-                    resp.send(Ok(())).unwrap();
                     continue;
                 }
-                let flow_id = *flow_id_map.get(&id).unwrap_or(&0);
-                let req = FlowOperation::Disconnect {}.to_op(flow_id);
-                let req_bytes = serde_json::to_vec(&req).map(Into::into).unwrap();
-                writer.send(req_bytes).await.unwrap();
-                let resp_bytes = reader.next().await.unwrap().unwrap();
-                let response: std::result::Result<Vec<u8>, String> =
-                    serde_json::from_slice(resp_bytes.as_ref()).unwrap();
-                match response {
-                    Ok(_) => {
-                        resp.send(Ok(())).unwrap();
+                match pending.remove(&response.id) {
+                    Some((id, is_connect, handler)) => {
+                        let refused = is_connect && response.resp.is_err();
+                        handler(Some(response.resp), &mut flow_id_map);
+                        if refused {
+                            // Not enrolled again with the next manager
+                            owned.remove(&id);
+                        }
+                        if is_connect {
+                            let deferred = connecting.remove(&id).unwrap_or_default();
+                            let mut released = closing.remove(&id);
+                            for op in deferred {
+                                if !flow_id_map.contains_key(&id) {
+                                    answer_locally(op, "Flow failed to connect".to_string());
+                                    continue;
+                                }
+                                let is_connect = matches!(op, ClientIpcOperation::Connect { .. });
+                                let released = match op {
+                                    ClientIpcOperation::Disconnect { .. } => released.take(),
+                                    _ => None,
+                                };
+                                if let Some((req, handler)) = prepare_request(id, op, pid, &flow_id_map, fallback) {
+                                    ready.push((id, is_connect, req, hold(released, handler)));
+                                }
+                            }
+                        }
                     }
-                    Err(e) => {
-                        resp.send(Err(e)).unwrap();
+                    None => {
+                        tracing::warn!(target: "sender:manager", "Unknown response id {}", response.id)
                     }
                 }
             }
-            ClientIpcOperation::QoEUpdate { obj_id, qoe, resp } => {
-                if obj_id == 0 {
-                    resp.send(Ok(Vec::new())).unwrap();
-                    continue;
+        }
+    };
+    // Fail the requests that will never be answered, connects fall back
+    for (_, (_, _, handler)) in pending.drain() {
+        handler(None, &mut flow_id_map);
+    }
+    for (_, _, _, handler) in ready {
+        handler(None, &mut flow_id_map);
+    }
+    for op in connecting.into_values().flatten() {
+        answer_locally(op, MANAGER_UNAVAILABLE.to_string());
+    }
+    if let RelayEnd::LinkLost = end {
+        // The flows of the lost manager are gone with it
+        for id in flow_id_map.keys() {
+            if let Some(conn) = owned.get(id) {
+                let _ = fallback.apply(*id, conn.sk.as_raw_fd());
+            }
+        }
+    }
+    end
+}
+
+/// Answer an operation that can not be relayed to manager. Disconnecting a flow that
+/// never connected succeeds.
+fn answer_locally(op: ClientIpcOperation, err: String) {
+    match op {
+        ClientIpcOperation::Load { resp, .. } => {
+            let _ = resp.send(Err(err));
+        }
+        ClientIpcOperation::MapUpdate { resp, .. } => {
+            let _ = resp.send(Err(err));
+        }
+        ClientIpcOperation::MapLookup { resp, .. } => {
+            let _ = resp.send(Err(err));
+        }
+        ClientIpcOperation::Connect { resp, .. } => {
+            let _ = resp.send(Err(err));
+        }
+        ClientIpcOperation::Disconnect { resp, .. } => {
+            let _ = resp.send(Ok(()));
+        }
+        ClientIpcOperation::QoEUpdate { resp, .. } => {
+            let _ = resp.send(Err(err));
+        }
+        ClientIpcOperation::Shutdown => {}
+    }
+}
+
+/// Socket of a connection enrolled with manager.
+#[derive(Debug, Clone, Copy)]
+pub struct FlowSocket<'a> {
+    pub fd: i32,
+    pub obj_id: u32,
+    pub tcp_ca: &'a [u8],
+    /// Value of the tunable in `sk_stg_map` instead of the one of the policy.
+    pub default_app_info: Option<u64>,
+}
+
+/// Inform manager of the new connection `id` on `socket`.
+pub async fn connect_flow(
+    manager_tx: &Sender<(u64, ClientIpcOperation)>,
+    alive_token: &CancellationToken,
+    id: u64,
+    socket: FlowSocket<'_>,
+    async_connect: bool,
+) -> anyhow::Result<()> {
+    if alive_token.is_cancelled() {
+        return Ok(());
+    }
+    // The socket is open until connect_flow returns
+    let sk = unsafe { BorrowedFd::borrow_raw(socket.fd) }.try_clone_to_owned()?;
+    let (tmp_tx, tmp_rx) = oneshot::channel();
+    manager_tx
+        .send((
+            id,
+            ClientIpcOperation::Connect {
+                obj_id: socket.obj_id,
+                sk: Arc::new(sk),
+                tcp_ca: socket.tcp_ca.to_vec(),
+                default_app_info: socket.default_app_info,
+                resp: tmp_tx,
+            },
+        ))
+        .await
+        .map_err(|_| MortiseError::Custom(MANAGER_UNAVAILABLE.to_string()))?;
+    if async_connect {
+        // Operations of the flow are held back by manager_ipc until it is ready
+        tokio::spawn(async move {
+            match tmp_rx.await {
+                Ok(Ok(())) => {
+                    tracing::debug!(target: "sender:manager", "Flow of connection {} ready", id)
+                }
+                Ok(Err(e)) => {
+                    tracing::warn!(target: "sender:manager", "Connection {} keeps default parameters: {}", id, e)
                 }
-                let flow_id = *flow_id_map.get(&id).unwrap_or(&0);
-                let req = FlowOperation::QoEUpdate { qoe }.to_op(flow_id);
-                let req_bytes = serde_json::to_vec(&req).map(Into::into).unwrap();
-                writer.send(req_bytes).await.unwrap();
-                let resp_bytes = reader.next().await.unwrap().unwrap();
-                let response: std::result::Result<Vec<u8>, String> =
-                    serde_json::from_slice(resp_bytes.as_ref()).unwrap();
-                match response {
-                    Ok(val) => {
-                        resp.send(Ok(val)).unwrap();
+                Err(_) => {}
+            }
+        });
+    } else {
+        tmp_rx
+            .await
+            .unwrap_or_else(|_| Err(MANAGER_UNAVAILABLE.to_string()))
+            .map_err(MortiseError::Custom)?;
+    }
+    Ok(())
+}
+
+/// Inform manager of the disconnection of connection `id`.
+pub async fn disconnect_flow(
+    manager_tx: &Sender<(u64, ClientIpcOperation)>,
+    alive_token: &CancellationToken,
+    id: u64,
+    obj_id: u32,
+) -> anyhow::Result<()> {
+    if alive_token.is_cancelled() {
+        return Ok(());
+    }
+    let (tmp_tx, tmp_rx) = oneshot::channel();
+    manager_tx
+        .send((
+            id,
+            ClientIpcOperation::Disconnect {
+                obj_id,
+                resp: tmp_tx,
+            },
+        ))
+        .await
+        .map_err(|_| MortiseError::Custom(MANAGER_UNAVAILABLE.to_string()))?;
+    tmp_rx
+        .await
+        .unwrap_or_else(|_| Err(MANAGER_UNAVAILABLE.to_string()))
+        .map_err(MortiseError::Custom)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mortise_common::ManagerRequest;
+    use std::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_util::codec::Framed;
+
+    type FakeManager = Framed<UnixStream, LengthDelimitedCodec>;
+
+    /// Link to a fake manager, which speaks JSON.
+    fn link() -> (ManagerReader, ManagerWriter, FakeManager) {
+        let (client, manager) = UnixStream::pair().unwrap();
+        let (rh, wh) = client.into_split();
+        let codec = *LengthDelimitedCodec::builder().length_field_type::<u32>();
+        (
+            codec.new_read(rh),
+            codec.new_write(wh),
+            codec.new_framed(manager),
+        )
+    }
+
+    async fn recv(manager: &mut FakeManager) -> ManagerRequest {
+        let frame = manager.next().await.unwrap().unwrap();
+        serde_json::from_slice(&frame).unwrap()
+    }
+
+    async fn answer(
+        manager: &mut FakeManager,
+        id: u64,
+        resp: std::result::Result<Vec<u8>, String>,
+    ) {
+        let frame = serde_json::to_vec(&ManagerResponse { id, resp }).unwrap();
+        manager.send(frame.into()).await.unwrap();
+    }
+
+    /// Served connection running `tcp_ca`, with the duplicate of its socket sent to the relay.
+    fn socket(tcp_ca: &str) -> (TcpListener, TcpStream, Arc<OwnedFd>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_tcp_congestion(stream.as_raw_fd(), tcp_ca.as_bytes()).unwrap();
+        let sk = Arc::new(OwnedFd::from(stream.try_clone().unwrap()));
+        (listener, stream, sk)
+    }
+
+    fn tcp_ca(stream: &TcpStream) -> Vec<u8> {
+        let tcp_ca = socket2::SockRef::from(stream).tcp_congestion().unwrap();
+        tcp_ca.split(|b| *b == 0).next().unwrap().to_vec()
+    }
+
+    fn fallback() -> Fallback {
+        Fallback {
+            tcp_ca: "reno".to_string(),
+            stats: Arc::default(),
+        }
+    }
+
+    fn connect(
+        sk: &Arc<OwnedFd>,
+    ) -> (
+        ClientIpcOperation,
+        oneshot::Receiver<std::result::Result<(), String>>,
+    ) {
+        let (resp, rx) = oneshot::channel();
+        let op = ClientIpcOperation::Connect {
+            obj_id: 1,
+            sk: sk.clone(),
+            tcp_ca: b"cubic".to_vec(),
+            default_app_info: None,
+            resp,
+        };
+        (op, rx)
+    }
+
+    fn disconnect() -> (
+        ClientIpcOperation,
+        oneshot::Receiver<std::result::Result<(), String>>,
+    ) {
+        let (resp, rx) = oneshot::channel();
+        (ClientIpcOperation::Disconnect { obj_id: 1, resp }, rx)
+    }
+
+    /// Answer the subscription and the connect of the relay, returns the id of the subscription.
+    async fn accept_connect(manager: &mut FakeManager, flow_id: u32) -> u64 {
+        let subscribe = recv(manager).await;
+        assert!(matches!(
+            subscribe.op,
+            Operation::Manager(ManagerOperation::Subscribe)
+        ));
+        let req = recv(manager).await;
+        assert!(matches!(
+            req.op,
+            Operation::Flow {
+                op: FlowOperation::Connect {
+                    obj_id: Some(1),
+                    ..
+                },
+                ..
+            }
+        ));
+        answer(manager, req.id, Ok(flow_id.to_be_bytes().to_vec())).await;
+        subscribe.id
+    }
+
+    #[tokio::test]
+    async fn test_relay_link_lost() {
+        let (reader, writer, mut manager) = link();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_listener, stream, sk) = socket("cubic");
+        let fallback = fallback();
+        let mut owned = HashMap::new();
+        let (end, _) = tokio::join!(
+            relay(
+                &mut rx,
+                reader,
+                writer,
+                &mut owned,
+                0,
+                Encoding::Json,
+                &fallback
+            ),
+            async move {
+                let (op, connected) = connect(&sk);
+                tx.send((1, op)).await.unwrap();
+                recv(&mut manager).await;
+                recv(&mut manager).await;
+                // The link is lost with the connect in flight
+                drop(manager);
+                assert_eq!(connected.await.unwrap(), Ok(()));
+            }
+        );
+        assert!(matches!(end, RelayEnd::LinkLost));
+        assert_eq!(tcp_ca(&stream), b"reno");
+        assert_eq!(fallback.stats.fallbacks.load(Ordering::Relaxed), 1);
+        assert!(owned.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn test_relay_reenroll() {
+        let (reader, writer, mut manager) = link();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_listener, stream, sk) = socket("reno");
+        let fallback = fallback();
+        let mut owned = HashMap::new();
+        owned.insert(
+            1,
+            OwnedConn {
+                obj_id: 1,
+                sk,
+                tcp_ca: b"cubic".to_vec(),
+                default_app_info: None,
+            },
+        );
+        let stream = &stream;
+        let (end, _) = tokio::join!(
+            relay(
+                &mut rx,
+                reader,
+                writer,
+                &mut owned,
+                0,
+                Encoding::Json,
+                &fallback
+            ),
+            async move {
+                accept_connect(&mut manager, 7).await;
+                assert_eq!(tcp_ca(stream), b"cubic");
+                let (op, disconnected) = disconnect();
+                tx.send((1, op)).await.unwrap();
+                let req = recv(&mut manager).await;
+                assert!(matches!(
+                    req.op,
+                    Operation::Flow {
+                        flow_id: 7,
+                        op: FlowOperation::Disconnect
                     }
-                    Err(e) => {
-                        resp.send(Err(e)).unwrap();
+                ));
+                answer(&mut manager, req.id, Ok(Vec::new())).await;
+                assert_eq!(disconnected.await.unwrap(), Ok(()));
+                tx.send((0, ClientIpcOperation::Shutdown)).await.unwrap();
+                assert!(manager.next().await.is_none());
+            }
+        );
+        assert!(matches!(end, RelayEnd::Shutdown));
+        assert_eq!(fallback.stats.reenrolled.load(Ordering::Relaxed), 1);
+        assert!(owned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_connect_refused() {
+        let (reader, writer, mut manager) = link();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_listener, _stream, sk) = socket("cubic");
+        let fallback = fallback();
+        let mut owned = HashMap::new();
+        let (end, _) = tokio::join!(
+            relay(
+                &mut rx,
+                reader,
+                writer,
+                &mut owned,
+                0,
+                Encoding::Json,
+                &fallback
+            ),
+            async move {
+                let (op, connected) = connect(&sk);
+                tx.send((1, op)).await.unwrap();
+                recv(&mut manager).await;
+                let req = recv(&mut manager).await;
+                answer(&mut manager, req.id, Err("refused".to_string())).await;
+                assert_eq!(connected.await.unwrap(), Err("refused".to_string()));
+                tx.send((0, ClientIpcOperation::Shutdown)).await.unwrap();
+                assert!(manager.next().await.is_none());
+            }
+        );
+        assert!(matches!(end, RelayEnd::Shutdown));
+        assert!(owned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_manager_shutdown() {
+        let (reader, writer, mut manager) = link();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_listener, stream, sk) = socket("cubic");
+        let fallback = fallback();
+        let mut owned = HashMap::new();
+        let stream = &stream;
+        let (end, _) = tokio::join!(
+            relay(
+                &mut rx,
+                reader,
+                writer,
+                &mut owned,
+                0,
+                Encoding::Json,
+                &fallback
+            ),
+            async move {
+                let (op, connected) = connect(&sk);
+                tx.send((1, op)).await.unwrap();
+                let subscribe_id = accept_connect(&mut manager, 5).await;
+                assert_eq!(connected.await.unwrap(), Ok(()));
+                let event = ManagerEvent::Shutdown {
+                    fallback_tcp_ca: "reno".to_string(),
+                    drain_ms: 100,
+                };
+                answer(
+                    &mut manager,
+                    subscribe_id,
+                    Ok(serde_json::to_vec(&event).unwrap()),
+                )
+                .await;
+                let req = recv(&mut manager).await;
+                assert!(matches!(
+                    req.op,
+                    Operation::Flow {
+                        flow_id: 5,
+                        op: FlowOperation::Disconnect
                     }
-                }
+                ));
+                assert_eq!(tcp_ca(stream), b"reno");
+                answer(&mut manager, req.id, Ok(Vec::new())).await;
+                // The flow is not disconnected twice
+                let (op, disconnected) = disconnect();
+                tx.send((1, op)).await.unwrap();
+                let req = recv(&mut manager).await;
+                assert!(matches!(req.op, Operation::Flow { flow_id: 0, .. }));
+                answer(&mut manager, req.id, Ok(Vec::new())).await;
+                assert_eq!(disconnected.await.unwrap(), Ok(()));
+                tx.send((0, ClientIpcOperation::Shutdown)).await.unwrap();
+                assert!(manager.next().await.is_none());
             }
+        );
+        assert!(matches!(end, RelayEnd::Shutdown));
+    }
+
+    type Answer<T> = oneshot::Receiver<std::result::Result<T, String>>;
+
+    /// Send a map update, a QoE update and a disconnect of connection 1, and wait for the
+    /// relay to take them.
+    async fn send_deferred(
+        tx: &Sender<(u64, ClientIpcOperation)>,
+    ) -> (Answer<()>, Answer<Vec<u8>>, Answer<()>) {
+        let (resp, updated) = oneshot::channel();
+        let op = ClientIpcOperation::MapUpdate {
+            obj_id: 1,
+            map_name: "sk_stg_map".to_string(),
+            val: AppInfo::default(),
+            flag: BpfMapFlags::ANY,
+            resp,
+        };
+        tx.send((1, op)).await.unwrap();
+        let (resp, reported) = oneshot::channel();
+        let qoe = FrameQoE {
+            server_send: 0,
+            client_recv: 0,
+            server_recv: 0,
+            size: 0,
+            frame_interval: Duration::from_millis(10),
+            frame_id: 0,
+        };
+        let op = ClientIpcOperation::QoEUpdate {
+            obj_id: 1,
+            qoe,
+            resp,
+        };
+        tx.send((1, op)).await.unwrap();
+        let (op, disconnected) = disconnect();
+        tx.send((1, op)).await.unwrap();
+        while tx.capacity() < tx.max_capacity() {
+            tokio::task::yield_now().await;
         }
+        (updated, reported, disconnected)
+    }
+
+    #[tokio::test]
+    async fn test_relay_deferred_until_connected() {
+        let (reader, writer, mut manager) = link();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_listener, _stream, sk) = socket("cubic");
+        let fallback = fallback();
+        let mut owned = HashMap::new();
+        let (end, _) = tokio::join!(
+            relay(
+                &mut rx,
+                reader,
+                writer,
+                &mut owned,
+                0,
+                Encoding::Json,
+                &fallback
+            ),
+            async move {
+                let (op, connected) = connect(&sk);
+                tx.send((1, op)).await.unwrap();
+                recv(&mut manager).await;
+                let req = recv(&mut manager).await;
+                let (updated, reported, disconnected) = send_deferred(&tx).await;
+                // Held back until the flow id is known
+                answer(&mut manager, req.id, Ok(3_u32.to_be_bytes().to_vec())).await;
+                assert_eq!(connected.await.unwrap(), Ok(()));
+                let update = recv(&mut manager).await;
+                assert!(matches!(
+                    update.op,
+                    Operation::Flow {
+                        flow_id: 3,
+                        op: FlowOperation::SkStgMapUpdate { .. }
+                    }
+                ));
+                let report = recv(&mut manager).await;
+                assert!(matches!(
+                    report.op,
+                    Operation::Flow {
+                        flow_id: 3,
+                        op: FlowOperation::QoEUpdate { .. }
+                    }
+                ));
+                let close = recv(&mut manager).await;
+                assert!(matches!(
+                    close.op,
+                    Operation::Flow {
+                        flow_id: 3,
+                        op: FlowOperation::Disconnect
+                    }
+                ));
+                answer(&mut manager, update.id, Ok(Vec::new())).await;
+                answer(&mut manager, report.id, Ok(Vec::new())).await;
+                assert_eq!(updated.await.unwrap(), Ok(()));
+                assert_eq!(reported.await.unwrap(), Ok(Vec::new()));
+                // The socket is held until the disconnect is answered
+                assert_eq!(Arc::strong_count(&sk), 2);
+                answer(&mut manager, close.id, Ok(Vec::new())).await;
+                assert_eq!(disconnected.await.unwrap(), Ok(()));
+                assert_eq!(Arc::strong_count(&sk), 1);
+                tx.send((0, ClientIpcOperation::Shutdown)).await.unwrap();
+                assert!(manager.next().await.is_none());
+            }
+        );
+        assert!(matches!(end, RelayEnd::Shutdown));
+        assert!(owned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_deferred_connect_refused() {
+        let (reader, writer, mut manager) = link();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (_listener, _stream, sk) = socket("cubic");
+        let fallback = fallback();
+        let mut owned = HashMap::new();
+        let (end, _) = tokio::join!(
+            relay(
+                &mut rx,
+                reader,
+                writer,
+                &mut owned,
+                0,
+                Encoding::Json,
+                &fallback
+            ),
+            async move {
+                let (op, connected) = connect(&sk);
+                tx.send((1, op)).await.unwrap();
+                recv(&mut manager).await;
+                let req = recv(&mut manager).await;
+                let (updated, reported, disconnected) = send_deferred(&tx).await;
+                answer(&mut manager, req.id, Err("refused".to_string())).await;
+                assert_eq!(connected.await.unwrap(), Err("refused".to_string()));
+                // Answered without manager
+                let err = "Flow failed to connect".to_string();
+                assert_eq!(updated.await.unwrap(), Err(err.clone()));
+                assert_eq!(reported.await.unwrap(), Err(err));
+                assert_eq!(disconnected.await.unwrap(), Ok(()));
+                assert_eq!(Arc::strong_count(&sk), 1);
+                tx.send((0, ClientIpcOperation::Shutdown)).await.unwrap();
+                assert!(manager.next().await.is_none());
+            }
+        );
+        assert!(matches!(end, RelayEnd::Shutdown));
+        assert!(owned.is_empty());
     }
 }
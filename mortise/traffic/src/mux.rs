@@ -0,0 +1,181 @@
+//! Multiplexed responses of the framed protocol.
+//!
+//! A client connecting with `mux` keeps several requests in flight, each answered on
+//! its own stream named by the request id. The server cuts the bodies into chunks and
+//! interleaves the chunks of the streams in the order of a [`MuxScheduler`], so that a
+//! large response does not hold back the following small ones. The head of a response
+//! is sent right before its first chunk, which is also when the hint of its request is
+//! pushed into the CCA.
+
+use crate::body::CHUNK_SIZE;
+use crate::{RequestHint, ServerResponse};
+use clap::ValueEnum;
+use speedy::{Readable, Writable};
+use std::collections::VecDeque;
+
+/// Frame of a multiplexed response.
+#[derive(Debug, Clone, Readable, Writable)]
+pub enum MuxFrame {
+    /// First frame of the stream `resp.id`, the stream ends with it if `resp.size` is 0.
+    Head(ServerResponse),
+    /// `len` bytes of the body of stream `id`, written after the frame.
+    Data { id: u32, len: u32 },
+}
+
+/// Order of the chunks of the streams in flight.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[clap(rename_all = "kebab-case")]
+pub enum MuxScheduler {
+    /// Streams are sent one after another, as without multiplexing.
+    #[default]
+    Fifo,
+    /// One chunk of each stream in turn.
+    RoundRobin,
+    /// Chunks of the stream with the fewest bytes left first.
+    Srf,
+}
+
+#[derive(Debug)]
+struct MuxStream {
+    head: ServerResponse,
+    hint: Option<RequestHint>,
+    head_sent: bool,
+    sent: u64,
+}
+
+/// Next piece of a stream to send.
+#[derive(Debug)]
+pub enum MuxChunk {
+    /// Head of a stream, with the hint of its request.
+    Head(ServerResponse, Option<RequestHint>),
+    Data {
+        id: u32,
+        offset: u64,
+        len: u32,
+    },
+}
+
+/// Streams in flight of a connection.
+#[derive(Debug, Default)]
+pub struct MuxQueue {
+    scheduler: MuxScheduler,
+    streams: VecDeque<MuxStream>,
+}
+
+impl MuxQueue {
+    pub fn new(scheduler: MuxScheduler) -> Self {
+        MuxQueue {
+            scheduler,
+            streams: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Add the stream answering a request with `hint`, in arrival order.
+    pub fn push(&mut self, head: ServerResponse, hint: Option<RequestHint>) {
+        self.streams.push_back(MuxStream {
+            head,
+            hint,
+            head_sent: false,
+            sent: 0,
+        });
+    }
+
+    /// Pick the next piece to send, the stream ends with its last one.
+    pub fn next_chunk(&mut self) -> Option<MuxChunk> {
+        let idx = match self.scheduler {
+            // Round-robin moves the served stream to the back
+            MuxScheduler::Fifo | MuxScheduler::RoundRobin => 0,
+            MuxScheduler::Srf => (0..self.streams.len())
+                .min_by_key(|&idx| self.streams[idx].head.size - self.streams[idx].sent)?,
+        };
+        let stream = self.streams.get_mut(idx)?;
+        if !stream.head_sent {
+            stream.head_sent = true;
+            let head = stream.head.clone();
+            let hint = stream.hint;
+            if head.size == 0 {
+                self.streams.remove(idx);
+            }
+            return Some(MuxChunk::Head(head, hint));
+        }
+        let id = stream.head.id;
+        let offset = stream.sent;
+        let len = (stream.head.size - offset).min(CHUNK_SIZE as u64);
+        stream.sent += len;
+        if stream.sent == stream.head.size {
+            self.streams.remove(idx);
+        } else if self.scheduler == MuxScheduler::RoundRobin {
+            self.streams.rotate_left(1);
+        }
+        Some(MuxChunk::Data {
+            id,
+            offset,
+            len: len as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MuxChunk, MuxQueue, MuxScheduler};
+    use crate::body::CHUNK_SIZE;
+    use crate::{RequestHint, ServerResponse};
+
+    /// Queue of streams 1, 2 and 3 of 3, 1 and 2 chunks, requested with a deadline of
+    /// 10 times their id.
+    fn queue(scheduler: MuxScheduler) -> MuxQueue {
+        let mut queue = MuxQueue::new(scheduler);
+        for (id, chunks) in [(1, 3), (2, 1), (3, 2)] {
+            let head = ServerResponse {
+                id,
+                client_send: 0,
+                server_recv: 0,
+                size: chunks * CHUNK_SIZE as u64,
+            };
+            let hint = RequestHint::new(id as u64 * 10, 0, 0).unwrap();
+            queue.push(head, Some(hint));
+        }
+        queue
+    }
+
+    fn order(scheduler: MuxScheduler) -> Vec<u32> {
+        let mut queue = queue(scheduler);
+        let mut ids = Vec::new();
+        while let Some(chunk) = queue.next_chunk() {
+            if let MuxChunk::Data { id, .. } = chunk {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn test_mux_schedulers() {
+        assert_eq!(order(MuxScheduler::Fifo), vec![1, 1, 1, 2, 3, 3]);
+        assert_eq!(order(MuxScheduler::RoundRobin), vec![1, 2, 3, 1, 3, 1]);
+        assert_eq!(order(MuxScheduler::Srf), vec![2, 3, 3, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_mux_hints() {
+        // The hint of a stream comes with its head, right before its first chunk
+        let mut queue = queue(MuxScheduler::Srf);
+        let mut heads = Vec::new();
+        let mut last_head = None;
+        while let Some(chunk) = queue.next_chunk() {
+            match chunk {
+                MuxChunk::Head(head, hint) => {
+                    heads.push((head.id, hint.unwrap().deadline_ms));
+                    last_head = Some(head.id);
+                }
+                MuxChunk::Data { id, offset: 0, .. } => assert_eq!(last_head, Some(id)),
+                MuxChunk::Data { .. } => {}
+            }
+        }
+        assert_eq!(heads, [(2, 20), (3, 30), (1, 10)]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Real-time interactive frames, like the video of a call.
+//!
+//! The server encodes a frame every frame interval at the bitrate of a
+//! [`FrameRateController`] and sends it as a `DataChunk`. The client acknowledges each
+//! frame with a `ChunkAck`, counting the frames arriving after their deadline as dropped.
+//! From the acks, the server feeds the QoE of the frames to manager and the controller
+//! adapts the bitrate of the encoder.
+
+use crate::transport::{RateController, SendChunkInfo};
+use mortise_common::qoe::FrameQoE;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Interval of the bitrate adaptation.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+const MIN_BITRATE_KBPS: f64 = 100.0;
+/// Bitrate cut when a frame of the last interval missed its deadline.
+const DECREASE: f64 = 0.85;
+/// Bitrate growth when the frames of the last interval arrived well before their deadline.
+const INCREASE: f64 = 1.05;
+
+/// One-way delay of an acknowledged frame.
+pub fn one_way_delay(qoe: &FrameQoE) -> Duration {
+    Duration::from_nanos(qoe.client_recv.saturating_sub(qoe.server_send))
+}
+
+/// Encoder of the frames, adapting its bitrate up to the target from the acks.
+pub struct FrameRateController {
+    frame_interval: Duration,
+    deadline: Duration,
+    target_kbps: f64,
+    bitrate_kbps: f64,
+    end: Instant,
+    acks: mpsc::UnboundedReceiver<FrameQoE>,
+}
+
+impl FrameRateController {
+    /// Frames of `fps` for `duration`, whose QoE of the acks arrive on `acks`.
+    pub fn new(
+        fps: u64,
+        target_kbps: u64,
+        deadline: Duration,
+        duration: Duration,
+        acks: mpsc::UnboundedReceiver<FrameQoE>,
+    ) -> Self {
+        FrameRateController {
+            frame_interval: Duration::from_micros(1_000_000 / fps.max(1)),
+            deadline,
+            target_kbps: target_kbps as f64,
+            bitrate_kbps: target_kbps as f64,
+            end: Instant::now() + duration,
+            acks,
+        }
+    }
+
+    pub fn bitrate_kbps(&self) -> f64 {
+        self.bitrate_kbps
+    }
+
+    /// Bytes of a frame at the current bitrate.
+    pub fn frame_bytes(&self) -> u64 {
+        (self.bitrate_kbps * 1000.0 / 8.0 * self.frame_interval.as_secs_f64()) as u64
+    }
+}
+
+#[async_trait::async_trait]
+impl RateController for FrameRateController {
+    async fn next_chunk(&mut self, id: u64) -> Option<SendChunkInfo> {
+        if Instant::now() >= self.end {
+            return None;
+        }
+        Some(SendChunkInfo {
+            id,
+            data_bytes: self.frame_bytes(),
+        })
+    }
+
+    fn get_chunk_interval(&self) -> &Duration {
+        &self.frame_interval
+    }
+
+    fn get_sample_interval(&self) -> &Duration {
+        &SAMPLE_INTERVAL
+    }
+
+    fn update_rate_sample(&mut self) {
+        let mut acked = 0;
+        let mut late = false;
+        let mut max_delay = Duration::ZERO;
+        while let Ok(qoe) = self.acks.try_recv() {
+            let delay = one_way_delay(&qoe);
+            acked += 1;
+            late |= delay > self.deadline;
+            max_delay = max_delay.max(delay);
+        }
+        if late {
+            self.bitrate_kbps = (self.bitrate_kbps * DECREASE).max(MIN_BITRATE_KBPS);
+        } else if acked > 0 && max_delay < self.deadline / 2 {
+            self.bitrate_kbps = (self.bitrate_kbps * INCREASE).min(self.target_kbps);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameRateController;
+    use crate::transport::RateController;
+    use mortise_common::qoe::FrameQoE;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn ack(delay_ms: u64) -> FrameQoE {
+        FrameQoE {
+            server_send: 0,
+            client_recv: delay_ms * 1_000_000,
+            server_recv: delay_ms * 2_000_000,
+            size: 1000,
+            frame_interval: Duration::from_millis(40),
+            frame_id: 0,
+        }
+    }
+
+    #[test]
+    fn test_frame_rate_controller() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let deadline = Duration::from_millis(100);
+        let mut ctrl = FrameRateController::new(25, 1000, deadline, Duration::ZERO, rx);
+        assert_eq!(ctrl.frame_bytes(), 5000);
+        tx.send(ack(20)).unwrap();
+        tx.send(ack(150)).unwrap();
+        ctrl.update_rate_sample();
+        assert_eq!(ctrl.bitrate_kbps(), 850.0);
+        // No ack, no news
+        ctrl.update_rate_sample();
+        assert_eq!(ctrl.bitrate_kbps(), 850.0);
+        for _ in 0..10 {
+            tx.send(ack(20)).unwrap();
+            ctrl.update_rate_sample();
+        }
+        assert_eq!(ctrl.bitrate_kbps(), 1000.0);
+    }
+}
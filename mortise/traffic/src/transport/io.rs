@@ -1,8 +1,9 @@
 use super::{RateCtrlOp, SendChunkInfo, TransportInfo, TransportOpt};
+use crate::body::write_zeros;
 use crate::{ClientIpcOperation, ModeOpt};
 use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
-use mortise_common::qoe::FrameQoE;
+use mortise_common::qoe::{AppInfo, FrameQoE};
 use mortise_common::CongestionOpt;
 use mortise_common::{get_clock_ns, sync::AtomicRawCell};
 use rustc_hash::FxHashMap as HashMap;
@@ -14,15 +15,16 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::{mpsc, oneshot};
 use tokio_util::codec::LengthDelimitedCodec;
 
-// 20 bytes from converting structs to bytes, the other 4 bytes from frame head (u32)
-pub const HEADER_OVERHEAD: u64 = 24;
+// 24 bytes from converting structs to bytes, the other 4 bytes from frame head (u32)
+pub const HEADER_OVERHEAD: u64 = 28;
 pub const TIMEOUT: u64 = 300;
 
 #[derive(Debug, Clone, Default, Readable, Writable)]
 pub struct DataChunk {
     pub id: u64,
     pub server_send: u64,
-    pub data: Vec<u8>,
+    /// Bytes of the chunk, written after the frame.
+    pub size: u64,
 }
 
 #[derive(Debug, Clone, Copy, Default, Readable, Writable)]
@@ -45,6 +47,124 @@ pub struct Stat {
 #[derive(Debug, Clone, Readable, Writable)]
 pub struct ClientConnectOpt {
     pub congestion: CongestionOpt,
+    /// Workload run over the connection.
+    pub app: ConnectApp,
+}
+
+/// Workload of a connection, with the options of its mode.
+#[derive(Debug, Clone, Readable, Writable)]
+pub enum ConnectApp {
+    /// Requests answered by the server.
+    Requests(RequestsOpt),
+    /// The client uploads the payloads with `Upload`, its socket is the one tuned.
+    Upload,
+    Video(VideoOpt),
+    WebRTC(WebRTCOpt),
+    Bulk(BulkOpt),
+}
+
+impl ConnectApp {
+    /// Value of the tunable of the CCA for the whole connection, e.g. to sweep static
+    /// trade-offs, instead of the one chosen by the policy of manager.
+    pub fn default_app_info(&self) -> Option<u64> {
+        match self {
+            ConnectApp::Requests(opt) => opt.default_app_info,
+            ConnectApp::Upload => None,
+            ConnectApp::Video(opt) => opt.default_app_info,
+            ConnectApp::WebRTC(opt) => opt.default_app_info,
+            ConnectApp::Bulk(opt) => opt.default_app_info,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Readable, Writable)]
+pub struct RequestsOpt {
+    /// Answer the requests on multiplexed streams (see [`crate::mux`]).
+    pub mux: bool,
+    pub default_app_info: Option<u64>,
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+pub struct VideoOpt {
+    /// Playback time of a segment, acknowledged with `Ack`.
+    pub segment: Duration,
+    pub default_app_info: Option<u64>,
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+pub struct WebRTCOpt {
+    pub frame_interval: Duration,
+    /// Target bitrate of the frames, in kbps.
+    pub bitrate_kbps: u64,
+    /// Frames arriving later than this after being sent are dropped.
+    pub deadline: Duration,
+    /// Time the server sends frames for.
+    pub duration: Duration,
+    pub default_app_info: Option<u64>,
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+pub struct BulkOpt {
+    /// Time the server sends for.
+    pub duration: Duration,
+    /// Bytes the server sends instead of sending for `duration`, if not 0.
+    pub bytes: u64,
+    /// Interval of the reports.
+    pub interval: Duration,
+    pub default_app_info: Option<u64>,
+}
+
+/// Map of the CCA receiving the hints of the responses, see [`RequestHint::app_info`].
+pub const HINT_MAP: &str = "hint_sk_stg";
+/// Map of the CCA receiving the pacing rate cap of the responses, see
+/// [`RequestHint::rate_cap`].
+pub const RATE_CAP_MAP: &str = "rate_cap_sk_stg";
+
+/// Hints of a request, which the server pushes into the CCA as it starts the response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Readable, Writable)]
+pub struct RequestHint {
+    /// Time the response should complete in after the request is sent, 0 for none.
+    pub deadline_ms: u32,
+    /// Higher is more urgent.
+    pub priority: u8,
+    /// Cap of the pacing rate during the response in kbps, 0 for none.
+    pub rate_cap_kbps: u64,
+}
+
+impl RequestHint {
+    /// Hint of the parsed values, refused if the deadline or the priority is out of range.
+    pub fn new(
+        deadline_ms: u64,
+        priority: u64,
+        rate_cap_kbps: u64,
+    ) -> std::result::Result<Self, String> {
+        Ok(RequestHint {
+            deadline_ms: u32::try_from(deadline_ms)
+                .map_err(|_| format!("Deadline of {} ms out of range", deadline_ms))?,
+            priority: u8::try_from(priority)
+                .map_err(|_| format!("Priority {} out of range", priority))?,
+            rate_cap_kbps,
+        })
+    }
+
+    /// Hint of a response of `remaining` bytes: `req` holds the bytes, `resp` the deadline
+    /// in ms above 8 bits of priority.
+    pub fn app_info(&self, remaining: u64) -> AppInfo {
+        AppInfo {
+            req: remaining,
+            resp: ((self.deadline_ms as u64) << 8) | self.priority as u64,
+        }
+    }
+
+    /// Pacing rate cap in bytes per second, as the kernel counts it. 0 lifts the cap of a
+    /// previous response.
+    pub fn rate_cap(&self) -> AppInfo {
+        let rate = self.rate_cap_kbps.saturating_mul(1000) / 8;
+        AppInfo {
+            req: rate,
+            resp: rate,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Readable, Writable)]
@@ -52,6 +172,7 @@ pub struct ClientRequest {
     pub id: u32,
     pub size: u32,
     pub client_send: u64,
+    pub hint: Option<RequestHint>,
 }
 
 #[derive(Debug, Clone, Readable, Writable)]
@@ -61,13 +182,19 @@ pub struct ServerResponse {
     /// This is the time that the server handles the request.
     /// The request may already stay in the queue for a while.
     pub server_recv: u64,
-    pub data: Vec<u8>,
+    /// Bytes of the body, written after the frame (see [`crate::body`]).
+    pub size: u64,
 }
 
 #[derive(Debug, Clone, Readable, Writable)]
 pub enum ClientRequestOpt {
     Connect(ClientConnectOpt),
     Request(ClientRequest),
+    /// Receipt of a response, whose QoE the server feeds to manager.
+    Ack(ChunkAck),
+    /// Request whose payload of `size` bytes is written after the frame, answered with
+    /// an empty response once received.
+    Upload(ClientRequest),
     Finish,
 }
 
@@ -76,7 +203,12 @@ pub struct ClientRequestStats {
     pub size: u32,
     pub client_send: u64,
     pub server_recv: u64,
+    /// Time the head of the response arrived.
+    pub client_first_byte: u64,
+    /// Time the whole body arrived.
     pub client_recv: u64,
+    /// Connection of the client the request was sent on.
+    pub conn: u32,
 }
 
 pub async fn handle_send(
@@ -87,7 +219,6 @@ pub async fn handle_send(
 ) {
     let mut writer = LengthDelimitedCodec::builder()
         .length_field_type::<u32>()
-        .max_frame_length(500 * 1024 * 1024) // 500MB
         .new_write(writer);
     let mut total_write_bytes: u64 = 0;
     transport_info.store(Box::new(TransportInfo { total_write_bytes }));
@@ -107,7 +238,7 @@ pub async fn handle_send(
                         let frame = DataChunk {
                             id,
                             server_send: get_clock_ns() as u64,
-                            data: vec![74; data_bytes as usize],
+                            size: data_bytes,
                         };
                         let b: Bytes = { frame.write_to_vec().map(Into::into).unwrap() };
                         total_write_bytes += data_bytes + HEADER_OVERHEAD;
@@ -116,6 +247,10 @@ pub async fn handle_send(
                             tracing::error!(target: "sender:send", "Failed to send data: {:?}", e);
                             break;
                         }
+                        if let Err(e) = write_zeros(writer.get_mut(), data_bytes).await {
+                            tracing::error!(target: "sender:send", "Failed to send data: {:?}", e);
+                            break;
+                        }
                     }
                     RateCtrlOp::Done => {
                         tracing::info!(target: "sender:send", "All data is sent!");
@@ -140,10 +275,14 @@ pub async fn handle_send(
     }
 }
 
+/// Receive the acks of the chunks of connection `conn_id`, feeding their QoE to manager
+/// in mortise mode and to `feedback`, the rate controller of the chunks.
 pub async fn handle_recv(
     reader: OwnedReadHalf,
-    manager_tx: mpsc::Sender<ClientIpcOperation>,
+    manager_tx: mpsc::Sender<(u64, ClientIpcOperation)>,
+    conn_id: u64,
     transport_opt: TransportOpt,
+    feedback: Option<mpsc::UnboundedSender<FrameQoE>>,
 ) -> HashMap<u64, Stat> {
     let mut statistics = HashMap::default();
     let mut reader = LengthDelimitedCodec::builder()
@@ -152,12 +291,21 @@ pub async fn handle_recv(
         .new_read(reader);
     tracing::info!(target: "sender:recv", "Begin to receive!");
     let obj_id = transport_opt.congestion.get_obj_id();
+    let frame_interval = Duration::from_micros(1_000_000 / transport_opt.frame.max(1));
+    // Cleared once manager_ipc is gone
+    let mut report_qoe = transport_opt.mode == ModeOpt::Mortise;
     loop {
         match reader.next().await {
             Some(res) => match res {
                 Ok(bytes) => {
                     let server_recv = get_clock_ns() as u64;
-                    let ack: ChunkAck = ChunkAck::read_from_buffer(bytes.as_ref()).unwrap();
+                    let ack = match ChunkAck::read_from_buffer(bytes.as_ref()) {
+                        Ok(ack) => ack,
+                        Err(e) => {
+                            tracing::error!(target: "sender:recv", "Invalid ack: {:?}", e);
+                            break;
+                        }
+                    };
                     // println!(
                     //     "recv ack: {}, s -> {} -> r -> {} -> s, size {}",
                     //     ack.id,
@@ -170,20 +318,25 @@ pub async fn handle_recv(
                         client_recv: ack.client_recv,
                         server_recv,
                         size: ack.size,
-                        frame_interval: Duration::from_micros(1_000_000 / 60),
+                        frame_interval,
                         frame_id: ack.id,
                     };
-                    if transport_opt.mode == ModeOpt::Mortise {
+                    if report_qoe {
                         let (tmp_tx, tmp_rx) = oneshot::channel();
-                        manager_tx
-                            .send(ClientIpcOperation::QoEUpdate {
-                                obj_id,
-                                qoe: qoe.clone(),
-                                resp: tmp_tx,
-                            })
-                            .await
-                            .unwrap();
-                        let _ = tmp_rx.await.unwrap().unwrap();
+                        let op = ClientIpcOperation::QoEUpdate {
+                            obj_id,
+                            qoe: qoe.clone(),
+                            resp: tmp_tx,
+                        };
+                        if manager_tx.send((conn_id, op)).await.is_err() {
+                            tracing::debug!(target: "sender:recv", "Manager gone, QoE of connection {} no longer reported", conn_id);
+                            report_qoe = false;
+                        } else if let Ok(Err(e)) = tmp_rx.await {
+                            tracing::debug!(target: "sender:recv", "QoE not recorded: {}", e);
+                        }
+                    }
+                    if let Some(ref feedback) = feedback {
+                        let _ = feedback.send(qoe.clone());
                     }
                     statistics.insert(
                         ack.id,
@@ -3,6 +3,8 @@ pub mod io;
 use crate::{AppOpt, ModeOpt};
 pub use mortise_common::CongestionOpt;
 use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 
 #[derive(Debug, Clone)]
 pub enum RateCtrlOp {
@@ -39,3 +41,37 @@ pub trait RateController {
     fn get_sample_interval(&self) -> &Duration;
     fn update_rate_sample(&mut self) {}
 }
+
+/// Feed the chunks of `ctrl` to `handle_send` every chunk interval, and sample the rate
+/// every sample interval, until the controller has no more chunk.
+pub async fn run_rate_controller<C: RateController + Send>(
+    mut ctrl: C,
+    app_tx: mpsc::UnboundedSender<RateCtrlOp>,
+    mut ready_rx: mpsc::UnboundedReceiver<()>,
+) {
+    // Wait for the sender before the first chunk
+    if app_tx.send(RateCtrlOp::Ready).is_err() || ready_rx.recv().await.is_none() {
+        return;
+    }
+    let mut chunk_timer = tokio::time::interval(*ctrl.get_chunk_interval());
+    // Chunks whose time passed while the sender was busy are skipped, as an encoder would
+    chunk_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut sample_timer = tokio::time::interval(*ctrl.get_sample_interval());
+    let mut id = 0;
+    loop {
+        tokio::select! {
+            _ = chunk_timer.tick() => {
+                let op = match ctrl.next_chunk(id).await {
+                    Some(info) => RateCtrlOp::Send(info),
+                    None => RateCtrlOp::Done,
+                };
+                let done = matches!(op, RateCtrlOp::Done);
+                if app_tx.send(op).is_err() || done {
+                    break;
+                }
+                id += 1;
+            }
+            _ = sample_timer.tick() => ctrl.update_rate_sample(),
+        }
+    }
+}
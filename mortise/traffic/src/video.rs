@@ -0,0 +1,226 @@
+//! Adaptive-bitrate video streaming.
+//!
+//! The client downloads the segments of a video one after another, each at a rung of
+//! the bitrate ladder picked by an [`Abr`], and simulates the playback buffer they fill.
+//! Each segment is acknowledged with a `ChunkAck`, from which the server feeds the QoE
+//! of the segment to manager.
+
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Rungs of the ladder in kbps, from the Pensieve video.
+pub const DEFAULT_LADDER_KBPS: [u64; 6] = [300, 750, 1200, 1850, 2850, 4300];
+
+/// Throughput samples of the throughput-based ABR.
+const THROUGHPUT_WINDOW: usize = 5;
+/// Share of the estimated throughput the throughput-based ABR may use.
+const THROUGHPUT_SAFETY: f64 = 0.9;
+/// Buffer level below which the buffer-based ABR picks the lowest rung.
+const RESERVOIR: Duration = Duration::from_secs(5);
+/// Penalty of a second of stall in the QoE, in Mbps.
+const STALL_PENALTY: f64 = 4.3;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[clap(rename_all = "lower")]
+pub enum AbrPolicy {
+    /// Rung proportional to the buffer level between the reservoir and the maximum (BBA).
+    #[default]
+    Buffer,
+    /// Highest rung below the harmonic mean of the last segment throughputs.
+    Throughput,
+}
+
+/// Picks the rung of the next segment.
+#[derive(Debug)]
+pub struct Abr {
+    policy: AbrPolicy,
+    ladder_kbps: Vec<u64>,
+    max_buffer: Duration,
+    throughputs_kbps: VecDeque<f64>,
+}
+
+impl Abr {
+    pub fn new(policy: AbrPolicy, ladder_kbps: Vec<u64>, max_buffer: Duration) -> Self {
+        Abr {
+            policy,
+            ladder_kbps,
+            max_buffer,
+            throughputs_kbps: VecDeque::with_capacity(THROUGHPUT_WINDOW),
+        }
+    }
+
+    pub fn bitrate_kbps(&self, rung: usize) -> u64 {
+        self.ladder_kbps[rung]
+    }
+
+    /// Record the throughput of a downloaded segment.
+    pub fn record(&mut self, size: u64, download: Duration) {
+        if self.throughputs_kbps.len() == THROUGHPUT_WINDOW {
+            self.throughputs_kbps.pop_front();
+        }
+        let secs = download.as_secs_f64().max(1e-6);
+        self.throughputs_kbps
+            .push_back(size as f64 * 8.0 / 1000.0 / secs);
+    }
+
+    /// Harmonic mean of the recorded throughputs.
+    pub fn throughput_kbps(&self) -> Option<f64> {
+        if self.throughputs_kbps.is_empty() {
+            return None;
+        }
+        let inverse: f64 = self.throughputs_kbps.iter().map(|t| 1.0 / t).sum();
+        Some(self.throughputs_kbps.len() as f64 / inverse)
+    }
+
+    /// Rung of the next segment with `buffer` of video ahead of the playback.
+    pub fn choose(&self, buffer: Duration) -> usize {
+        let top = self.ladder_kbps.len() - 1;
+        match self.policy {
+            AbrPolicy::Buffer => {
+                if buffer <= RESERVOIR {
+                    return 0;
+                }
+                let cushion = self.max_buffer.saturating_sub(RESERVOIR);
+                if cushion.is_zero() {
+                    return top;
+                }
+                let level = (buffer - RESERVOIR).as_secs_f64() / cushion.as_secs_f64();
+                ((level * top as f64) as usize).min(top)
+            }
+            AbrPolicy::Throughput => match self.throughput_kbps() {
+                Some(throughput) => self
+                    .ladder_kbps
+                    .iter()
+                    .rposition(|&kbps| kbps as f64 <= throughput * THROUGHPUT_SAFETY)
+                    .unwrap_or(0),
+                None => 0,
+            },
+        }
+    }
+}
+
+/// Playback of a downloaded segment.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentPlayback {
+    /// Video ahead of the playback once the segment arrived.
+    pub buffer: Duration,
+    /// Playback stalled while the segment was downloaded.
+    pub stall: Duration,
+    /// Linear QoE of the segment: bitrate minus the penalties of stall and switch, in Mbps.
+    pub qoe: f64,
+}
+
+/// Simulated playback buffer of the client.
+#[derive(Debug)]
+pub struct Playback {
+    segment: Duration,
+    buffer: Duration,
+    last_kbps: Option<u64>,
+    /// Time until the first segment arrived and the playback started.
+    pub startup: Option<Duration>,
+    pub total_stall: Duration,
+    pub stalls: u32,
+    pub switches: u32,
+}
+
+impl Playback {
+    pub fn new(segment: Duration) -> Self {
+        Playback {
+            segment,
+            buffer: Duration::ZERO,
+            last_kbps: None,
+            startup: None,
+            total_stall: Duration::ZERO,
+            stalls: 0,
+            switches: 0,
+        }
+    }
+
+    pub fn buffer(&self) -> Duration {
+        self.buffer
+    }
+
+    /// Time to wait before the next request so that the buffer stays below `max_buffer`.
+    pub fn idle(&self, max_buffer: Duration) -> Duration {
+        (self.buffer + self.segment).saturating_sub(max_buffer)
+    }
+
+    /// Play `elapsed` of the buffer while no segment is downloaded.
+    pub fn drain(&mut self, elapsed: Duration) {
+        self.buffer = self.buffer.saturating_sub(elapsed);
+    }
+
+    /// Add a segment at `kbps` which took `download`, `since_start` after the session began.
+    pub fn on_segment(
+        &mut self,
+        kbps: u64,
+        download: Duration,
+        since_start: Duration,
+    ) -> SegmentPlayback {
+        let mut stall = Duration::ZERO;
+        if self.startup.is_none() {
+            self.startup = Some(since_start);
+        } else if download > self.buffer {
+            stall = download - self.buffer;
+            self.buffer = Duration::ZERO;
+            self.total_stall += stall;
+            self.stalls += 1;
+        } else {
+            self.buffer -= download;
+        }
+        self.buffer += self.segment;
+        let switch = match self.last_kbps {
+            Some(last) if last != kbps => {
+                self.switches += 1;
+                last.abs_diff(kbps)
+            }
+            _ => 0,
+        };
+        self.last_kbps = Some(kbps);
+        let qoe = (kbps as f64 - switch as f64) / 1000.0 - STALL_PENALTY * stall.as_secs_f64();
+        SegmentPlayback {
+            buffer: self.buffer,
+            stall,
+            qoe,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Abr, AbrPolicy, Playback, DEFAULT_LADDER_KBPS};
+    use std::time::Duration;
+
+    #[test]
+    fn test_abr() {
+        let ladder = DEFAULT_LADDER_KBPS.to_vec();
+        let buffer = Abr::new(AbrPolicy::Buffer, ladder.clone(), Duration::from_secs(30));
+        assert_eq!(buffer.choose(Duration::from_secs(2)), 0);
+        assert_eq!(buffer.choose(Duration::from_secs(15)), 2);
+        assert_eq!(buffer.choose(Duration::from_secs(30)), 5);
+
+        let mut throughput = Abr::new(AbrPolicy::Throughput, ladder, Duration::from_secs(30));
+        assert_eq!(throughput.choose(Duration::ZERO), 0);
+        // 2000 kbps then 4000 kbps, harmonic mean of 2667 kbps
+        throughput.record(250_000, Duration::from_secs(1));
+        throughput.record(500_000, Duration::from_secs(1));
+        assert_eq!(throughput.choose(Duration::ZERO), 3);
+    }
+
+    #[test]
+    fn test_playback() {
+        let mut playback = Playback::new(Duration::from_secs(4));
+        let first = playback.on_segment(300, Duration::from_secs(1), Duration::from_secs(1));
+        assert_eq!(playback.startup, Some(Duration::from_secs(1)));
+        assert_eq!(first.buffer, Duration::from_secs(4));
+        let second = playback.on_segment(750, Duration::from_secs(6), Duration::from_secs(7));
+        assert_eq!(second.stall, Duration::from_secs(2));
+        assert_eq!(second.buffer, Duration::from_secs(4));
+        assert_eq!((playback.stalls, playback.switches), (1, 1));
+        assert_eq!(
+            playback.idle(Duration::from_secs(6)),
+            Duration::from_secs(2)
+        );
+    }
+}